@@ -0,0 +1,251 @@
+use crate::asr::{TranscriptionSegment, TranscriptionWord};
+
+/// How far (in words) the alignment path may stray from the diagonal.
+const MIN_BAND_WIDTH: usize = 200;
+/// Upper bound of the band, so that the step matrix takes about 1KB per
+/// script word whatever the length mismatch.
+const MAX_BAND_WIDTH: usize = 500;
+const UNREACHABLE: u32 = u32::MAX / 2;
+
+#[derive(Clone, Copy)]
+enum Step {
+    Match,
+    SkipScript,
+    SkipRecognized,
+}
+
+fn normalize_word(word: &str) -> String {
+    word.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Aligns the script words with the recognized words using a banded
+/// Needleman-Wunsch alignment.
+///
+/// Only the steps inside the band are kept for the traceback; the costs need
+/// just the previous row.
+///
+/// Returns, for every script word, the index of the recognized word it was
+/// paired with, or `None` if it was treated as a gap.
+fn align_words(script: &[String], recognized: &[String]) -> Vec<Option<usize>> {
+    let n = script.len();
+    let m = recognized.len();
+    if n == 0 || m == 0 {
+        return vec![None; n];
+    }
+
+    let script: Vec<String> = script.iter().map(|w| normalize_word(w)).collect();
+    let recognized: Vec<String> = recognized.iter().map(|w| normalize_word(w)).collect();
+    let band = (MIN_BAND_WIDTH + n.abs_diff(m) / 2).min(MAX_BAND_WIDTH);
+    let column_range = |i: usize| {
+        let center = i * m / n;
+        (center.saturating_sub(band), (center + band).min(m))
+    };
+
+    // Each row only stores the columns inside the band.
+    let mut steps: Vec<Vec<Step>> = Vec::with_capacity(n + 1);
+    let mut previous_costs: Vec<u32> = Vec::new();
+    let mut previous_lo = 0;
+    let mut row_costs: Vec<u32> = Vec::new();
+    for i in 0..=n {
+        let (lo, hi) = column_range(i);
+        let previous_cost = |j: usize| -> u32 {
+            if i == 0 || j < previous_lo || j - previous_lo >= previous_costs.len() {
+                UNREACHABLE
+            } else {
+                previous_costs[j - previous_lo]
+            }
+        };
+        row_costs.clear();
+        let mut row_steps = Vec::with_capacity(hi - lo + 1);
+        for j in lo..=hi {
+            let (cost, step) = if i == 0 {
+                (j as u32, Step::SkipRecognized)
+            } else if j == 0 {
+                (i as u32, Step::SkipScript)
+            } else {
+                let substitution = if script[i - 1] == recognized[j - 1] {
+                    0
+                } else {
+                    1
+                };
+                let left = if j > lo {
+                    row_costs[j - lo - 1]
+                } else {
+                    UNREACHABLE
+                };
+                [
+                    (previous_cost(j - 1) + substitution, Step::Match),
+                    (previous_cost(j) + 1, Step::SkipScript),
+                    (left + 1, Step::SkipRecognized),
+                ]
+                .into_iter()
+                .min_by_key(|(cost, _)| *cost)
+                .unwrap()
+            };
+            row_costs.push(cost);
+            row_steps.push(step);
+        }
+        std::mem::swap(&mut previous_costs, &mut row_costs);
+        previous_lo = lo;
+        steps.push(row_steps);
+    }
+
+    let mut pairs = vec![None; n];
+    let (mut i, mut j) = (n, m);
+    while i > 0 && j > 0 {
+        let (lo, _) = column_range(i);
+        match steps[i][j - lo] {
+            Step::Match => {
+                pairs[i - 1] = Some(j - 1);
+                i -= 1;
+                j -= 1;
+            }
+            Step::SkipScript => i -= 1,
+            Step::SkipRecognized => j -= 1,
+        }
+    }
+    pairs
+}
+
+/// Assigns start/end times to each script line from the recognized words.
+///
+/// Lines that could not be matched at all are placed in the gap between
+/// their neighbours.
+pub(crate) fn align_lines(
+    lines: &[String],
+    recognized: &[TranscriptionWord],
+) -> Vec<TranscriptionSegment> {
+    let mut script_words = Vec::new();
+    let mut word_lines = Vec::new();
+    for (line_index, line) in lines.iter().enumerate() {
+        for word in line.split_whitespace() {
+            script_words.push(word.to_string());
+            word_lines.push(line_index);
+        }
+    }
+    let recognized_texts: Vec<String> = recognized.iter().map(|w| w.text.clone()).collect();
+    let pairs = align_words(&script_words, &recognized_texts);
+
+    let mut line_words: Vec<Vec<TranscriptionWord>> = vec![Vec::new(); lines.len()];
+    for ((script_word, line_index), pair) in script_words.iter().zip(word_lines).zip(pairs) {
+        if let Some(recognized_index) = pair {
+            let timing = &recognized[recognized_index];
+            line_words[line_index].push(TranscriptionWord {
+                text: script_word.clone(),
+                start_ms: timing.start_ms,
+                end_ms: timing.end_ms,
+            });
+        }
+    }
+
+    let timings: Vec<Option<(u32, u32)>> = line_words
+        .iter()
+        .map(|words| match (words.first(), words.last()) {
+            (Some(first), Some(last)) => Some((first.start_ms, last.end_ms)),
+            _ => None,
+        })
+        .collect();
+
+    let audio_end_ms = recognized.last().map(|w| w.end_ms).unwrap_or(0);
+    let mut previous_end_ms = 0;
+    let mut segments = Vec::with_capacity(lines.len());
+    for (index, (line, words)) in lines.iter().zip(line_words).enumerate() {
+        let (start_ms, end_ms) = timings[index].unwrap_or_else(|| {
+            let next_start_ms = timings[index + 1..]
+                .iter()
+                .flatten()
+                .map(|(start, _)| *start)
+                .next()
+                .unwrap_or(audio_end_ms);
+            (previous_end_ms, next_start_ms.max(previous_end_ms))
+        });
+        previous_end_ms = end_ms;
+        segments.push(TranscriptionSegment {
+            start_time_ms: start_ms,
+            end_time_ms: Some(end_ms),
+            original_text: line.trim().to_string(),
+            words,
+        });
+    }
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recognized(words: &[(&str, u32, u32)]) -> Vec<TranscriptionWord> {
+        words
+            .iter()
+            .map(|(text, start_ms, end_ms)| TranscriptionWord {
+                text: text.to_string(),
+                start_ms: *start_ms,
+                end_ms: *end_ms,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_align_lines_tolerates_recognition_errors() {
+        let lines = vec![
+            "Good morning, everyone.".to_string(),
+            "Let's begin.".to_string(),
+        ];
+        let words = recognized(&[
+            ("good", 100, 300),
+            ("morning", 300, 700),
+            ("everyone", 700, 1200),
+            ("uh", 1500, 1700),
+            ("let", 2000, 2300),
+            ("begin", 2300, 2800),
+        ]);
+
+        let segments = align_lines(&lines, &words);
+
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].start_time_ms, 100);
+        assert_eq!(segments[0].end_time_ms, Some(1200));
+        assert_eq!(segments[1].start_time_ms, 2000);
+        assert_eq!(segments[1].end_time_ms, Some(2800));
+        assert_eq!(segments[1].original_text, "Let's begin.");
+    }
+
+    #[test]
+    fn test_align_lines_places_unmatched_lines_between_neighbours() {
+        let lines = vec![
+            "one two".to_string(),
+            "zzz".to_string(),
+            "three four".to_string(),
+        ];
+        let words = recognized(&[
+            ("one", 0, 200),
+            ("two", 200, 400),
+            ("three", 1000, 1200),
+            ("four", 1200, 1400),
+        ]);
+
+        let segments = align_lines(&lines, &words);
+
+        assert_eq!(segments[1].start_time_ms, 400);
+        assert_eq!(segments[1].end_time_ms, Some(1000));
+        assert!(segments[1].words.is_empty());
+    }
+
+    #[test]
+    fn test_align_words_follows_an_insertion_inside_the_band() {
+        let script: Vec<String> = (0..2000).map(|i| format!("w{}", i)).collect();
+        // The recording has 300 extra words in the middle of the script.
+        let mut recognized = script[..1000].to_vec();
+        recognized.extend((0..300).map(|i| format!("extra{}", i)));
+        recognized.extend_from_slice(&script[1000..]);
+
+        let pairs = align_words(&script, &recognized);
+
+        assert_eq!(pairs[999], Some(999));
+        assert_eq!(pairs[1000], Some(1300));
+        assert_eq!(pairs[1999], Some(2299));
+    }
+}
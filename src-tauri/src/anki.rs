@@ -0,0 +1,644 @@
+// cSpell:words anki apkg dconf sfld csum usn ivl odue odid revlog sortf tmpls qfmt afmt latexsvg
+use log::info;
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// Separates the fields of a note in the `flds` column.
+const FIELD_SEPARATOR: &str = "\x1f";
+pub(crate) const FIELD_NAMES: [&str; 6] = [
+    "Expression",
+    "Sentence",
+    "Meaning",
+    "CoreMeaning",
+    "PartOfSpeech",
+    "Audio",
+];
+
+pub(crate) const DEFAULT_NOTE_TYPE_NAME: &str = "Kotonoha Sentence Card";
+const DEFAULT_FRONT_TEMPLATE: &str = "<div class=\"sentence\">{{Sentence}}</div>\n{{Audio}}";
+const DEFAULT_BACK_TEMPLATE: &str = "{{FrontSide}}\n<hr id=answer>\n\
+<div class=\"expression\">{{Expression}}</div>\n\
+<div class=\"part-of-speech\">{{PartOfSpeech}}</div>\n\
+<div class=\"meaning\">{{Meaning}}</div>\n\
+<div class=\"core-meaning\">{{CoreMeaning}}</div>";
+const DEFAULT_CSS: &str = ".card {\n  font-family: arial;\n  font-size: 20px;\n  text-align: center;\n  color: black;\n  background-color: white;\n}\n\
+.sentence b {\n  color: #2563eb;\n}\n\
+.part-of-speech {\n  font-size: 14px;\n  color: gray;\n}\n\
+.core-meaning {\n  font-size: 16px;\n  text-align: left;\n}\n";
+
+/// Schema version 11, which every Anki release can import.
+const COLLECTION_SCHEMA: &str = "
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null,
+    scm integer not null, ver integer not null, dty integer not null,
+    usn integer not null, ls integer not null, conf text not null,
+    models text not null, decks text not null, dconf text not null,
+    tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null,
+    mod integer not null, usn integer not null, tags text not null,
+    flds text not null, sfld integer not null, csum integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null,
+    ord integer not null, mod integer not null, usn integer not null,
+    type integer not null, queue integer not null, due integer not null,
+    ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null,
+    odid integer not null, flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null,
+    ease integer not null, ivl integer not null, lastIvl integer not null,
+    factor integer not null, time integer not null, type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn ON notes (usn);
+CREATE INDEX ix_cards_usn ON cards (usn);
+CREATE INDEX ix_revlog_usn ON revlog (usn);
+CREATE INDEX ix_cards_nid ON cards (nid);
+CREATE INDEX ix_cards_sched ON cards (did, queue, due);
+CREATE INDEX ix_revlog_cid ON revlog (cid);
+CREATE INDEX ix_notes_csum ON notes (csum);
+";
+
+/// The `content` JSON of a row in the `sentence_cards` table.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SentenceCardContent {
+    pub id: String,
+    pub expression: String,
+    /// The sentence with the expression highlighted by `<b>` tags.
+    #[serde(alias = "exampleSentence")]
+    pub sentence: String,
+    pub contextual_definition: String,
+    pub core_meaning: String,
+    #[serde(default)]
+    pub part_of_speech: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AnkiExportCard {
+    pub content: SentenceCardContent,
+    /// AppLocalData-relative path of a clip created by `export_audio_clip`.
+    pub audio_clip: Option<String>,
+}
+
+/// Name, templates and styling of the exported note type. Unset values fall
+/// back to the built-in sentence card layout.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct AnkiNoteType {
+    pub name: Option<String>,
+    pub front_template: Option<String>,
+    pub back_template: Option<String>,
+    pub css: Option<String>,
+}
+
+impl AnkiNoteType {
+    pub(crate) fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(DEFAULT_NOTE_TYPE_NAME)
+    }
+
+    pub(crate) fn front_template(&self) -> &str {
+        self.front_template
+            .as_deref()
+            .unwrap_or(DEFAULT_FRONT_TEMPLATE)
+    }
+
+    pub(crate) fn back_template(&self) -> &str {
+        self.back_template
+            .as_deref()
+            .unwrap_or(DEFAULT_BACK_TEMPLATE)
+    }
+
+    pub(crate) fn css(&self) -> &str {
+        self.css.as_deref().unwrap_or(DEFAULT_CSS)
+    }
+}
+
+/// A note ready to be written to a package.
+pub(crate) struct AnkiNote {
+    pub(crate) guid: String,
+    pub(crate) fields: Vec<String>,
+    /// File name inside the package and the file it is read from.
+    pub(crate) media: Option<(String, PathBuf)>,
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Escapes the sentence but keeps the `<b>` highlighting of the expression.
+fn sentence_html(sentence: &str) -> String {
+    escape_html(sentence)
+        .replace("&lt;b&gt;", "<b>")
+        .replace("&lt;/b&gt;", "</b>")
+}
+
+fn strip_html(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => stripped.push(c),
+            _ => {}
+        }
+    }
+    stripped
+}
+
+fn sha1_prefix(text: &str) -> u64 {
+    let digest = Sha1::digest(text.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().expect("SHA-1 has 20 bytes"))
+}
+
+/// Anki's duplicate check key: the first 32 bits of the SHA-1 of the stripped
+/// first field.
+fn field_checksum(field: &str) -> i64 {
+    (sha1_prefix(&strip_html(field)) >> 32) as i64
+}
+
+/// Derives a stable id from a name so that exporting again updates the same
+/// deck and note type instead of creating copies.
+fn stable_id(name: &str) -> i64 {
+    // stay within the integers JavaScript can represent exactly
+    (sha1_prefix(name) & ((1 << 52) - 1)) as i64 + 1_000_000_000_000
+}
+
+impl AnkiNote {
+    pub(crate) fn from_card(content: &SentenceCardContent, media: Option<PathBuf>) -> Self {
+        let media = media.map(|path| {
+            let extension = path
+                .extension()
+                .and_then(|e| e.to_str())
+                .unwrap_or("ogg")
+                .to_string();
+            (format!("kotonoha_{}.{}", content.id, extension), path)
+        });
+        let audio = media
+            .as_ref()
+            .map(|(name, _)| format!("[sound:{}]", name))
+            .unwrap_or_default();
+        Self {
+            guid: content.id.clone(),
+            fields: vec![
+                escape_html(&content.expression),
+                sentence_html(&content.sentence),
+                escape_html(&content.contextual_definition),
+                escape_html(&content.core_meaning),
+                escape_html(&content.part_of_speech),
+                audio,
+            ],
+            media,
+        }
+    }
+}
+
+fn models_json(model_id: i64, deck_id: i64, note_type: &AnkiNoteType, now_secs: i64) -> String {
+    let fields: Vec<_> = FIELD_NAMES
+        .iter()
+        .enumerate()
+        .map(|(ord, name)| {
+            json!({
+                "name": name,
+                "ord": ord,
+                "sticky": false,
+                "rtl": false,
+                "font": "Arial",
+                "size": 20,
+                "media": [],
+            })
+        })
+        .collect();
+    let model = json!({
+        "id": model_id,
+        "name": note_type.name(),
+        "type": 0,
+        "mod": now_secs,
+        "usn": -1,
+        "sortf": 0,
+        "did": deck_id,
+        "tmpls": [{
+            "name": "Card 1",
+            "ord": 0,
+            "qfmt": note_type.front_template(),
+            "afmt": note_type.back_template(),
+            "did": null,
+            "bqfmt": "",
+            "bafmt": "",
+        }],
+        "flds": fields,
+        "css": note_type.css(),
+        "latexPre": "\\documentclass[12pt]{article}\n\\special{papersize=3in,5in}\n\\usepackage{amssymb,amsmath}\n\\pagestyle{empty}\n\\setlength{\\parindent}{0in}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "latexsvg": false,
+        "req": [[0, "any", [1]]],
+        "tags": [],
+        "vers": [],
+    });
+    json!({ model_id.to_string(): model }).to_string()
+}
+
+fn decks_json(deck_id: i64, deck_name: &str, now_secs: i64) -> String {
+    let deck = |id: i64, name: &str| {
+        json!({
+            "id": id,
+            "name": name,
+            "mod": now_secs,
+            "usn": -1,
+            "lrnToday": [0, 0],
+            "revToday": [0, 0],
+            "newToday": [0, 0],
+            "timeToday": [0, 0],
+            "collapsed": false,
+            "desc": "",
+            "dyn": 0,
+            "conf": 1,
+            "extendNew": 0,
+            "extendRev": 0,
+        })
+    };
+    json!({
+        "1": deck(1, "Default"),
+        deck_id.to_string(): deck(deck_id, deck_name),
+    })
+    .to_string()
+}
+
+fn dconf_json() -> String {
+    json!({
+        "1": {
+            "id": 1,
+            "name": "Default",
+            "mod": 0,
+            "usn": 0,
+            "maxTaken": 60,
+            "autoplay": true,
+            "timer": 0,
+            "replayq": true,
+            "dyn": false,
+            "new": {
+                "bury": false,
+                "delays": [1.0, 10.0],
+                "initialFactor": 2500,
+                "ints": [1, 4, 0],
+                "order": 1,
+                "perDay": 20,
+            },
+            "rev": {
+                "bury": false,
+                "ease4": 1.3,
+                "ivlFct": 1.0,
+                "maxIvl": 36500,
+                "perDay": 200,
+                "hardFactor": 1.2,
+            },
+            "lapse": {
+                "delays": [10.0],
+                "leechAction": 1,
+                "leechFails": 8,
+                "minInt": 1,
+                "mult": 0.0,
+            },
+        }
+    })
+    .to_string()
+}
+
+fn conf_json(model_id: i64, deck_id: i64) -> String {
+    json!({
+        "nextPos": 1,
+        "estTimes": true,
+        "activeDecks": [deck_id],
+        "sortType": "noteFld",
+        "timeLim": 0,
+        "sortBackwards": false,
+        "addToCur": true,
+        "curDeck": deck_id,
+        "newBury": true,
+        "newSpread": 0,
+        "dueCounts": true,
+        "curModel": model_id,
+        "collapseTime": 1200,
+    })
+    .to_string()
+}
+
+fn write_collection(
+    collection_path: &Path,
+    deck_name: &str,
+    note_type: &AnkiNoteType,
+    notes: &[AnkiNote],
+    now_ms: i64,
+) -> Result<(), String> {
+    let now_secs = now_ms / 1000;
+    let model_id = stable_id(note_type.name.as_deref().unwrap_or(DEFAULT_NOTE_TYPE_NAME));
+    let deck_id = stable_id(deck_name);
+
+    let mut conn = Connection::open(collection_path)
+        .map_err(|e| format!("Failed to create Anki collection: {}", e))?;
+    let tx = conn
+        .transaction()
+        .map_err(|e| format!("Failed to start transaction: {}", e))?;
+    tx.execute_batch(COLLECTION_SCHEMA)
+        .map_err(|e| format!("Failed to create Anki tables: {}", e))?;
+    tx.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?3, 11, 0, 0, 0, ?4, ?5, ?6, ?7, '{}')",
+        params![
+            now_secs,
+            now_ms,
+            now_ms,
+            conf_json(model_id, deck_id),
+            models_json(model_id, deck_id, note_type, now_secs),
+            decks_json(deck_id, deck_name, now_secs),
+            dconf_json(),
+        ],
+    )
+    .map_err(|e| format!("Failed to write Anki collection: {}", e))?;
+
+    for (i, note) in notes.iter().enumerate() {
+        // ids only have to be unique within the package
+        let id = now_ms + i as i64;
+        let sort_field = strip_html(&note.fields[0]);
+        tx.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, '', ?5, ?6, ?7, 0, '')",
+            params![
+                id,
+                note.guid,
+                model_id,
+                now_secs,
+                note.fields.join(FIELD_SEPARATOR),
+                sort_field,
+                field_checksum(&note.fields[0]),
+            ],
+        )
+        .map_err(|e| format!("Failed to write Anki note: {}", e))?;
+        tx.execute(
+            "INSERT INTO cards VALUES (?1, ?2, ?3, 0, ?4, -1, 0, 0, ?5, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+            params![id, id, deck_id, now_secs, i as i64 + 1],
+        )
+        .map_err(|e| format!("Failed to write Anki card: {}", e))?;
+    }
+
+    tx.commit()
+        .map_err(|e| format!("Failed to save Anki collection: {}", e))
+}
+
+/// Writes an `.apkg` package: a zip of the `collection.anki2` SQLite database,
+/// the media files numbered from 0, and a `media` JSON mapping those numbers
+/// to the file names the notes refer to.
+pub(crate) fn create_anki_package(
+    output_path: &Path,
+    deck_name: &str,
+    note_type: &AnkiNoteType,
+    notes: &[AnkiNote],
+) -> Result<(), String> {
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+    // Every export gets a directory of its own, removed when it is dropped,
+    // so that simultaneous exports never share a collection file.
+    let temp_dir = tempfile::Builder::new()
+        .prefix("kotonoha-anki-")
+        .tempdir()
+        .map_err(|e| format!("Failed to create temporary directory: {}", e))?;
+    let collection_path = temp_dir.path().join("collection.anki2");
+    write_collection(&collection_path, deck_name, note_type, notes, now_ms)?;
+    let collection =
+        fs::read(&collection_path).map_err(|e| format!("Failed to read collection: {}", e))?;
+    drop(temp_dir);
+
+    let file =
+        File::create(output_path).map_err(|e| format!("Failed to create package file: {}", e))?;
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut add_file = |name: &str, bytes: &[u8]| {
+        zip.start_file(name, options)
+            .and_then(|_| zip.write_all(bytes).map_err(Into::into))
+            .map_err(|e| format!("Failed to write '{}' to package: {}", name, e))
+    };
+
+    add_file("collection.anki2", &collection)?;
+    let mut media_map = HashMap::new();
+    for (name, path) in notes.iter().filter_map(|note| note.media.as_ref()) {
+        let bytes =
+            fs::read(path).map_err(|e| format!("Failed to read audio clip {:?}: {}", path, e))?;
+        let index = media_map.len().to_string();
+        add_file(&index, &bytes)?;
+        media_map.insert(index, name.clone());
+    }
+    let media_json = serde_json::to_string(&media_map)
+        .map_err(|e| format!("Failed to serialize media map: {}", e))?;
+    add_file("media", media_json.as_bytes())?;
+
+    zip.finish()
+        .map_err(|e| format!("Failed to finish package: {}", e))?;
+    Ok(())
+}
+
+/// Builds the notes of `cards`, resolving their audio clips in AppLocalData.
+pub(crate) fn notes_from_cards(
+    app_handle: &AppHandle,
+    cards: &[AnkiExportCard],
+) -> Result<Vec<AnkiNote>, String> {
+    cards
+        .iter()
+        .map(|card| {
+            let media = card
+                .audio_clip
+                .as_ref()
+                .map(|clip| {
+                    app_handle
+                        .path()
+                        .resolve(clip, BaseDirectory::AppLocalData)
+                        .map_err(|e| format!("Failed to resolve path '{}': {}", clip, e))
+                })
+                .transpose()?;
+            Ok(AnkiNote::from_card(&card.content, media))
+        })
+        .collect()
+}
+
+#[tauri::command]
+pub async fn export_anki_package(
+    app_handle: AppHandle,
+    cards: Vec<AnkiExportCard>,
+    deck_name: String,
+    note_type: Option<AnkiNoteType>,
+    output_path: String,
+) -> Result<(), String> {
+    info!(
+        "export_anki_package: {} cards to {} ({})",
+        cards.len(),
+        output_path,
+        deck_name
+    );
+    let notes = notes_from_cards(&app_handle, &cards)?;
+
+    create_anki_package(
+        Path::new(&output_path),
+        &deck_name,
+        &note_type.unwrap_or_default(),
+        &notes,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use zip::ZipArchive;
+
+    fn content(id: &str, sentence: &str) -> SentenceCardContent {
+        SentenceCardContent {
+            id: id.to_string(),
+            expression: "pull an all-nighter".to_string(),
+            sentence: sentence.to_string(),
+            contextual_definition: "徹夜する".to_string(),
+            core_meaning: "一晩中起きていること".to_string(),
+            part_of_speech: "慣用句".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sentence_html_keeps_highlighting_only() {
+        assert_eq!(
+            sentence_html("I <b>can't</b> say 1 < 2 & <i>more</i>"),
+            "I <b>can't</b> say 1 &lt; 2 &amp; &lt;i&gt;more&lt;/i&gt;"
+        );
+    }
+
+    #[test]
+    fn test_content_reads_example_sentence_alias() {
+        let content: SentenceCardContent = serde_json::from_str(
+            r#"{"id":"a","expression":"x","exampleSentence":"<b>x</b> y",
+                "contextualDefinition":"d","coreMeaning":"m","status":"active"}"#,
+        )
+        .unwrap();
+        assert_eq!(content.sentence, "<b>x</b> y");
+        assert_eq!(content.part_of_speech, "");
+    }
+
+    #[test]
+    fn test_create_anki_package_with_audio() {
+        let dir = std::env::temp_dir().join(format!("kotonoha-anki-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let clip_path = dir.join("1200-3400.ogg");
+        fs::write(&clip_path, b"OggS").unwrap();
+        let notes = vec![
+            AnkiNote::from_card(
+                &content("card-1", "I had to <b>pull an all-nighter</b>."),
+                Some(clip_path),
+            ),
+            AnkiNote::from_card(&content("card-2", "No audio."), None),
+        ];
+        let package_path = dir.join("deck.apkg");
+
+        create_anki_package(&package_path, "Kotonoha", &AnkiNoteType::default(), &notes).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&package_path).unwrap()).unwrap();
+        let mut media = String::new();
+        archive
+            .by_name("media")
+            .unwrap()
+            .read_to_string(&mut media)
+            .unwrap();
+        assert_eq!(media, r#"{"0":"kotonoha_card-1.ogg"}"#);
+        let mut clip = vec![];
+        archive
+            .by_name("0")
+            .unwrap()
+            .read_to_end(&mut clip)
+            .unwrap();
+        assert_eq!(clip, b"OggS");
+
+        let collection_path = dir.join("collection.anki2");
+        let mut collection = File::create(&collection_path).unwrap();
+        std::io::copy(
+            &mut archive.by_name("collection.anki2").unwrap(),
+            &mut collection,
+        )
+        .unwrap();
+        let conn = Connection::open(&collection_path).unwrap();
+        let fields: Vec<String> = conn
+            .prepare("SELECT flds FROM notes ORDER BY id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(fields.len(), 2);
+        let first: Vec<&str> = fields[0].split(FIELD_SEPARATOR).collect();
+        assert_eq!(first[1], "I had to <b>pull an all-nighter</b>.");
+        assert_eq!(first[5], "[sound:kotonoha_card-1.ogg]");
+        let card_count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM cards", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(card_count, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_simultaneous_exports_do_not_share_the_collection() {
+        let dir = std::env::temp_dir().join(format!(
+            "kotonoha-anki-parallel-test-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+
+        let package_paths: Vec<PathBuf> = (0..4)
+            .map(|i| dir.join(format!("deck-{}.apkg", i)))
+            .collect();
+        std::thread::scope(|scope| {
+            for (i, package_path) in package_paths.iter().enumerate() {
+                scope.spawn(move || {
+                    let notes = vec![AnkiNote::from_card(
+                        &content(&format!("card-{}", i), "Sentence."),
+                        None,
+                    )];
+                    create_anki_package(package_path, "Kotonoha", &AnkiNoteType::default(), &notes)
+                        .unwrap();
+                });
+            }
+        });
+
+        for (i, package_path) in package_paths.iter().enumerate() {
+            let mut archive = ZipArchive::new(File::open(package_path).unwrap()).unwrap();
+            let collection_path = dir.join(format!("collection-{}.anki2", i));
+            std::io::copy(
+                &mut archive.by_name("collection.anki2").unwrap(),
+                &mut File::create(&collection_path).unwrap(),
+            )
+            .unwrap();
+            let conn = Connection::open(&collection_path).unwrap();
+            let guid: String = conn
+                .query_row("SELECT guid FROM notes", [], |row| row.get(0))
+                .unwrap();
+            assert_eq!(guid, format!("card-{}", i));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
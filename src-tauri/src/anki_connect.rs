@@ -0,0 +1,524 @@
+// cSpell:words anki
+use base64::{engine::general_purpose, Engine as _};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::fs;
+use tauri::AppHandle;
+
+use crate::anki::{
+    notes_from_cards, AnkiExportCard, AnkiNote, AnkiNoteType, DEFAULT_NOTE_TYPE_NAME, FIELD_NAMES,
+};
+
+const DEFAULT_ANKI_CONNECT_URL: &str = "http://127.0.0.1:8765";
+const ANKI_CONNECT_VERSION: u32 = 6;
+/// Every pushed note is tagged with this prefix followed by the card id, which
+/// is how cards that are already in Anki are found.
+const CARD_TAG_PREFIX: &str = "kotonoha::";
+
+#[derive(Deserialize)]
+struct AnkiConnectResponse<T> {
+    result: Option<T>,
+    error: Option<String>,
+}
+
+/// A client for the AnkiConnect add-on's JSON-RPC API.
+pub(crate) struct AnkiConnectClient {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl AnkiConnectClient {
+    pub(crate) fn new(url: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+        }
+    }
+
+    async fn invoke<T: DeserializeOwned>(&self, action: &str, params: Value) -> Result<T, String> {
+        let request = json!({
+            "action": action,
+            "version": ANKI_CONNECT_VERSION,
+            "params": params,
+        });
+        let response = self
+            .client
+            .post(&self.url)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to connect to AnkiConnect: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("HTTP error: {}", response.status()));
+        }
+        let response: AnkiConnectResponse<T> = response
+            .json()
+            .await
+            .map_err(|e| format!("Invalid AnkiConnect response: {}", e))?;
+        match response {
+            AnkiConnectResponse {
+                error: Some(error), ..
+            } => Err(format!("AnkiConnect {} failed: {}", action, error)),
+            AnkiConnectResponse {
+                result: Some(result),
+                ..
+            } => Ok(result),
+            _ => Err(format!("AnkiConnect {} returned no result", action)),
+        }
+    }
+
+    pub(crate) async fn find_notes(&self, query: &str) -> Result<Vec<i64>, String> {
+        self.invoke("findNotes", json!({ "query": query })).await
+    }
+
+    pub(crate) async fn store_media_file(
+        &self,
+        filename: &str,
+        data: &[u8],
+    ) -> Result<String, String> {
+        self.invoke(
+            "storeMediaFile",
+            json!({
+                "filename": filename,
+                "data": general_purpose::STANDARD.encode(data),
+            }),
+        )
+        .await
+    }
+
+    pub(crate) async fn model_names(&self) -> Result<Vec<String>, String> {
+        self.invoke("modelNames", json!({})).await
+    }
+
+    pub(crate) async fn create_model(&self, note_type: &AnkiNoteType) -> Result<Value, String> {
+        self.invoke(
+            "createModel",
+            json!({
+                "modelName": note_type.name(),
+                "inOrderFields": FIELD_NAMES,
+                "css": note_type.css(),
+                "isCloze": false,
+                "cardTemplates": [{
+                    "Name": "Card 1",
+                    "Front": note_type.front_template(),
+                    "Back": note_type.back_template(),
+                }],
+            }),
+        )
+        .await
+    }
+
+    pub(crate) async fn create_deck(&self, deck_name: &str) -> Result<i64, String> {
+        self.invoke("createDeck", json!({ "deck": deck_name }))
+            .await
+    }
+
+    /// Returns the id of each added note, or `None` for notes Anki rejected.
+    pub(crate) async fn add_notes(&self, notes: Vec<Value>) -> Result<Vec<Option<i64>>, String> {
+        self.invoke("addNotes", json!({ "notes": notes })).await
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(
+    tag = "outcome",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum AnkiPushOutcome {
+    Added { note_id: i64 },
+    AlreadyInAnki { note_id: i64 },
+    Failed { message: String },
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AnkiPushResult {
+    pub card_id: String,
+    #[serde(flatten)]
+    pub outcome: AnkiPushOutcome,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AnkiPushReport {
+    pub results: Vec<AnkiPushResult>,
+    /// Cards that are in Anki now, whether added or already there. The
+    /// frontend marks them as sent through its card repository.
+    pub sent_card_ids: Vec<String>,
+}
+
+impl AnkiPushReport {
+    fn new(results: Vec<AnkiPushResult>) -> Self {
+        let sent_card_ids = results
+            .iter()
+            .filter(|r| !matches!(r.outcome, AnkiPushOutcome::Failed { .. }))
+            .map(|r| r.card_id.clone())
+            .collect();
+        Self {
+            results,
+            sent_card_ids,
+        }
+    }
+}
+
+fn card_tag(card_id: &str) -> String {
+    format!("{}{}", CARD_TAG_PREFIX, card_id)
+}
+
+fn note_json(note: &AnkiNote, deck_name: &str, model_name: &str) -> Value {
+    let fields: Map<String, Value> = FIELD_NAMES
+        .iter()
+        .zip(&note.fields)
+        .map(|(name, value)| (name.to_string(), Value::from(value.as_str())))
+        .collect();
+    json!({
+        "deckName": deck_name,
+        "modelName": model_name,
+        "fields": fields,
+        "tags": ["kotonoha", card_tag(&note.guid)],
+        // cards are deduplicated by their id, not by the first field
+        "options": { "allowDuplicate": true },
+    })
+}
+
+/// Creates the note type with the layout of the exported packages, unless the
+/// profile already has it, e.g. from importing a package.
+async fn ensure_note_type(client: &AnkiConnectClient, model_name: &str) -> Result<(), String> {
+    if client
+        .model_names()
+        .await?
+        .iter()
+        .any(|name| name == model_name)
+    {
+        return Ok(());
+    }
+    info!("Creating Anki note type {}", model_name);
+    let note_type = AnkiNoteType {
+        name: Some(model_name.to_string()),
+        ..Default::default()
+    };
+    client.create_model(&note_type).await?;
+    Ok(())
+}
+
+/// Adds the notes that are not in Anki yet, uploading their audio first.
+/// Only a failure to reach AnkiConnect is an error; problems with single notes
+/// are reported in their results.
+pub(crate) async fn push_notes(
+    client: &AnkiConnectClient,
+    deck_name: &str,
+    model_name: &str,
+    notes: &[AnkiNote],
+) -> Result<Vec<AnkiPushResult>, String> {
+    let mut outcomes: Vec<Option<AnkiPushOutcome>> = vec![None; notes.len()];
+    let mut pending = vec![];
+    for (i, note) in notes.iter().enumerate() {
+        let query = format!("tag:{}", card_tag(&note.guid));
+        if let Some(&note_id) = client.find_notes(&query).await?.first() {
+            outcomes[i] = Some(AnkiPushOutcome::AlreadyInAnki { note_id });
+            continue;
+        }
+        if let Some((filename, path)) = &note.media {
+            let stored = match fs::read(path) {
+                Ok(data) => client.store_media_file(filename, &data).await,
+                Err(e) => Err(format!("Failed to read audio clip {:?}: {}", path, e)),
+            };
+            if let Err(message) = stored {
+                outcomes[i] = Some(AnkiPushOutcome::Failed { message });
+                continue;
+            }
+        }
+        pending.push(i);
+    }
+
+    if !pending.is_empty() {
+        ensure_note_type(client, model_name).await?;
+        client.create_deck(deck_name).await?;
+        let note_values = pending
+            .iter()
+            .map(|&i| note_json(&notes[i], deck_name, model_name))
+            .collect();
+        match client.add_notes(note_values).await {
+            Ok(note_ids) => {
+                for (&i, note_id) in pending.iter().zip(note_ids) {
+                    outcomes[i] = Some(match note_id {
+                        Some(note_id) => AnkiPushOutcome::Added { note_id },
+                        None => AnkiPushOutcome::Failed {
+                            message: "Anki rejected the note".to_string(),
+                        },
+                    });
+                }
+            }
+            Err(message) => {
+                warn!("addNotes failed: {}", message);
+                for &i in &pending {
+                    outcomes[i] = Some(AnkiPushOutcome::Failed {
+                        message: message.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    Ok(notes
+        .iter()
+        .zip(outcomes)
+        .map(|(note, outcome)| AnkiPushResult {
+            card_id: note.guid.clone(),
+            outcome: outcome.unwrap_or(AnkiPushOutcome::Failed {
+                message: "AnkiConnect did not answer for the note".to_string(),
+            }),
+        })
+        .collect())
+}
+
+#[tauri::command]
+pub async fn push_cards_to_anki(
+    app_handle: AppHandle,
+    cards: Vec<AnkiExportCard>,
+    deck_name: String,
+    model_name: Option<String>,
+    anki_connect_url: Option<String>,
+) -> Result<AnkiPushReport, String> {
+    info!("push_cards_to_anki: {} cards to {}", cards.len(), deck_name);
+    let notes = notes_from_cards(&app_handle, &cards)?;
+    let client = AnkiConnectClient::new(
+        anki_connect_url
+            .as_deref()
+            .unwrap_or(DEFAULT_ANKI_CONNECT_URL),
+    );
+    let results = push_notes(
+        &client,
+        &deck_name,
+        model_name.as_deref().unwrap_or(DEFAULT_NOTE_TYPE_NAME),
+        &notes,
+    )
+    .await?;
+
+    Ok(AnkiPushReport::new(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anki::SentenceCardContent;
+    use std::{
+        collections::HashMap,
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    type Requests = Arc<Mutex<Vec<Value>>>;
+
+    /// The state of the mock Anki profile.
+    struct MockAnki {
+        /// Note id of every card already in Anki.
+        existing: HashMap<String, i64>,
+        models: Vec<String>,
+    }
+
+    fn respond(request: &Value, anki: &MockAnki) -> Value {
+        let params = &request["params"];
+        match request["action"].as_str().unwrap() {
+            "findNotes" => {
+                let query = params["query"].as_str().unwrap();
+                let card_id = query.trim_start_matches("tag:kotonoha::");
+                json!(anki.existing.get(card_id).into_iter().collect::<Vec<_>>())
+            }
+            "modelNames" => json!(anki.models),
+            "createModel" => json!({ "id": 3000, "name": params["modelName"] }),
+            "storeMediaFile" => params["filename"].clone(),
+            "createDeck" => json!(1),
+            "addNotes" => {
+                let count = params["notes"].as_array().unwrap().len() as i64;
+                json!((0..count).map(|i| 2000 + i).collect::<Vec<_>>())
+            }
+            action => panic!("unexpected action {}", action),
+        }
+    }
+
+    fn serve(stream: TcpStream, anki: &MockAnki, requests: &Requests) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+        loop {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                let line = line.trim_end();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let request: Value = serde_json::from_slice(&body).unwrap();
+            let response = json!({ "result": respond(&request, anki), "error": null }).to_string();
+            requests.lock().unwrap().push(request);
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                response.len(),
+                response
+            )
+            .unwrap();
+        }
+    }
+
+    /// Starts a minimal AnkiConnect on a free port and returns its URL.
+    fn spawn_mock_anki_connect(anki: MockAnki) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let recorded = requests.clone();
+        let anki = Arc::new(anki);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (anki, recorded) = (anki.clone(), recorded.clone());
+                thread::spawn(move || serve(stream.unwrap(), &anki, &recorded));
+            }
+        });
+        (url, requests)
+    }
+
+    fn note(id: &str, media: Option<std::path::PathBuf>) -> AnkiNote {
+        let content = SentenceCardContent {
+            id: id.to_string(),
+            expression: "almost there".to_string(),
+            sentence: "It's <b>almost there</b>.".to_string(),
+            contextual_definition: "もう少し".to_string(),
+            core_meaning: "目標まであと少しの状態".to_string(),
+            part_of_speech: "表現".to_string(),
+        };
+        AnkiNote::from_card(&content, media)
+    }
+
+    #[test]
+    fn test_push_notes_skips_cards_already_in_anki() {
+        let clip_path =
+            std::env::temp_dir().join(format!("kotonoha-push-{}.ogg", std::process::id()));
+        fs::write(&clip_path, b"OggS").unwrap();
+        let (url, requests) = spawn_mock_anki_connect(MockAnki {
+            existing: HashMap::from([("card-1".to_string(), 1000)]),
+            models: vec![DEFAULT_NOTE_TYPE_NAME.to_string()],
+        });
+        let client = AnkiConnectClient::new(&url);
+        let notes = vec![
+            note("card-1", None),
+            note("card-2", Some(clip_path.clone())),
+        ];
+
+        let results = tauri::async_runtime::block_on(push_notes(
+            &client,
+            "Kotonoha",
+            DEFAULT_NOTE_TYPE_NAME,
+            &notes,
+        ))
+        .unwrap();
+        fs::remove_file(&clip_path).unwrap();
+
+        assert_eq!(
+            results,
+            vec![
+                AnkiPushResult {
+                    card_id: "card-1".to_string(),
+                    outcome: AnkiPushOutcome::AlreadyInAnki { note_id: 1000 },
+                },
+                AnkiPushResult {
+                    card_id: "card-2".to_string(),
+                    outcome: AnkiPushOutcome::Added { note_id: 2000 },
+                },
+            ]
+        );
+        let mut results = results;
+        results.push(AnkiPushResult {
+            card_id: "card-3".to_string(),
+            outcome: AnkiPushOutcome::Failed {
+                message: "Anki rejected the note".to_string(),
+            },
+        });
+        assert_eq!(
+            AnkiPushReport::new(results).sent_card_ids,
+            ["card-1", "card-2"]
+        );
+
+        let requests = requests.lock().unwrap();
+        let actions: Vec<&str> = requests
+            .iter()
+            .map(|r| r["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                "findNotes",
+                "findNotes",
+                "storeMediaFile",
+                "modelNames",
+                "createDeck",
+                "addNotes"
+            ]
+        );
+        assert_eq!(requests[2]["params"]["filename"], "kotonoha_card-2.ogg");
+        assert_eq!(requests[2]["params"]["data"], "T2dnUw==");
+        let added = &requests[5]["params"]["notes"][0];
+        assert_eq!(added["fields"]["Sentence"], "It's <b>almost there</b>.");
+        assert_eq!(added["fields"]["Audio"], "[sound:kotonoha_card-2.ogg]");
+        assert_eq!(added["tags"][1], "kotonoha::card-2");
+    }
+
+    #[test]
+    fn test_push_notes_creates_the_note_type_on_a_new_profile() {
+        let (url, requests) = spawn_mock_anki_connect(MockAnki {
+            existing: HashMap::new(),
+            models: vec!["Basic".to_string()],
+        });
+        let client = AnkiConnectClient::new(&url);
+
+        let results = tauri::async_runtime::block_on(push_notes(
+            &client,
+            "Kotonoha",
+            DEFAULT_NOTE_TYPE_NAME,
+            &[note("card-1", None)],
+        ))
+        .unwrap();
+
+        assert_eq!(results[0].outcome, AnkiPushOutcome::Added { note_id: 2000 });
+        let requests = requests.lock().unwrap();
+        let actions: Vec<&str> = requests
+            .iter()
+            .map(|r| r["action"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                "findNotes",
+                "modelNames",
+                "createModel",
+                "createDeck",
+                "addNotes"
+            ]
+        );
+        let model = &requests[2]["params"];
+        assert_eq!(model["modelName"], DEFAULT_NOTE_TYPE_NAME);
+        assert_eq!(model["inOrderFields"], json!(FIELD_NAMES));
+        assert!(model["cardTemplates"][0]["Front"]
+            .as_str()
+            .unwrap()
+            .contains("{{Sentence}}"));
+        assert_eq!(
+            requests[4]["params"]["notes"][0]["modelName"],
+            DEFAULT_NOTE_TYPE_NAME
+        );
+    }
+}
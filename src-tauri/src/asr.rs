@@ -0,0 +1,561 @@
+// cSpell:words sherpa parakeet nemo onnx
+use log::{debug, error, info};
+use rodio::Source;
+use rubato::{FftFixedIn, ResampleError, Resampler};
+use serde::Serialize;
+use sherpa_rs::transducer::{TransducerConfig, TransducerRecognizer};
+use std::{
+    collections::HashMap,
+    ops::Range,
+    path::Path,
+    sync::{LazyLock, Mutex},
+};
+use tauri::{path::BaseDirectory, AppHandle, Emitter, Manager};
+use tokio_util::sync::CancellationToken;
+
+use crate::alignment::align_lines;
+use crate::audio::{downmix_to_mono, open_audio_decoder};
+
+const TRANSCRIPTION_ID: &str = "transcription";
+
+/// Sample rate expected by the Parakeet TDT model.
+const ASR_SAMPLE_RATE: u32 = 16000;
+/// Input frames the resampler processes at a time.
+const RESAMPLE_CHUNK_FRAMES: usize = 4096;
+const RESAMPLE_SUB_CHUNKS: usize = 2;
+/// Upper bound for the length of audio passed to the recognizer at once.
+const MAX_CHUNK_SECONDS: u32 = 30;
+/// Window at the end of each chunk in which we look for a pause to cut at.
+const CHUNK_SEARCH_SECONDS: u32 = 5;
+/// Frame length used to find the quietest point inside the search window.
+const ENERGY_FRAME_MS: u32 = 50;
+/// Tokens carry only their start time, so the last token of a segment is
+/// assumed to last at most this long.
+const MAX_TOKEN_DURATION_MS: u32 = 800;
+
+static TRANSCRIPTION_CANCEL_TOKENS: LazyLock<Mutex<HashMap<String, CancellationToken>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TranscriptionSegment {
+    pub start_time_ms: u32,
+    pub end_time_ms: Option<u32>,
+    pub original_text: String,
+    pub words: Vec<TranscriptionWord>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TranscriptionWord {
+    pub text: String,
+    pub start_ms: u32,
+    pub end_ms: u32,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct TranscriptionProgressPayload {
+    progress: u8, // 0-100
+    start_ms: u32,
+    end_ms: u32,
+    text: String,
+}
+
+fn create_recognizer(model_dir: &Path) -> Result<TransducerRecognizer, String> {
+    let model_file = |name: &str| -> Result<String, String> {
+        let path = model_dir.join(name);
+        if !path.exists() {
+            return Err(format!("Model file not found: {:?}", path));
+        }
+        Ok(path.to_string_lossy().to_string())
+    };
+
+    let num_threads = std::thread::available_parallelism()
+        .map(|n| n.get().min(4) as i32)
+        .unwrap_or(2);
+
+    let config = TransducerConfig {
+        encoder: model_file("encoder.int8.onnx")?,
+        decoder: model_file("decoder.int8.onnx")?,
+        joiner: model_file("joiner.int8.onnx")?,
+        tokens: model_file("tokens.txt")?,
+        model_type: "nemo_transducer".to_string(),
+        decoding_method: "greedy_search".to_string(),
+        sample_rate: ASR_SAMPLE_RATE as i32,
+        num_threads,
+        debug: false,
+        ..Default::default()
+    };
+
+    TransducerRecognizer::new(config).map_err(|e| format!("Could not create recognizer: {:?}", e))
+}
+
+/// Resamples a stream of mono samples, so that only the (much smaller)
+/// resampled signal has to be kept in memory.
+///
+/// The FFT resampler is band-limited, so content above the new Nyquist
+/// frequency is filtered out instead of aliasing into the output.
+pub(crate) fn resample<I>(samples: I, from_rate: u32, to_rate: u32) -> Result<Vec<f32>, String>
+where
+    I: Iterator<Item = f32>,
+{
+    if from_rate == to_rate {
+        return Ok(samples.collect());
+    }
+    let mut resampler = FftFixedIn::<f32>::new(
+        from_rate as usize,
+        to_rate as usize,
+        RESAMPLE_CHUNK_FRAMES,
+        RESAMPLE_SUB_CHUNKS,
+        1,
+    )
+    .map_err(|e| format!("Could not create resampler: {}", e))?;
+    let resample_error = |e: ResampleError| format!("Could not resample audio: {}", e);
+
+    let mut output = Vec::new();
+    let mut chunk = Vec::with_capacity(resampler.input_frames_next());
+    let mut input_len = 0;
+    for sample in samples {
+        chunk.push(sample);
+        if chunk.len() == resampler.input_frames_next() {
+            let resampled = resampler.process(&[&chunk], None).map_err(resample_error)?;
+            output.extend_from_slice(&resampled[0]);
+            input_len += chunk.len();
+            chunk.clear();
+        }
+    }
+    input_len += chunk.len();
+
+    // Feed the rest and then silence until the delayed frames are out.
+    let delay = resampler.output_delay();
+    let output_len = (input_len as u64 * to_rate as u64 / from_rate as u64) as usize;
+    while output.len() < delay + output_len {
+        let resampled = if chunk.is_empty() {
+            resampler.process_partial(None::<&[Vec<f32>]>, None)
+        } else {
+            resampler.process_partial(Some(&[&chunk]), None)
+        }
+        .map_err(resample_error)?;
+        output.extend_from_slice(&resampled[0]);
+        chunk.clear();
+    }
+    output.drain(..delay);
+    output.truncate(output_len);
+    Ok(output)
+}
+
+/// Splits the samples into chunks no longer than `MAX_CHUNK_SECONDS`, cutting
+/// at the quietest frame near the end of each chunk so that words are not
+/// split in the middle.
+fn split_into_chunks(samples: &[f32], sample_rate: u32) -> Vec<Range<usize>> {
+    let max_len = (sample_rate * MAX_CHUNK_SECONDS) as usize;
+    let search_len = (sample_rate * CHUNK_SEARCH_SECONDS) as usize;
+    let frame_len = ((sample_rate * ENERGY_FRAME_MS / 1000) as usize).max(1);
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < samples.len() {
+        let hard_end = start + max_len;
+        if hard_end >= samples.len() {
+            chunks.push(start..samples.len());
+            break;
+        }
+
+        let search_start = hard_end.saturating_sub(search_len).max(start + frame_len);
+        let mut best_cut = hard_end;
+        let mut best_energy = f32::MAX;
+        let mut frame_start = search_start;
+        while frame_start + frame_len <= hard_end {
+            let frame = &samples[frame_start..frame_start + frame_len];
+            let energy = frame.iter().map(|s| s * s).sum::<f32>();
+            if energy < best_energy {
+                best_energy = energy;
+                best_cut = frame_start + frame_len / 2;
+            }
+            frame_start += frame_len;
+        }
+
+        chunks.push(start..best_cut);
+        start = best_cut;
+    }
+    chunks
+}
+
+fn is_sentence_end(token: &str) -> bool {
+    token
+        .trim_end()
+        .ends_with(['.', '?', '!', '。', '？', '！'])
+}
+
+/// Groups recognized tokens into sentence-like segments, keeping the timing
+/// of every word inside each segment.
+///
+/// `timestamps` holds the start time of each token in seconds relative to the
+/// chunk, which itself starts at `offset_ms` and ends at `chunk_end_ms`.
+fn build_segments(
+    tokens: &[String],
+    timestamps: &[f32],
+    offset_ms: u32,
+    chunk_end_ms: u32,
+) -> Vec<TranscriptionSegment> {
+    let token_start_ms =
+        |index: usize| offset_ms + (timestamps[index].max(0.0) * 1000.0).round() as u32;
+
+    let count = tokens.len().min(timestamps.len());
+    let mut segments = Vec::new();
+    // (text, start of the first token, start of the last token)
+    let mut pending_words: Vec<(String, u32, u32)> = Vec::new();
+
+    for (index, token) in tokens.iter().take(count).enumerate() {
+        // SentencePiece marks word boundaries with U+2581
+        let token = token.replace('\u{2581}', " ");
+        let start_ms = token_start_ms(index);
+        match pending_words.last_mut() {
+            Some((text, _, last_ms)) if !token.starts_with(char::is_whitespace) => {
+                text.push_str(&token);
+                *last_ms = start_ms;
+            }
+            _ => pending_words.push((token.trim().to_string(), start_ms, start_ms)),
+        }
+
+        let is_last = index + 1 == count;
+        if !is_sentence_end(&token) && !is_last {
+            continue;
+        }
+
+        let next_start_ms = if is_last {
+            chunk_end_ms
+        } else {
+            token_start_ms(index + 1)
+        };
+        let mut words = Vec::with_capacity(pending_words.len());
+        for (i, (text, start_ms, last_ms)) in pending_words.iter().enumerate() {
+            let following_ms = pending_words
+                .get(i + 1)
+                .map(|(_, next_ms, _)| *next_ms)
+                .unwrap_or(next_start_ms);
+            words.push(TranscriptionWord {
+                text: text.clone(),
+                start_ms: *start_ms,
+                end_ms: (last_ms + MAX_TOKEN_DURATION_MS)
+                    .min(following_ms)
+                    .max(*start_ms),
+            });
+        }
+        pending_words.clear();
+        words.retain(|word| !word.text.is_empty());
+
+        let (Some(first), Some(last)) = (words.first(), words.last()) else {
+            continue;
+        };
+        let text = words
+            .iter()
+            .map(|word| word.text.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        segments.push(TranscriptionSegment {
+            start_time_ms: first.start_ms,
+            end_time_ms: Some(last.end_ms),
+            original_text: text,
+            words,
+        });
+    }
+    segments
+}
+
+fn samples_to_ms(samples: usize, sample_rate: u32) -> u32 {
+    (samples as u64 * 1000 / sample_rate as u64) as u32
+}
+
+fn process_transcription<F>(
+    recognizer: &mut TransducerRecognizer,
+    samples: &[f32],
+    cancel_token: &CancellationToken,
+    mut callback: F,
+) -> Result<Vec<TranscriptionSegment>, String>
+where
+    F: FnMut(u8, &TranscriptionSegment),
+{
+    let chunks = split_into_chunks(samples, ASR_SAMPLE_RATE);
+    let total_chunks = chunks.len().max(1); // avoid div-by-zero
+    let mut segments = Vec::new();
+
+    for (idx, chunk) in chunks.into_iter().enumerate() {
+        if cancel_token.is_cancelled() {
+            return Err("Transcription cancelled".to_string());
+        }
+
+        let offset_ms = samples_to_ms(chunk.start, ASR_SAMPLE_RATE);
+        let chunk_end_ms = samples_to_ms(chunk.end, ASR_SAMPLE_RATE);
+        let result = recognizer.transcribe(ASR_SAMPLE_RATE, &samples[chunk]);
+        debug!(
+            "Recognized chunk {} ({} - {} ms): {}",
+            idx, offset_ms, chunk_end_ms, result.text
+        );
+
+        let progress = (((idx + 1) * 100) / total_chunks) as u8;
+        for segment in build_segments(&result.tokens, &result.timestamps, offset_ms, chunk_end_ms) {
+            callback(progress, &segment);
+            segments.push(segment);
+        }
+    }
+    Ok(segments)
+}
+
+fn load_recognition_input(
+    app_handle: &AppHandle,
+    path: &str,
+    model_dir: &str,
+) -> Result<(TransducerRecognizer, Vec<f32>), String> {
+    let audio_path = app_handle
+        .path()
+        .resolve(path, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Failed to resolve path '{}': {}", path, e))?;
+    let model_path = app_handle
+        .path()
+        .resolve(model_dir, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Could not resolve model path: {:?}", e))?;
+
+    let recognizer = create_recognizer(&model_path)?;
+
+    let decoder = open_audio_decoder(&audio_path)?;
+    let sample_rate = decoder.sample_rate();
+    let channels = decoder.channels();
+    let samples = resample(
+        downmix_to_mono(decoder, channels),
+        sample_rate,
+        ASR_SAMPLE_RATE,
+    )?;
+    info!(
+        "Loaded {} ms of audio for recognition",
+        samples_to_ms(samples.len(), ASR_SAMPLE_RATE)
+    );
+
+    Ok((recognizer, samples))
+}
+
+fn emit_transcription_progress(
+    app_handle: &AppHandle,
+    progress: u8,
+    segment: &TranscriptionSegment,
+) {
+    app_handle
+        .emit(
+            "transcription-progress",
+            TranscriptionProgressPayload {
+                progress,
+                start_ms: segment.start_time_ms,
+                end_ms: segment.end_time_ms.unwrap_or(segment.start_time_ms),
+                text: segment.original_text.clone(),
+            },
+        )
+        .unwrap_or_else(|e| {
+            error!("Could not emit transcription-progress event: {:?}", e);
+        });
+}
+
+fn transcribe_file(
+    app_handle: &AppHandle,
+    path: &str,
+    model_dir: &str,
+    cancel_token: &CancellationToken,
+) -> Result<Vec<TranscriptionSegment>, String> {
+    let (mut recognizer, samples) = load_recognition_input(app_handle, path, model_dir)?;
+    process_transcription(
+        &mut recognizer,
+        &samples,
+        cancel_token,
+        |progress, segment| emit_transcription_progress(app_handle, progress, segment),
+    )
+}
+
+fn align_file(
+    app_handle: &AppHandle,
+    path: &str,
+    lines: &[String],
+    model_dir: &str,
+    cancel_token: &CancellationToken,
+) -> Result<Vec<TranscriptionSegment>, String> {
+    let (mut recognizer, samples) = load_recognition_input(app_handle, path, model_dir)?;
+    let recognized = process_transcription(
+        &mut recognizer,
+        &samples,
+        cancel_token,
+        |progress, segment| emit_transcription_progress(app_handle, progress, segment),
+    )?;
+    let words: Vec<TranscriptionWord> = recognized
+        .into_iter()
+        .flat_map(|segment| segment.words)
+        .collect();
+    info!(
+        "Aligning {} lines against {} recognized words",
+        lines.len(),
+        words.len()
+    );
+    Ok(align_lines(lines, &words))
+}
+
+/// Registers a new recognition job. Only one job may use the recognizer at a
+/// time, and `cancel_transcription` cancels whichever job is running.
+fn begin_recognition_job() -> Result<CancellationToken, String> {
+    let mut tokens = TRANSCRIPTION_CANCEL_TOKENS.lock().unwrap();
+    if tokens.contains_key(TRANSCRIPTION_ID) {
+        return Err("Transcription already in progress".to_string());
+    }
+    let cancel_token = CancellationToken::new();
+    tokens.insert(TRANSCRIPTION_ID.to_string(), cancel_token.clone());
+    Ok(cancel_token)
+}
+
+fn end_recognition_job() {
+    TRANSCRIPTION_CANCEL_TOKENS
+        .lock()
+        .unwrap()
+        .remove(TRANSCRIPTION_ID);
+}
+
+#[tauri::command]
+pub async fn start_transcription(
+    app_handle: AppHandle,
+    path: String,
+    model_dir: String,
+) -> Result<Vec<TranscriptionSegment>, String> {
+    let cancel_token = begin_recognition_job()?;
+    let result = transcribe_file(&app_handle, &path, &model_dir, &cancel_token);
+    // 完了またはエラー時にトークンを削除
+    end_recognition_job();
+    result
+}
+
+#[tauri::command]
+pub async fn align_transcript(
+    app_handle: AppHandle,
+    path: String,
+    lines: Vec<String>,
+    model_dir: String,
+) -> Result<Vec<TranscriptionSegment>, String> {
+    let cancel_token = begin_recognition_job()?;
+    let result = align_file(&app_handle, &path, &lines, &model_dir, &cancel_token);
+    // 完了またはエラー時にトークンを削除
+    end_recognition_job();
+    result
+}
+
+#[tauri::command]
+pub async fn cancel_transcription() -> Result<(), String> {
+    if let Some(token) = TRANSCRIPTION_CANCEL_TOKENS
+        .lock()
+        .unwrap()
+        .remove(TRANSCRIPTION_ID)
+    {
+        token.cancel();
+        Ok(())
+    } else {
+        Err("No transcription in progress".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn word(text: &str, start_ms: u32, end_ms: u32) -> TranscriptionWord {
+        TranscriptionWord {
+            text: text.to_string(),
+            start_ms,
+            end_ms,
+        }
+    }
+
+    #[test]
+    fn test_build_segments_splits_on_sentence_punctuation() {
+        let tokens = tokens(&["▁Hello", ".", "▁How", "▁are", "▁you", "?"]);
+        let timestamps = [0.0, 0.4, 2.0, 2.2, 2.4, 2.6];
+
+        let segments = build_segments(&tokens, &timestamps, 1000, 5000);
+
+        assert_eq!(
+            segments,
+            vec![
+                TranscriptionSegment {
+                    start_time_ms: 1000,
+                    end_time_ms: Some(2200),
+                    original_text: "Hello.".to_string(),
+                    words: vec![word("Hello.", 1000, 2200)],
+                },
+                TranscriptionSegment {
+                    start_time_ms: 3000,
+                    end_time_ms: Some(4400),
+                    original_text: "How are you?".to_string(),
+                    words: vec![
+                        word("How", 3000, 3200),
+                        word("are", 3200, 3400),
+                        word("you?", 3400, 4400),
+                    ],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_segments_joins_subword_tokens() {
+        let tokens = tokens(&["▁pod", "cast", "▁episode"]);
+        let timestamps = [0.0, 0.2, 0.5];
+
+        let segments = build_segments(&tokens, &timestamps, 0, 2000);
+
+        assert_eq!(segments.len(), 1);
+        assert_eq!(
+            segments[0].words,
+            vec![word("podcast", 0, 500), word("episode", 500, 1300)]
+        );
+    }
+
+    fn tone_rms(frequency: f32, from_rate: u32, to_rate: u32) -> (usize, f32) {
+        let samples = (0..from_rate).map(|n| {
+            (2.0 * std::f32::consts::PI * frequency * n as f32 / from_rate as f32).sin() * 0.5
+        });
+        let resampled = resample(samples, from_rate, to_rate).unwrap();
+        // Skip the edges, where the tone starts and stops abruptly.
+        let middle = &resampled[resampled.len() / 4..resampled.len() * 3 / 4];
+        let rms = (middle.iter().map(|s| s * s).sum::<f32>() / middle.len() as f32).sqrt();
+        (resampled.len(), rms)
+    }
+
+    #[test]
+    fn test_resample_filters_content_above_the_new_nyquist_frequency() {
+        // A tone below 8kHz survives downsampling to 16kHz...
+        let (len, rms) = tone_rms(1000.0, 48000, 16000);
+        assert_eq!(len, 16000);
+        assert!((rms - 0.5 / 2.0_f32.sqrt()).abs() < 0.01, "{}", rms);
+        // ...but one above it is removed instead of aliasing to 4kHz.
+        let (len, rms) = tone_rms(12000.0, 44100, 16000);
+        assert_eq!(len, 16000);
+        assert!(rms < 0.01, "{}", rms);
+        // Upsampling keeps the length and the level too.
+        let (len, rms) = tone_rms(1000.0, 22050, 48000);
+        assert_eq!(len, 48000);
+        assert!((rms - 0.5 / 2.0_f32.sqrt()).abs() < 0.01, "{}", rms);
+    }
+
+    #[test]
+    fn test_split_into_chunks_cuts_at_silence() {
+        let sample_rate = 1000;
+        let mut samples = vec![0.5_f32; (sample_rate * 40) as usize];
+        // a pause 28 seconds in
+        for sample in &mut samples[28000..28200] {
+            *sample = 0.0;
+        }
+
+        let chunks = split_into_chunks(&samples, sample_rate);
+
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].end >= 28000 && chunks[0].end <= 28200);
+        assert_eq!(chunks[1], chunks[0].end..samples.len());
+    }
+}
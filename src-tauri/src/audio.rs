@@ -1,88 +1,437 @@
 use log::{debug, error, info, warn};
-use rodio::{Decoder, OutputStream, OutputStreamBuilder, Sink, Source};
-use serde::Serialize;
+use rodio::{cpal, Decoder, Sink, Source};
+use serde::{Deserialize, Serialize};
 use std::{
-    fs,
-    io::{BufReader, Cursor},
-    sync::{Arc, Mutex},
+    collections::HashSet,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
     thread,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tauri::{path::BaseDirectory, AppHandle, Emitter, Manager, State};
 
+use crate::loudness::{normalization_gain, LoudnessMeter};
+use crate::microphone::MicrophoneRecording;
+use crate::output_device::{open_output_stream, output_devices, AudioOutput, OutputDevice};
+use crate::playback::{
+    HoldReason, PlaybackControl, PlaybackRange, PlaybackSource, RangeLoopCompleted,
+    MAX_PLAYBACK_RATE, MIN_PLAYBACK_RATE,
+};
+use crate::waveform::{
+    load_peak_file, quantize_peak, save_peak_file, PeakFile, SourceFingerprint,
+    BASE_FRAMES_PER_PEAK,
+};
+
 const POSITION_UPDATE_FREQUENCY: u64 = 200;
+/// Directory under AppLocalData the learner's recordings are saved in.
+const RECORDING_DIR: &str = "recordings";
+
+// Voice activity detection parameters
+const VAD_FRAME_MS: u32 = 20;
+const VAD_DEFAULT_MIN_SILENCE_MS: u32 = 300;
+const VAD_DEFAULT_PADDING_MS: u32 = 100;
+const VAD_MIN_SPEECH_MS: u32 = 100;
+/// A frame is speech if it is this much louder than the estimated noise floor.
+const VAD_THRESHOLD_ABOVE_NOISE_DB: f32 = 12.0;
+/// Frames quieter than this are always treated as silence.
+const VAD_ABSOLUTE_THRESHOLD_DB: f32 = -55.0;
 
 pub struct AudioState {
-    pub stream: Mutex<Option<OutputStream>>, // to keep the stream alive
-    pub sink: Arc<Mutex<Option<Sink>>>,      // Arc to share between threads
-    pub audio_data: Mutex<Option<Vec<u8>>>,
-    pub playback_position_tracker: Mutex<Option<thread::JoinHandle<()>>>,
+    pub stream: Mutex<OutputStreamState>, // to keep the stream alive
+    pub sink: Arc<Mutex<Option<Sink>>>,   // Arc to share between threads
+    /// Copied out with `opened_audio_path`, so that it is never locked
+    /// while `sink` is.
+    pub audio_path: Mutex<Option<PathBuf>>,
+    pub playback_position_tracker: Mutex<Option<PositionTracker>>, // started on first use
+    /// Position tracker threads alive, which should never be more than one.
+    pub tracker_threads: Arc<AtomicUsize>,
+    pub playback_control: Arc<PlaybackControl>, // shared with the playing source
+    pub playback_state: Arc<Mutex<PlaybackStateMachine>>,
+    pub secondary: Arc<SecondaryTrack>, // mixed into the same stream as `sink`
+    pub track_mixes: Mutex<TrackMixes>,
+    pub recording: Mutex<Option<ActiveRecording>>,
+    /// Files still being written, such as TTS output. Playback that reaches
+    /// the end of one waits there for more audio instead of ending.
+    pub growing_media: Mutex<HashSet<PathBuf>>,
 }
 
-#[derive(Clone)]
-struct DecodedAudio {
-    sample_rate: u32,
-    channels: u16,
-    samples: Vec<f32>,
-    total_duration: Option<Duration>,
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioTrack {
+    /// The episode.
+    Main,
+    /// Audio played after the episode, such as the TTS of a line.
+    Secondary,
+}
+
+/// A take of the learner being recorded to `path`, relative to AppLocalData.
+pub struct ActiveRecording {
+    path: String,
+    recording: MicrophoneRecording,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordedTake {
+    /// Relative to AppLocalData.
+    path: String,
+    duration_ms: u64,
+}
+
+/// Called on the audio thread when an output stream fails.
+type StreamErrorCallback = Arc<dyn Fn(cpal::StreamError) + Send + Sync>;
+
+/// Opens the output of `device_id`, or of the system default device if `None`.
+type OpenOutput = fn(Option<&str>, StreamErrorCallback) -> Result<Box<dyn AudioOutput>, String>;
+
+fn open_device_output(
+    device_id: Option<&str>,
+    on_error: StreamErrorCallback,
+) -> Result<Box<dyn AudioOutput>, String> {
+    let stream = open_output_stream(device_id, move |error| on_error(error))?;
+    Ok(Box::new(stream))
+}
+
+/// The output every track is mixed into.
+pub struct OutputStreamState {
+    output: Option<Box<dyn AudioOutput>>,
+    /// `None` for the system default device.
+    device_id: Option<String>,
+    /// Counts the streams opened, so that errors of a replaced stream are
+    /// ignored.
+    generation: u64,
+    /// Opens the devices; tests open outputs without a device instead.
+    open_output: OpenOutput,
+}
+
+impl Default for OutputStreamState {
+    fn default() -> Self {
+        Self {
+            output: None,
+            device_id: None,
+            generation: 0,
+            open_output: open_device_output,
+        }
+    }
+}
+
+impl OutputStreamState {
+    /// Opens a stream on `device_id`, replacing the current one on success.
+    fn open<E: PlaybackEmitter + Clone>(
+        &mut self,
+        emitter: &E,
+        device_id: Option<String>,
+    ) -> Result<(), String> {
+        let generation = self.generation + 1;
+        let error_emitter = emitter.clone();
+        let output = (self.open_output)(
+            device_id.as_deref(),
+            Arc::new(move |error| error_emitter.report_stream_error(generation, error)),
+        )?;
+        self.output = Some(output);
+        self.device_id = device_id;
+        self.generation = generation;
+        Ok(())
+    }
+}
+
+/// Payload of the `output-device-lost` event.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputDeviceLost {
+    /// `None` if the system default device was in use.
+    device_id: Option<String>,
+    message: String,
+}
+
+/// Volume and mute switch of one track.
+#[derive(Clone, Copy, Debug)]
+pub struct TrackMix {
+    volume: f32,
+    muted: bool,
+}
+
+impl Default for TrackMix {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            muted: false,
+        }
+    }
+}
+
+impl TrackMix {
+    fn effective_volume(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TrackMixes {
+    main: TrackMix,
+    secondary: TrackMix,
+}
+
+impl TrackMixes {
+    fn get_mut(&mut self, track: AudioTrack) -> &mut TrackMix {
+        match track {
+            AudioTrack::Main => &mut self.main,
+            AudioTrack::Secondary => &mut self.secondary,
+        }
+    }
+}
+
+/// A second sink on the output stream of the episode.
+///
+/// `play_line_with_tts` queues the TTS of a line here; the position tracker
+/// starts it once the main track has played the line to its end.
+#[derive(Default)]
+pub struct SecondaryTrack {
+    sink: Mutex<Option<Sink>>,
+    /// Start the sink when the main track holds at the end of its range.
+    queued: AtomicBool,
+}
+
+impl SecondaryTrack {
+    fn replace(&self, sink: Sink, queued: bool) {
+        if let Some(old_sink) = self.sink.lock().unwrap().replace(sink) {
+            old_sink.stop();
+        }
+        self.queued.store(queued, Ordering::SeqCst);
+    }
+
+    fn clear(&self) {
+        self.queued.store(false, Ordering::SeqCst);
+        if let Some(sink) = self.sink.lock().unwrap().take() {
+            sink.stop();
+        }
+    }
+
+    /// Starts the sink if it is queued. Returns whether it was started.
+    fn start_if_queued(&self) -> bool {
+        if !self.queued.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        match self.sink.lock().unwrap().as_ref() {
+            Some(sink) => {
+                sink.play();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn is_playing(&self) -> bool {
+        self.sink
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|sink| !sink.is_paused() && !sink.empty())
+    }
+
+    fn pause(&self) {
+        if let Some(sink) = self.sink.lock().unwrap().as_ref() {
+            sink.pause();
+        }
+    }
+
+    /// Resumes the sink if it has been started and has not finished yet.
+    /// Returns whether there was something to resume.
+    fn resume(&self) -> bool {
+        if self.queued.load(Ordering::SeqCst) {
+            return false;
+        }
+        match self.sink.lock().unwrap().as_ref() {
+            Some(sink) if !sink.empty() => {
+                sink.play();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn set_volume(&self, volume: f32) {
+        if let Some(sink) = self.sink.lock().unwrap().as_ref() {
+            sink.set_volume(volume);
+        }
+    }
+}
+
+/// A line of the episode followed by its TTS rendition.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LineWithTts {
+    start_ms: u32,
+    end_ms: u32,
+    /// Absolute, or relative to AppLocalData.
+    tts_audio_path: String,
+    tts_start_ms: u32,
+    tts_end_ms: u32,
 }
 
 #[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
 pub struct AudioInfo {
     duration: u64,
     peaks: Vec<f32>,
+    loudness_lufs: Option<f32>,
+    /// Only detected when `analyze_audio` is given `min_silence_ms`.
+    silences: Option<Vec<SilenceSegment>>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SpeechSegment {
+    start_ms: u32,
+    end_ms: u32,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SilenceSegment {
+    pub(crate) start_ms: u32,
+    pub(crate) end_ms: u32,
+}
+
+/// Payload of the `playback-state` event.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "state")]
+pub enum PlaybackStateEvent {
+    Playing,
+    Paused,
+    Stopped,
+    Ended,
+    Seeked { ms: u64 },
+    Error { message: String },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlaybackStatus {
+    Stopped,
+    Playing,
+    Paused,
+    Ended,
+}
+
+/// What happened to the playback, as reported by the commands and the tracker.
+#[derive(Debug)]
+pub(crate) enum PlaybackInput {
+    Play,
+    Pause,
+    Stop,
+    Seek(u64),
+    ReachedEnd,
+    Fail(String),
+}
+
+/// The authoritative playback state. Commands and the tracker only report
+/// inputs; the transitions decide which `playback-state` events are emitted,
+/// so the same state is never announced twice.
+pub struct PlaybackStateMachine {
+    status: PlaybackStatus,
+}
+
+impl Default for PlaybackStateMachine {
+    fn default() -> Self {
+        Self {
+            status: PlaybackStatus::Stopped,
+        }
+    }
+}
+
+impl PlaybackStateMachine {
+    fn handle(&mut self, input: PlaybackInput) -> Option<PlaybackStateEvent> {
+        use PlaybackStatus::*;
+        let (status, event) = match (self.status, input) {
+            (Playing, PlaybackInput::Play) => (Playing, None),
+            (_, PlaybackInput::Play) => (Playing, Some(PlaybackStateEvent::Playing)),
+            (Playing, PlaybackInput::Pause) => (Paused, Some(PlaybackStateEvent::Paused)),
+            (status, PlaybackInput::Pause) => (status, None),
+            (Stopped, PlaybackInput::Stop) => (Stopped, None),
+            (_, PlaybackInput::Stop) => (Stopped, Some(PlaybackStateEvent::Stopped)),
+            // Seeking prepares a paused sink when there was none, and leaves
+            // the end of the media.
+            (Stopped | Ended, PlaybackInput::Seek(ms)) => {
+                (Paused, Some(PlaybackStateEvent::Seeked { ms }))
+            }
+            (status, PlaybackInput::Seek(ms)) => (status, Some(PlaybackStateEvent::Seeked { ms })),
+            (Playing, PlaybackInput::ReachedEnd) => (Ended, Some(PlaybackStateEvent::Ended)),
+            (status, PlaybackInput::ReachedEnd) => (status, None),
+            (status, PlaybackInput::Fail(message)) => {
+                (status, Some(PlaybackStateEvent::Error { message }))
+            }
+        };
+        self.status = status;
+        event
+    }
 }
 
 impl Default for AudioState {
     fn default() -> Self {
         Self {
-            stream: Mutex::new(None),
+            stream: Mutex::new(OutputStreamState::default()),
             sink: Arc::new(Mutex::new(None)),
-            audio_data: Mutex::new(None),
+            audio_path: Mutex::new(None),
             playback_position_tracker: Mutex::new(None),
+            tracker_threads: Arc::new(AtomicUsize::new(0)),
+            playback_control: Arc::new(PlaybackControl::default()),
+            playback_state: Arc::new(Mutex::new(PlaybackStateMachine::default())),
+            secondary: Arc::new(SecondaryTrack::default()),
+            track_mixes: Mutex::new(TrackMixes::default()),
+            recording: Mutex::new(None),
+            growing_media: Mutex::new(HashSet::new()),
         }
     }
 }
 
 // Core audio operations (reusable functions)
 
-fn open_audio_file(file_path: &std::path::Path) -> Result<Vec<u8>, String> {
-    info!("open_audio_file: {:?}", file_path);
-
-    let file_bytes =
-        fs::read(file_path).map_err(|e| format!("Failed to read audio file: {}", e))?;
+/// Opens a file-backed, seekable decoder. Samples are decoded lazily while the
+/// decoder is iterated, so memory use does not depend on the file length.
+pub(crate) fn open_audio_decoder(file_path: &Path) -> Result<Decoder<BufReader<File>>, String> {
+    info!("open_audio_decoder: {:?}", file_path);
 
-    Ok(file_bytes)
+    let file = File::open(file_path).map_err(|e| format!("Failed to open audio file: {}", e))?;
+    Decoder::try_from(file).map_err(|e| format!("Failed to decode audio file: {}", e))
 }
 
-fn decode_audio_source(file_bytes: Vec<u8>) -> Result<DecodedAudio, String> {
-    info!("decode_audio_source: {} bytes", file_bytes.len());
-
-    let cursor = Cursor::new(file_bytes);
-    let source = Decoder::try_from(BufReader::new(cursor))
-        .map_err(|e| format!("Failed to decode audio from memory: {}", e))?;
-
-    let sample_rate = source.sample_rate();
-    let channels = source.channels() as u16;
-    let total_duration = source.total_duration();
-    let samples: Vec<f32> = source.map(|s| s as f32 / i16::MAX as f32).collect();
-
-    Ok(DecodedAudio {
-        sample_rate,
-        channels,
-        samples,
-        total_duration,
+/// Averages interleaved samples into a single channel, one frame at a time.
+pub(crate) fn downmix_to_mono<I>(samples: I, channels: u16) -> impl Iterator<Item = f32>
+where
+    I: Iterator<Item = f32>,
+{
+    let channels = channels.max(1) as usize;
+    let mut samples = samples;
+    std::iter::from_fn(move || {
+        let (sum, count) = samples
+            .by_ref()
+            .take(channels)
+            .fold((0.0_f32, 0_usize), |(sum, count), s| (sum + s, count + 1));
+        (count > 0).then(|| sum / count as f32)
     })
 }
 
-fn calculate_duration(decoded: &DecodedAudio) -> u64 {
-    if let Some(duration) = decoded.total_duration {
+fn calculate_duration(
+    total_duration: Option<Duration>,
+    total_samples: u64,
+    sample_rate: u32,
+    channels: u16,
+) -> u64 {
+    if let Some(duration) = total_duration {
         debug!("Audio duration from metadata: {} ms", duration.as_millis());
         duration.as_millis() as u64
     } else {
-        let total_samples = decoded.samples.len() as u64;
-        let channels = decoded.channels as u64;
-        let sample_rate = decoded.sample_rate as u64;
+        let channels = channels as u64;
+        let sample_rate = sample_rate as u64;
         if sample_rate > 0 && channels > 0 && total_samples > 0 {
             let num_frames = total_samples / channels;
             let calculated_duration = (num_frames * 1000) / sample_rate;
@@ -101,61 +450,240 @@ fn calculate_duration(decoded: &DecodedAudio) -> u64 {
     }
 }
 
-fn calculate_peaks(samples: &[f32], max_peaks: usize) -> Vec<f32> {
-    if max_peaks == 0 || samples.is_empty() {
-        return vec![];
+/// Decodes the whole file once and summarizes it into a multi-resolution
+/// peak file, measuring its loudness on the way. Only one base peak per
+/// `BASE_FRAMES_PER_PEAK` frames is kept in memory while decoding.
+fn generate_peak_file(file_path: &Path) -> Result<PeakFile, String> {
+    info!("generate_peak_file: {:?}", file_path);
+
+    let source = SourceFingerprint::of(file_path)
+        .map_err(|e| format!("Failed to read audio file metadata: {}", e))?;
+    let decoder = open_audio_decoder(file_path)?;
+    let sample_rate = decoder.sample_rate();
+    let channels = decoder.channels();
+    let total_duration = decoder.total_duration();
+
+    let samples_per_peak = BASE_FRAMES_PER_PEAK * channels.max(1) as usize;
+    let mut base_peaks = Vec::new();
+    let mut current_peak = 0.0_f32;
+    let mut current_len = 0;
+    let mut decoded_samples = 0_u64;
+    let mut loudness_meter = LoudnessMeter::new(sample_rate, channels);
+    for sample in decoder {
+        loudness_meter.push(sample);
+        current_peak = current_peak.max(sample.abs());
+        current_len += 1;
+        decoded_samples += 1;
+        if current_len == samples_per_peak {
+            base_peaks.push(quantize_peak(current_peak));
+            current_peak = 0.0;
+            current_len = 0;
+        }
+    }
+    if current_len > 0 {
+        base_peaks.push(quantize_peak(current_peak));
     }
 
-    let chunk_size = (samples.len() as f64 / max_peaks as f64).ceil() as usize;
-    if chunk_size == 0 {
-        return vec![];
+    let duration = calculate_duration(total_duration, decoded_samples, sample_rate, channels);
+    let loudness_lufs = loudness_meter.integrated_loudness();
+    debug!("Integrated loudness: {:?} LUFS", loudness_lufs);
+    Ok(PeakFile::new(source, duration, loudness_lufs, base_peaks))
+}
+
+/// Loads the cached peak file of `file_path`, generating it if needed.
+fn cached_peak_file(file_path: &Path) -> Result<PeakFile, String> {
+    let peak_file = match load_peak_file(file_path) {
+        Some(peak_file) => {
+            debug!("Using cached peak file");
+            peak_file
+        }
+        None => {
+            let peak_file = generate_peak_file(file_path)?;
+            if let Err(e) = save_peak_file(file_path, &peak_file) {
+                // The cache is only an optimization, so keep going without it.
+                warn!("Failed to save peak file: {}", e);
+            }
+            peak_file
+        }
+    };
+    Ok(peak_file)
+}
+
+fn audio_info(peak_file: &PeakFile, max_peaks: usize) -> AudioInfo {
+    let peaks = peak_file.peaks(max_peaks);
+
+    debug!("Calculated {} peaks", peaks.len());
+
+    AudioInfo {
+        duration: peak_file.duration_ms,
+        peaks,
+        loudness_lufs: peak_file.loudness_lufs,
+        silences: None,
     }
+}
 
-    let raw_peaks: Vec<f32> = samples
-        .chunks(chunk_size)
-        .map(|chunk| chunk.iter().map(|&s| s.abs()).fold(0.0f32, |a, b| a.max(b)))
-        .collect();
+/// Gain that brings an episode to the target loudness, but no further than
+/// its loudest sample can go without clipping. Audio whose loudness is
+/// unknown (silent) is played as is.
+fn loudness_gain(peak_file: &PeakFile) -> f32 {
+    let Some(loudness_lufs) = peak_file.loudness_lufs else {
+        return 1.0;
+    };
+    normalization_gain(loudness_lufs).min(1.0 / peak_file.sample_peak())
+}
 
-    let max_peak = raw_peaks.iter().cloned().fold(0.0f32, f32::max).max(1e-6);
-    raw_peaks.iter().map(|&p| p / max_peak).collect()
+/// Computes the RMS level (in dBFS) of consecutive frames, reading the file
+/// incrementally.
+fn calculate_frame_levels_db(file_path: &Path, frame_ms: u32) -> Result<Vec<f32>, String> {
+    let decoder = open_audio_decoder(file_path)?;
+    let channels = decoder.channels().max(1) as usize;
+    let frame_len = ((decoder.sample_rate() * frame_ms / 1000) as usize).max(1) * channels;
+
+    let mut levels = Vec::new();
+    let mut sum_squares = 0.0_f32;
+    let mut len = 0;
+    let mut push_level = |sum_squares: f32, len: usize| {
+        let mean_square = sum_squares / len as f32;
+        levels.push(10.0 * mean_square.max(1e-10).log10());
+    };
+    for sample in decoder {
+        sum_squares += sample * sample;
+        len += 1;
+        if len == frame_len {
+            push_level(sum_squares, len);
+            sum_squares = 0.0;
+            len = 0;
+        }
+    }
+    if len > 0 {
+        push_level(sum_squares, len);
+    }
+    Ok(levels)
 }
 
-fn analyze_audio_file(file_bytes: Vec<u8>, max_peaks: usize) -> Result<AudioInfo, String> {
-    info!(
-        "analyze_audio_file: {} bytes, max_peaks: {}",
-        file_bytes.len(),
-        max_peaks
+/// Turns per-frame levels into speech segments.
+///
+/// Pauses shorter than `min_silence_ms` are bridged, very short bursts are
+/// discarded, and every segment is widened by `padding_ms` on both sides.
+fn detect_speech_from_levels(
+    levels_db: &[f32],
+    frame_ms: u32,
+    min_silence_ms: u32,
+    padding_ms: u32,
+) -> Vec<SpeechSegment> {
+    if levels_db.is_empty() {
+        return vec![];
+    }
+
+    // Estimate the noise floor from the quietest 10% of frames.
+    let mut sorted = levels_db.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let noise_floor = sorted[sorted.len() / 10];
+    let threshold = (noise_floor + VAD_THRESHOLD_ABOVE_NOISE_DB).max(VAD_ABSOLUTE_THRESHOLD_DB);
+    debug!(
+        "VAD noise floor: {:.1} dB, threshold: {:.1} dB",
+        noise_floor, threshold
     );
 
-    let decoded = decode_audio_source(file_bytes)?;
-    let duration = calculate_duration(&decoded);
-    let peaks = calculate_peaks(&decoded.samples, max_peaks);
+    let mut raw: Vec<(u32, u32)> = Vec::new();
+    for (index, &level) in levels_db.iter().enumerate() {
+        if level <= threshold {
+            continue;
+        }
+        let start = index as u32 * frame_ms;
+        let end = start + frame_ms;
+        match raw.last_mut() {
+            Some((_, last_end)) if start - *last_end < min_silence_ms => *last_end = end,
+            _ => raw.push((start, end)),
+        }
+    }
+
+    let total_ms = levels_db.len() as u32 * frame_ms;
+    let mut segments: Vec<SpeechSegment> = Vec::new();
+    for (start, end) in raw {
+        if end - start < VAD_MIN_SPEECH_MS {
+            continue;
+        }
+        let start_ms = start.saturating_sub(padding_ms);
+        let end_ms = (end + padding_ms).min(total_ms);
+        match segments.last_mut() {
+            Some(last) if start_ms <= last.end_ms => last.end_ms = end_ms,
+            _ => segments.push(SpeechSegment { start_ms, end_ms }),
+        }
+    }
+    segments
+}
 
-    debug!("Calculated {} peaks", peaks.len());
+/// The gaps of at least `min_silence_ms` between speech segments, including
+/// the silence before the first and after the last one.
+fn silences_between(
+    speech: &[SpeechSegment],
+    total_ms: u32,
+    min_silence_ms: u32,
+) -> Vec<SilenceSegment> {
+    let mut silences = Vec::new();
+    let mut silence_start_ms = 0;
+    for segment in speech.iter().chain(std::iter::once(&SpeechSegment {
+        start_ms: total_ms,
+        end_ms: total_ms,
+    })) {
+        if segment.start_ms >= silence_start_ms + min_silence_ms.max(1) {
+            silences.push(SilenceSegment {
+                start_ms: silence_start_ms,
+                end_ms: segment.start_ms,
+            });
+        }
+        silence_start_ms = silence_start_ms.max(segment.end_ms);
+    }
+    silences
+}
+
+/// Detects the silences of at least `min_silence_ms` in the file, using the
+/// same voice activity detection as `detect_speech_segments`. Also returns
+/// the length of the decoded audio in ms.
+pub(crate) fn detect_silences(
+    file_path: &Path,
+    min_silence_ms: u32,
+) -> Result<(Vec<SilenceSegment>, u32), String> {
+    let levels_db = calculate_frame_levels_db(file_path, VAD_FRAME_MS)?;
+    let speech = detect_speech_from_levels(&levels_db, VAD_FRAME_MS, min_silence_ms, 0);
+    let total_ms = levels_db.len() as u32 * VAD_FRAME_MS;
+    Ok((
+        silences_between(&speech, total_ms, min_silence_ms),
+        total_ms,
+    ))
+}
 
-    Ok(AudioInfo { duration, peaks })
+/// Creates a sink on the shared output, opening the selected output device on
+/// first use. Every track is a sink on the mixer of this output.
+fn connect_sink<E: PlaybackEmitter + Clone>(
+    output: &Mutex<OutputStreamState>,
+    emitter: &E,
+) -> Result<Sink, String> {
+    let mut output = output.lock().unwrap();
+    if output.output.is_none() {
+        let device_id = output.device_id.clone();
+        output.open(emitter, device_id)?;
+    }
+    let audio_output = output.output.as_ref().expect("the output is open");
+    Ok(Sink::connect_new(audio_output.mixer()))
 }
 
-fn create_audio_playback(
-    audio_data: &[u8],
-    stream: Option<&OutputStream>,
+fn create_audio_playback<E: PlaybackEmitter + Clone>(
+    output: &Mutex<OutputStreamState>,
+    emitter: &E,
+    audio_path: &Path,
+    control: &Arc<PlaybackControl>,
+    volume: f32,
     start_paused: bool,
-) -> Result<(Option<OutputStream>, Sink), String> {
+) -> Result<Sink, String> {
     info!("create_audio_playback");
 
-    let cursor = Cursor::new(audio_data.to_vec());
-    let source = Decoder::try_from(BufReader::new(cursor))
-        .map_err(|e| format!("Failed to decode audio from memory: {}", e))?;
+    let decoder = open_audio_decoder(audio_path)?;
+    let source = PlaybackSource::new(decoder, Arc::clone(control));
 
-    let (stream, sink) = if let Some(stream) = stream {
-        let sink = Sink::connect_new(stream.mixer());
-        (None, sink)
-    } else {
-        let stream = OutputStreamBuilder::open_default_stream()
-            .map_err(|e| format!("Failed to open audio output stream: {}", e))?;
-        let sink = Sink::connect_new(&stream.mixer());
-        (Some(stream), sink)
-    };
+    let sink = connect_sink(output, emitter)?;
+    sink.set_volume(volume);
 
     if start_paused {
         sink.pause();
@@ -163,105 +691,472 @@ fn create_audio_playback(
 
     sink.append(source);
 
-    Ok((stream, sink))
+    Ok(sink)
 }
 
-fn seek_audio_forward(sink: &Sink, position_ms: u32) -> Result<(), String> {
-    info!("seek_audio_forward: {}", position_ms);
-    let target_pos = Duration::from_millis(position_ms as u64);
-    sink.try_seek(target_pos)
-        .map_err(|e| format!("Failed to seek audio: {}", e))?;
-    Ok(())
+/// Queues `start_ms..end_ms` of `tts_path` on a paused sink.
+fn create_tts_playback<E: PlaybackEmitter + Clone>(
+    output: &Mutex<OutputStreamState>,
+    emitter: &E,
+    tts_path: &Path,
+    start_ms: u32,
+    end_ms: u32,
+    volume: f32,
+) -> Result<Sink, String> {
+    info!("create_tts_playback: {}-{}", start_ms, end_ms);
+
+    let mut decoder = open_audio_decoder(tts_path)?;
+    decoder
+        .try_seek(Duration::from_millis(start_ms as u64))
+        .map_err(|e| format!("Failed to seek TTS audio: {}", e))?;
+    let source = decoder.take_duration(Duration::from_millis((end_ms - start_ms) as u64));
+
+    let sink = connect_sink(output, emitter)?;
+    sink.set_volume(volume);
+    sink.pause();
+    sink.append(source);
+
+    Ok(sink)
 }
 
-fn seek_audio_backward_with_recreation(
-    audio_data: &[u8],
-    stream: &OutputStream,
-    position_ms: u32,
-    start_paused: bool,
-) -> Result<Sink, String> {
-    info!("seek_audio_backward_with_recreation: {}", position_ms);
+/// Receives the events of the playback.
+pub(crate) trait PlaybackEmitter: Send + Sync + 'static {
+    fn emit_state(&self, event: PlaybackStateEvent) -> Result<(), String>;
+    fn emit_position(&self, position_ms: u64) -> Result<(), String>;
+    fn emit_range_loop_completed(&self, event: RangeLoopCompleted) -> Result<(), String>;
+    fn emit_output_device_lost(&self, event: OutputDeviceLost) -> Result<(), String>;
+    /// Called on the audio thread when the output stream of `generation`
+    /// fails.
+    fn report_stream_error(&self, generation: u64, error: cpal::StreamError);
+}
+
+impl PlaybackEmitter for AppHandle {
+    fn emit_state(&self, event: PlaybackStateEvent) -> Result<(), String> {
+        self.emit("playback-state", event)
+            .map_err(|e| format!("Failed to emit playback-state event: {}", e))
+    }
 
-    let (_, new_sink) = create_audio_playback(audio_data, Some(stream), start_paused)?;
-    seek_audio_forward(&new_sink, position_ms)?;
+    fn emit_position(&self, position_ms: u64) -> Result<(), String> {
+        self.emit("playback-position", position_ms)
+            .map_err(|e| format!("Failed to emit playback-position event: {}", e))
+    }
+
+    fn emit_range_loop_completed(&self, event: RangeLoopCompleted) -> Result<(), String> {
+        self.emit("range-loop-completed", event)
+            .map_err(|e| format!("Failed to emit range-loop-completed event: {}", e))
+    }
 
-    Ok(new_sink)
+    fn emit_output_device_lost(&self, event: OutputDeviceLost) -> Result<(), String> {
+        self.emit("output-device-lost", event)
+            .map_err(|e| format!("Failed to emit output-device-lost event: {}", e))
+    }
+
+    fn report_stream_error(&self, generation: u64, error: cpal::StreamError) {
+        let app_handle = self.clone();
+        // Handled off the audio thread, since handling it drops the stream.
+        thread::spawn(move || {
+            let state: State<AudioState> = app_handle.state();
+            handle_stream_error(&state, &app_handle, generation, error)
+        });
+    }
 }
 
-fn start_playback_position_tracking(
-    app_handle: AppHandle,
-    sink_mutex: Arc<Mutex<Option<Sink>>>,
-    tracker_mutex: &Mutex<Option<thread::JoinHandle<()>>>,
-) -> Result<(), String> {
-    info!("start_playback_position_tracking");
-    let mut tracker_guard = tracker_mutex
-        .lock()
-        .map_err(|e| format!("Failed to lock tracker mutex: {}", e))?;
+enum TrackerMessage {
+    /// Playback may have started or moved; poll the sink again.
+    Wake,
+    /// Polls the sink like the timer does and acknowledges once done, so that
+    /// tests do not have to wait for the timer.
+    #[cfg(test)]
+    Tick(mpsc::Sender<()>),
+    Shutdown,
+}
 
-    // Stop any existing tracker
-    if let Some(_handle) = tracker_guard.take() {
-        warn!("Stopping existing playback position tracker.");
-        // It's not straightforward to "stop" a thread from outside.
-        // For now, we'll just drop the handle, assuming the thread will eventually
-        // terminate if the sink is stopped or dropped.
-        // A more robust solution would involve a channel for signaling termination.
+/// The single playback position tracker of an `AudioState`.
+///
+/// The thread lives as long as the tracker. It reports the position while the
+/// sink is playing and blocks on its channel otherwise, so play/seek/pause
+/// calls only have to wake it up.
+pub struct PositionTracker {
+    sender: mpsc::Sender<TrackerMessage>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl PositionTracker {
+    fn spawn<E: PlaybackEmitter>(
+        emitter: E,
+        sink_mutex: Arc<Mutex<Option<Sink>>>,
+        secondary: Arc<SecondaryTrack>,
+        control: Arc<PlaybackControl>,
+        state_machine: Arc<Mutex<PlaybackStateMachine>>,
+        running_threads: Arc<AtomicUsize>,
+    ) -> Self {
+        info!("Starting playback position tracker");
+        let (sender, receiver) = mpsc::channel();
+        running_threads.fetch_add(1, Ordering::SeqCst);
+        let handle = thread::spawn(move || {
+            run_position_tracker(
+                emitter,
+                sink_mutex,
+                secondary,
+                control,
+                state_machine,
+                receiver,
+            );
+            running_threads.fetch_sub(1, Ordering::SeqCst);
+            info!("Playback position tracker thread terminated.");
+        });
+        Self {
+            sender,
+            handle: Some(handle),
+        }
     }
 
-    let app_handle_clone = app_handle.clone();
+    fn wake(&self) {
+        if self.sender.send(TrackerMessage::Wake).is_err() {
+            error!("Playback position tracker thread is not running");
+        }
+    }
+}
 
+impl Drop for PositionTracker {
+    fn drop(&mut self) {
+        let _ = self.sender.send(TrackerMessage::Shutdown);
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                error!("Failed to join playback position tracker thread: {:?}", e);
+            }
+        }
+    }
+}
+
+fn run_position_tracker<E: PlaybackEmitter>(
+    emitter: E,
+    sink_mutex: Arc<Mutex<Option<Sink>>>,
+    secondary: Arc<SecondaryTrack>,
+    control: Arc<PlaybackControl>,
+    state_machine: Arc<Mutex<PlaybackStateMachine>>,
+    receiver: mpsc::Receiver<TrackerMessage>,
+) {
+    let mut is_playing = false;
     let mut emit_error_count = 0;
-    let handle = thread::spawn(move || {
-        loop {
-            let (should_break, current_pos_ms) = {
-                let sink_locked = sink_mutex.lock().unwrap();
-                if let Some(sink) = sink_locked.as_ref() {
+    loop {
+        let message = if is_playing {
+            match receiver.recv_timeout(Duration::from_millis(POSITION_UPDATE_FREQUENCY)) {
+                Ok(message) => Some(message),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            // Nothing to report until playback is started or moved.
+            match receiver.recv() {
+                Ok(message) => Some(message),
+                Err(_) => break,
+            }
+        };
+        if let Some(TrackerMessage::Shutdown) = message {
+            break;
+        }
+
+        let current_pos_ms = {
+            let sink_locked = sink_mutex.lock().unwrap();
+            match sink_locked.as_ref() {
+                Some(sink) => {
+                    // At the end of the audio written so far, playback goes
+                    // on once more has been written.
+                    let hold_reason = control.hold_reason();
+                    let waiting_for_media =
+                        hold_reason == Some(HoldReason::MediaEnd) && control.is_media_growing();
+                    if let Some(reason) = hold_reason.filter(|_| !waiting_for_media) {
+                        sink.pause();
+                        let input = match reason {
+                            HoldReason::MediaEnd => Some(PlaybackInput::ReachedEnd),
+                            // The secondary track plays after the range, if
+                            // queued; the playback pauses when it finishes.
+                            HoldReason::RangeEnd
+                                if secondary.start_if_queued() || secondary.is_playing() =>
+                            {
+                                None
+                            }
+                            // The last repetition stops at the end of the range.
+                            HoldReason::RangeEnd => Some(PlaybackInput::Pause),
+                        };
+                        if let Some(input) = input {
+                            report_playback(&state_machine, &emitter, input);
+                        }
+                    }
                     if sink.is_paused() || sink.empty() {
-                        info!("Playback paused or empty, stopping tracker thread.");
-                        (true, 0)
+                        None
                     } else {
-                        (false, sink.get_pos().as_millis() as u64)
+                        // Report media time, which differs from the time
+                        // played when the playback rate is not 1.0.
+                        Some(control.position_ms())
                     }
-                } else {
-                    info!("No sink available, stopping tracker thread.");
-                    (true, 0)
                 }
-            };
-            if should_break {
-                break;
+                None => None,
             }
+        };
 
-            if let Err(e) = app_handle_clone.emit("playback-position", current_pos_ms) {
-                error!("Failed to emit playback-position event: {}", e);
-                emit_error_count += 1;
-                if emit_error_count >= 5 {
-                    error!("Too many emit errors, stopping playback position tracker thread.");
-                    break;
-                }
+        for event in control.take_range_events() {
+            if let Err(e) = emitter.emit_range_loop_completed(event) {
+                error!("{}", e);
             }
+        }
 
-            thread::sleep(Duration::from_millis(POSITION_UPDATE_FREQUENCY));
+        is_playing = match current_pos_ms {
+            Some(position_ms) => match emitter.emit_position(position_ms) {
+                Ok(()) => {
+                    emit_error_count = 0;
+                    true
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    emit_error_count += 1;
+                    if emit_error_count >= 5 {
+                        error!("Too many emit errors, pausing playback position tracking.");
+                    }
+                    emit_error_count < 5
+                }
+            },
+            None => false,
+        };
+        // Keep polling until the secondary track has finished.
+        is_playing = is_playing || secondary.is_playing();
+
+        #[cfg(test)]
+        if let Some(TrackerMessage::Tick(done)) = message {
+            let _ = done.send(());
         }
-        info!("Playback position tracker thread terminated.");
+    }
+}
+
+/// Starts the tracker of `state` on first use and lets it poll the sink.
+fn wake_position_tracker<E: PlaybackEmitter>(state: &AudioState, emitter: E) {
+    let mut tracker_guard = state.playback_position_tracker.lock().unwrap();
+    let tracker = tracker_guard.get_or_insert_with(|| {
+        PositionTracker::spawn(
+            emitter,
+            Arc::clone(&state.sink),
+            Arc::clone(&state.secondary),
+            Arc::clone(&state.playback_control),
+            Arc::clone(&state.playback_state),
+            Arc::clone(&state.tracker_threads),
+        )
     });
+    tracker.wake();
+}
 
-    *tracker_guard = Some(handle);
-    Ok(())
+/// Reports a failed playback operation as an `Error` state.
+fn report_error<'a, E: PlaybackEmitter>(
+    state: &'a AudioState,
+    emitter: &'a E,
+) -> impl Fn(&String) + 'a {
+    move |e| {
+        report_playback(
+            &state.playback_state,
+            emitter,
+            PlaybackInput::Fail(e.clone()),
+        )
+    }
 }
 
-fn stop_playback_position_tracking(state: State<AudioState>) -> Result<(), String> {
-    info!("stop_playback_position_tracking");
-    let mut tracker_guard = state.playback_position_tracker.lock().unwrap();
-    if let Some(handle) = tracker_guard.take() {
-        // Attempt to join the thread. This will block until the thread finishes.
-        // In a real application, you might want to send a signal to the thread
-        // to allow it to terminate gracefully without blocking the main thread.
-        if let Err(e) = handle.join() {
-            error!("Failed to join playback position tracker thread: {:?}", e);
+/// Feeds `input` to the state machine and emits the resulting state, if any.
+fn report_playback<E: PlaybackEmitter>(
+    state_machine: &Mutex<PlaybackStateMachine>,
+    emitter: &E,
+    input: PlaybackInput,
+) {
+    let event = state_machine.lock().unwrap().handle(input);
+    if let Some(event) = event {
+        if let Err(e) = emitter.emit_state(event) {
+            error!("{}", e);
         }
     }
+}
+
+/// Copies the path of the opened audio. The lock is released before
+/// returning, so callers can lock `sink` afterwards without risking a
+/// deadlock with another command.
+fn opened_audio_path(state: &AudioState) -> Result<PathBuf, String> {
+    state
+        .audio_path
+        .lock()
+        .unwrap()
+        .clone()
+        .ok_or("Audio path not found in state. Call open_audio first.".to_string())
+}
+
+/// Moves the main track to `sink`, at the current position. Playback goes on
+/// if it was playing and `keep_playing` is set, and is paused otherwise.
+fn rebuild_playback<E: PlaybackEmitter>(
+    state: &AudioState,
+    emitter: E,
+    sink: Sink,
+    keep_playing: bool,
+) -> Result<(), String> {
+    // The secondary track is only queued for the line being played.
+    state.secondary.clear();
+    let audio_path = opened_audio_path(state);
+    let mut sink_guard = state.sink.lock().unwrap();
+    let old_sink = match sink_guard.take() {
+        // Only a sink that still holds the source has a position to keep.
+        Some(old_sink) if !old_sink.empty() => old_sink,
+        _ => return Ok(()),
+    };
+    let was_playing = !old_sink.is_paused();
+    let position_ms = state.playback_control.position_ms();
+    old_sink.stop();
+
+    let audio_path = audio_path?;
+    // The loop of a range does not survive the switch.
+    state.playback_control.set_range(None);
+    let mut source = PlaybackSource::new(
+        open_audio_decoder(&audio_path)?,
+        Arc::clone(&state.playback_control),
+    );
+    source
+        .try_seek(Duration::from_millis(position_ms))
+        .map_err(|e| format!("Failed to seek audio: {}", e))?;
+    sink.set_volume(state.track_mixes.lock().unwrap().main.effective_volume());
+    sink.pause();
+    sink.append(source);
+    let playing = was_playing && keep_playing;
+    if playing {
+        sink.play();
+    }
+    *sink_guard = Some(sink);
+    drop(sink_guard);
+    info!(
+        "Playback moved to the new output at {}ms ({})",
+        position_ms,
+        if playing { "playing" } else { "paused" }
+    );
+
+    if was_playing && !playing {
+        report_playback(&state.playback_state, &emitter, PlaybackInput::Pause);
+    }
+    wake_position_tracker(state, emitter);
     Ok(())
 }
 
+/// Reopens the output stream on `device_id` and moves playback to it. The
+/// current stream is kept if the device cannot be opened.
+fn switch_output_device<E: PlaybackEmitter + Clone>(
+    state: &AudioState,
+    emitter: &E,
+    device_id: Option<String>,
+    keep_playing: bool,
+) -> Result<(), String> {
+    let sink = {
+        let mut output = state.stream.lock().unwrap();
+        output.open(emitter, device_id)?;
+        let audio_output = output.output.as_ref().expect("the output is open");
+        Sink::connect_new(audio_output.mixer())
+    };
+    rebuild_playback(state, emitter.clone(), sink, keep_playing)
+}
+
+/// Handles an error reported by the output stream of `generation`. When the
+/// device is gone, playback falls back to the system default device, paused.
+fn handle_stream_error<E: PlaybackEmitter + Clone>(
+    state: &AudioState,
+    emitter: &E,
+    generation: u64,
+    error: cpal::StreamError,
+) {
+    let device_id = {
+        let output = state.stream.lock().unwrap();
+        if output.generation != generation {
+            debug!("Ignoring error of a replaced output stream: {}", error);
+            return;
+        }
+        output.device_id.clone()
+    };
+
+    let message = error.to_string();
+    if !matches!(error, cpal::StreamError::DeviceNotAvailable) {
+        error!("Audio output error: {}", message);
+        report_playback(&state.playback_state, emitter, PlaybackInput::Fail(message));
+        return;
+    }
+
+    warn!("Output device lost: {:?}", device_id);
+    if let Err(e) = emitter.emit_output_device_lost(OutputDeviceLost {
+        device_id,
+        message: message.clone(),
+    }) {
+        error!("{}", e);
+    }
+    if let Err(e) = switch_output_device(state, emitter, None, false) {
+        error!("Failed to fall back to the default output device: {}", e);
+        // Nothing can be played until a device is selected again.
+        state.stream.lock().unwrap().output = None;
+        report_playback(
+            &state.playback_state,
+            emitter,
+            PlaybackInput::Fail(format!("{}: {}", message, e)),
+        );
+    }
+}
+
+/// Marks `path` as being written, so that playing it does not end at the end
+/// of the audio written so far.
+pub(crate) fn begin_growing_media(app_handle: &AppHandle, path: &Path) {
+    let state: State<AudioState> = app_handle.state();
+    state
+        .growing_media
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf());
+}
+
+/// Called by the writer of `path` whenever more audio has been written, and
+/// with `finished` once it is complete. Playback waiting at the end of the
+/// audio written so far goes on from there.
+pub(crate) fn media_grown(app_handle: &AppHandle, path: &Path, finished: bool) {
+    let state: State<AudioState> = app_handle.state();
+    continue_growing_media(&state, app_handle, path, finished);
+}
+
+fn continue_growing_media<E: PlaybackEmitter + Clone>(
+    state: &AudioState,
+    emitter: &E,
+    path: &Path,
+    finished: bool,
+) {
+    {
+        let mut growing_media = state.growing_media.lock().unwrap();
+        if finished {
+            growing_media.remove(path);
+        }
+        if state.audio_path.lock().unwrap().as_deref() != Some(path) {
+            return;
+        }
+        if finished {
+            state.playback_control.set_media_growing(false);
+        }
+    }
+    // The decoder stops at the end of the file as it was when it was opened,
+    // so a new one is opened at the same position. Until the file is complete
+    // this is only done when playback is waiting for more audio.
+    let waiting = state.playback_control.hold_reason() == Some(HoldReason::MediaEnd);
+    let has_source = state
+        .sink
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|sink| !sink.empty());
+    if !has_source || !(waiting || finished) {
+        return;
+    }
+    debug!("Reopening growing media {:?}", path);
+    let result = connect_sink(&state.stream, emitter)
+        .and_then(|sink| rebuild_playback(state, emitter.clone(), sink, true));
+    if let Err(e) = result {
+        error!("Failed to continue playback of growing media: {}", e);
+        report_playback(&state.playback_state, emitter, PlaybackInput::Fail(e));
+    }
+}
+
 // Tauri command wrappers
 
 #[tauri::command]
@@ -271,13 +1166,21 @@ pub async fn open_audio(app_handle: AppHandle, path: String) -> Result<(), Strin
         .resolve(&path, BaseDirectory::AppLocalData)
         .map_err(|e| format!("Failed to resolve path '{}': {}", path, e))?;
 
-    let file_bytes = open_audio_file(&full_path)?;
+    // Make sure the file can be decoded before remembering it. Playback
+    // reopens the file and decodes it on the fly.
+    open_audio_decoder(&full_path)?;
 
-    // Store audio data in state
     let state: State<AudioState> = app_handle.state();
-    let mut audio_data_guard = state.audio_data.lock().unwrap();
-    *audio_data_guard = Some(file_bytes);
-    info!("Audio data loaded and stored in state");
+    // Until the episode has been analyzed its loudness is unknown.
+    let gain = load_peak_file(&full_path).map_or(1.0, |peak_file| loudness_gain(&peak_file));
+    state.playback_control.set_gain(gain);
+    let growing_media = state.growing_media.lock().unwrap();
+    state
+        .playback_control
+        .set_media_growing(growing_media.contains(&full_path));
+    let mut audio_path_guard = state.audio_path.lock().unwrap();
+    *audio_path_guard = Some(full_path);
+    info!("Audio path stored in state");
     Ok(())
 }
 
@@ -308,168 +1211,967 @@ pub async fn analyze_audio(
     app_handle: AppHandle,
     path: String,
     max_peaks: usize,
+    min_silence_ms: Option<u32>,
 ) -> Result<AudioInfo, String> {
     // NOTE: 再生を邪魔しないように別途オーディオファイルを開いてデータを取得する
     let full_path = app_handle
         .path()
         .resolve(&path, BaseDirectory::AppLocalData)
         .map_err(|e| format!("Failed to resolve path '{}': {}", path, e))?;
-    let file_bytes = open_audio_file(&full_path)?;
 
-    analyze_audio_file(file_bytes, max_peaks)
+    info!("analyze_audio: {:?}, max_peaks: {}", full_path, max_peaks);
+    let peak_file = cached_peak_file(&full_path)?;
+    let mut info = audio_info(&peak_file, max_peaks);
+    if let Some(min_silence_ms) = min_silence_ms {
+        let (silences, _) = detect_silences(&full_path, min_silence_ms)?;
+        info!("Detected {} silences", silences.len());
+        info.silences = Some(silences);
+    }
+
+    // Normalize the opened episode as soon as its loudness is known.
+    let state: State<AudioState> = app_handle.state();
+    if state.audio_path.lock().unwrap().as_ref() == Some(&full_path) {
+        state.playback_control.set_gain(loudness_gain(&peak_file));
+    }
+    Ok(info)
 }
 
 #[tauri::command]
-pub fn play_audio(app_handle: AppHandle, state: State<AudioState>) -> Result<(), String> {
-    if let Some(sink) = state.sink.lock().unwrap().as_ref() {
-        warn!("Stopping existing audio playback");
-        sink.stop();
-    }
+pub async fn detect_speech_segments(
+    app_handle: AppHandle,
+    path: String,
+    min_silence_ms: Option<u32>,
+    padding_ms: Option<u32>,
+) -> Result<Vec<SpeechSegment>, String> {
+    let full_path = app_handle
+        .path()
+        .resolve(&path, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Failed to resolve path '{}': {}", path, e))?;
 
-    // Get audio data from state
-    let audio_data_guard = state.audio_data.lock().unwrap();
-    let file_bytes = audio_data_guard
-        .as_ref()
-        .ok_or("Audio data not found in state. Call open_audio first.".to_string())?;
+    let levels_db = calculate_frame_levels_db(&full_path, VAD_FRAME_MS)?;
+    let segments = detect_speech_from_levels(
+        &levels_db,
+        VAD_FRAME_MS,
+        min_silence_ms.unwrap_or(VAD_DEFAULT_MIN_SILENCE_MS),
+        padding_ms.unwrap_or(VAD_DEFAULT_PADDING_MS),
+    );
+    info!("Detected {} speech segments", segments.len());
+    Ok(segments)
+}
 
-    // Play audio
-    let (stream, sink) = create_audio_playback(file_bytes, None, false)?;
+/// Starts the main track from the beginning on a new sink.
+fn start_playback<E: PlaybackEmitter + Clone>(
+    state: &AudioState,
+    emitter: E,
+) -> Result<(), String> {
+    let audio_path = opened_audio_path(state)?;
+    state.secondary.clear();
 
-    // Store stream and sink in state
-    let mut stream_guard = state.stream.lock().unwrap();
-    *stream_guard = stream;
+    // Play audio
+    let sink = create_audio_playback(
+        &state.stream,
+        &emitter,
+        &audio_path,
+        &state.playback_control,
+        state.track_mixes.lock().unwrap().main.effective_volume(),
+        false,
+    )
+    .inspect_err(report_error(state, &emitter))?;
+
+    // Store sink in state
     let mut sink_guard = state.sink.lock().unwrap();
+    if let Some(old_sink) = sink_guard.as_ref() {
+        warn!("Stopping existing audio playback");
+        old_sink.stop();
+    }
     *sink_guard = Some(sink);
+    drop(sink_guard);
 
-    // Start tracking playback position
-    let sink_mutex = Arc::clone(&state.sink);
-    let tracker_mutex = &state.playback_position_tracker;
-    start_playback_position_tracking(app_handle, sink_mutex, tracker_mutex)
-        .map_err(|e| format!("Failed to start playback position tracking: {}", e))?;
+    report_playback(&state.playback_state, &emitter, PlaybackInput::Play);
+    wake_position_tracker(state, emitter);
 
     Ok(())
 }
 
-#[tauri::command]
-pub fn pause_audio(state: State<AudioState>) -> Result<(), String> {
-    info!("pause_audio");
+fn pause_playback<E: PlaybackEmitter>(state: &AudioState, emitter: &E) {
+    state.secondary.pause();
     if let Some(sink) = state.sink.lock().unwrap().as_ref() {
         sink.pause();
+        report_playback(&state.playback_state, emitter, PlaybackInput::Pause);
+    }
+}
+
+fn resume_playback<E: PlaybackEmitter>(state: &AudioState, emitter: E) {
+    if state.secondary.resume() {
+        // The main track stays held at the end of the line until the
+        // secondary track has finished.
+        report_playback(&state.playback_state, &emitter, PlaybackInput::Play);
+    } else if let Some(sink) = state.sink.lock().unwrap().as_ref() {
+        state.playback_control.release_hold();
+        sink.play();
+        report_playback(&state.playback_state, &emitter, PlaybackInput::Play);
+    }
+
+    wake_position_tracker(state, emitter);
+}
+
+fn stop_playback<E: PlaybackEmitter>(state: &AudioState, emitter: &E) {
+    state.playback_control.set_range(None);
+    state.secondary.clear();
+    if let Some(sink) = state.sink.lock().unwrap().as_ref() {
+        sink.stop();
     }
+    report_playback(&state.playback_state, emitter, PlaybackInput::Stop);
+    // The tracker notices that the sink is empty and goes idle by itself.
+}
+
+/// Seeks in either direction, preparing a paused sink if there is none.
+///
+/// The playback source seeks the file decoder directly, so the sink and the
+/// tracker keep running. The seek is applied by the audio thread, and a
+/// failed seek is returned once it has been tried.
+fn seek_playback<E: PlaybackEmitter + Clone>(
+    state: &AudioState,
+    emitter: E,
+    position_ms: u32,
+) -> Result<(), String> {
+    info!("seek_playback: {}", position_ms);
+    // Seeking leaves the range that is being repeated.
+    state.playback_control.set_range(None);
+    state.secondary.clear();
+    let audio_path = opened_audio_path(state);
+    let mut sink_guard = state.sink.lock().unwrap();
+
+    if sink_guard.as_ref().is_none_or(|sink| sink.empty()) {
+        let sink = create_audio_playback(
+            &state.stream,
+            &emitter,
+            &audio_path?,
+            &state.playback_control,
+            state.track_mixes.lock().unwrap().main.effective_volume(),
+            true,
+        )
+        .inspect_err(report_error(state, &emitter))?;
+        *sink_guard = Some(sink);
+    }
+    let sink = sink_guard.as_ref().expect("a sink has been prepared");
+    sink.try_seek(Duration::from_millis(position_ms as u64))
+        .map_err(|e| format!("Failed to seek audio: {}", e))
+        .inspect_err(report_error(state, &emitter))?;
+    drop(sink_guard);
+
+    emitter.emit_position(position_ms as u64)?;
+    report_playback(
+        &state.playback_state,
+        &emitter,
+        PlaybackInput::Seek(position_ms as u64),
+    );
+    wake_position_tracker(state, emitter);
+
+    Ok(())
+}
+
+#[tauri::command]
+pub fn play_audio(app_handle: AppHandle, state: State<AudioState>) -> Result<(), String> {
+    start_playback(&state, app_handle)
+}
+
+#[tauri::command]
+pub fn pause_audio(app_handle: AppHandle, state: State<AudioState>) -> Result<(), String> {
+    info!("pause_audio");
+    pause_playback(&state, &app_handle);
     Ok(())
 }
 
 #[tauri::command]
 pub fn resume_audio(app_handle: AppHandle, state: State<AudioState>) -> Result<(), String> {
     info!("resume_audio");
-    if let Some(sink) = state.sink.lock().unwrap().as_ref() {
+    resume_playback(&state, app_handle);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn stop_audio(app_handle: AppHandle, state: State<AudioState>) -> Result<(), String> {
+    info!("stop_audio");
+    stop_playback(&state, &app_handle);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn seek_audio(
+    app_handle: AppHandle,
+    position_ms: u32,
+    state: State<AudioState>,
+) -> Result<(), String> {
+    seek_playback(&state, app_handle, position_ms)
+}
+
+/// Plays `range` on the main track, creating a paused sink first if needed.
+fn start_range<E: PlaybackEmitter + Clone>(
+    state: &AudioState,
+    emitter: E,
+    range: PlaybackRange,
+) -> Result<(), String> {
+    let audio_path = opened_audio_path(state);
+    let mut sink_guard = state.sink.lock().unwrap();
+    if sink_guard.as_ref().is_none_or(|sink| sink.empty()) {
+        let sink = create_audio_playback(
+            &state.stream,
+            &emitter,
+            &audio_path?,
+            &state.playback_control,
+            state.track_mixes.lock().unwrap().main.effective_volume(),
+            true,
+        )
+        .inspect_err(report_error(state, &emitter))?;
+        *sink_guard = Some(sink);
+    }
+
+    // The source jumps to the start of the range inside the audio thread and
+    // loops there without recreating the sink. A hold left by the previous
+    // range is released here, so that the tracker does not act on it before
+    // the source has seen the new range.
+    state.playback_control.set_range(Some(range));
+    state.playback_control.release_hold();
+    if let Some(sink) = sink_guard.as_ref() {
         sink.play();
     }
+    drop(sink_guard);
 
-    let sink_mutex = Arc::clone(&state.sink);
-    let tracker_mutex = &state.playback_position_tracker;
-    start_playback_position_tracking(app_handle, sink_mutex, tracker_mutex)
-        .map_err(|e| format!("Failed to start playback position tracking: {}", e))?;
+    report_playback(&state.playback_state, &emitter, PlaybackInput::Play);
+    wake_position_tracker(state, emitter);
 
     Ok(())
 }
 
 #[tauri::command]
-pub fn stop_audio(state: State<AudioState>) -> Result<(), String> {
-    info!("stop_audio");
-    if let Some(sink) = state.sink.lock().unwrap().as_ref() {
-        sink.stop();
+pub fn play_range(
+    app_handle: AppHandle,
+    start_ms: u32,
+    end_ms: u32,
+    repeat_count: u32,
+    gap_ms: u32,
+    state: State<AudioState>,
+) -> Result<(), String> {
+    info!(
+        "play_range: {}-{} x{} (gap {}ms)",
+        start_ms, end_ms, repeat_count, gap_ms
+    );
+    if end_ms <= start_ms {
+        return Err(format!("Invalid range: {}-{}", start_ms, end_ms));
     }
-    stop_playback_position_tracking(state)
-        .map_err(|e| format!("Failed to stop playback position tracking: {}", e))?;
-    Ok(())
+    if repeat_count == 0 {
+        return Err("repeat_count must be at least 1".to_string());
+    }
+
+    state.secondary.clear();
+    start_range(
+        &state,
+        app_handle,
+        PlaybackRange {
+            start_ms,
+            end_ms,
+            repeat_count,
+            gap_ms,
+        },
+    )
 }
 
+/// Plays a line of the episode once, then the matching range of its TTS
+/// audio on the secondary track.
 #[tauri::command]
-pub fn seek_audio(
+pub fn play_line_with_tts(
     app_handle: AppHandle,
-    position_ms: u32,
+    line: LineWithTts,
     state: State<AudioState>,
 ) -> Result<(), String> {
-    let mut sink_opt = state.sink.lock().unwrap();
-
-    if let Some(sink) = sink_opt.as_mut() {
-        let current_pos = sink.get_pos();
-        let target_pos = Duration::from_millis(position_ms as u64);
-        let is_paused = sink.is_paused();
-
-        if target_pos < current_pos {
-            warn!("Seeking backwards. Recreating source from stored data.");
-
-            let data_guard = state.audio_data.lock().unwrap();
-            if let Some(audio_data) = data_guard.as_ref() {
-                let stream_guard = state.stream.lock().unwrap();
-                if let Some(stream) = stream_guard.as_ref() {
-                    // Stop the old sink before replacing it.
-                    sink.stop();
-
-                    let new_sink = seek_audio_backward_with_recreation(
-                        audio_data,
-                        stream,
-                        position_ms,
-                        is_paused,
-                    )?;
-                    if is_paused {
-                        app_handle
-                            .emit("playback-position", position_ms)
-                            .map_err(|e| {
-                                format!("Failed to emit playback-position event: {}", e)
-                            })?;
-                    }
+    info!(
+        "play_line_with_tts: {}-{} then {} {}-{}",
+        line.start_ms, line.end_ms, line.tts_audio_path, line.tts_start_ms, line.tts_end_ms
+    );
+    if line.end_ms <= line.start_ms {
+        return Err(format!("Invalid range: {}-{}", line.start_ms, line.end_ms));
+    }
+    if line.tts_end_ms <= line.tts_start_ms {
+        return Err(format!(
+            "Invalid TTS range: {}-{}",
+            line.tts_start_ms, line.tts_end_ms
+        ));
+    }
+    let tts_path = app_handle
+        .path()
+        .resolve(&line.tts_audio_path, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Failed to resolve path '{}': {}", line.tts_audio_path, e))?;
+
+    let tts_sink = create_tts_playback(
+        &state.stream,
+        &app_handle,
+        &tts_path,
+        line.tts_start_ms,
+        line.tts_end_ms,
+        state
+            .track_mixes
+            .lock()
+            .unwrap()
+            .secondary
+            .effective_volume(),
+    )
+    .inspect_err(report_error(&state, &app_handle))?;
+    // Started by the position tracker when the main track reaches the end of
+    // the line.
+    state.secondary.replace(tts_sink, true);
+
+    start_range(
+        &state,
+        app_handle,
+        PlaybackRange {
+            start_ms: line.start_ms,
+            end_ms: line.end_ms,
+            repeat_count: 1,
+            gap_ms: 0,
+        },
+    )
+    .inspect_err(|_| state.secondary.clear())
+}
 
-                    // Replace the sink inside the Option.
-                    *sink_opt = Some(new_sink);
-
-                    let sink_mutex = Arc::clone(&state.sink);
-                    let tracker_mutex = &state.playback_position_tracker;
-                    start_playback_position_tracking(app_handle, sink_mutex, tracker_mutex)
-                        .map_err(|e| {
-                            format!("Failed to start playback position tracking: {}", e)
-                        })?;
-                } else {
-                    error!("No audio stream found in state");
-                    return Err("No audio stream in state".to_string());
-                }
-            } else {
-                error!("No audio source stored in state");
-                return Err("No audio source stored".to_string());
+#[tauri::command]
+pub fn set_playback_rate(rate: f32, state: State<AudioState>) -> Result<(), String> {
+    info!("set_playback_rate: {}", rate);
+    if !(MIN_PLAYBACK_RATE..=MAX_PLAYBACK_RATE).contains(&rate) {
+        return Err(format!(
+            "Playback rate must be between {} and {}: {}",
+            MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE, rate
+        ));
+    }
+    // The playing source picks up the new rate at its next window.
+    state.playback_control.set_rate(rate);
+    Ok(())
+}
+
+/// Applies the mix of `track` to its sink, if there is one.
+fn apply_track_mix(state: &AudioState, track: AudioTrack, mix: TrackMix) {
+    let volume = mix.effective_volume();
+    match track {
+        AudioTrack::Main => {
+            if let Some(sink) = state.sink.lock().unwrap().as_ref() {
+                sink.set_volume(volume);
             }
-        } else {
-            seek_audio_forward(sink, position_ms)?;
-            app_handle
-                .emit("playback-position", position_ms)
-                .map_err(|e| format!("Failed to emit playback-position event: {}", e))?;
         }
-    } else {
-        // Get audio data from state
-        let audio_data_guard = state.audio_data.lock().unwrap();
-        let file_bytes = audio_data_guard
-            .as_ref()
-            .ok_or("Audio data not found in state. Call open_audio first.".to_string())?;
+        AudioTrack::Secondary => state.secondary.set_volume(volume),
+    }
+}
+
+#[tauri::command]
+pub fn set_volume(
+    volume: f32,
+    track: Option<AudioTrack>,
+    state: State<AudioState>,
+) -> Result<(), String> {
+    let track = track.unwrap_or(AudioTrack::Main);
+    info!("set_volume: {:?} {}", track, volume);
+    if !(0.0..=1.0).contains(&volume) {
+        return Err(format!("Volume must be between 0 and 1: {}", volume));
+    }
+    // Applied on top of the loudness normalization gain of the source.
+    let mix = {
+        let mut mixes = state.track_mixes.lock().unwrap();
+        let mix = mixes.get_mut(track);
+        mix.volume = volume;
+        *mix
+    };
+    apply_track_mix(&state, track, mix);
+    Ok(())
+}
+
+#[tauri::command]
+pub fn set_track_muted(
+    track: AudioTrack,
+    muted: bool,
+    state: State<AudioState>,
+) -> Result<(), String> {
+    info!("set_track_muted: {:?} {}", track, muted);
+    let mix = {
+        let mut mixes = state.track_mixes.lock().unwrap();
+        let mix = mixes.get_mut(track);
+        mix.muted = muted;
+        *mix
+    };
+    apply_track_mix(&state, track, mix);
+    Ok(())
+}
 
-        // Play audio
-        let (stream, sink) = create_audio_playback(file_bytes, None, true)?;
-        seek_audio_forward(&sink, position_ms)?;
-        app_handle
-            .emit("playback-position", position_ms)
-            .map_err(|e| format!("Failed to emit playback-position event: {}", e))?;
+#[tauri::command]
+pub fn list_output_devices() -> Result<Vec<OutputDevice>, String> {
+    output_devices()
+}
 
-        // Store stream and sink in state
-        let mut stream_guard = state.stream.lock().unwrap();
-        *stream_guard = stream;
-        *sink_opt = Some(sink);
+/// Switches the output to `device_id`, or to the system default device if
+/// `None`, keeping the position and the playing state.
+#[tauri::command]
+pub fn set_output_device(
+    app_handle: AppHandle,
+    device_id: Option<String>,
+    state: State<AudioState>,
+) -> Result<(), String> {
+    info!("set_output_device: {:?}", device_id);
+    switch_output_device(&state, &app_handle, device_id, true)
+}
 
-        // Start tracking playback position
-        let sink_mutex = Arc::clone(&state.sink);
-        let tracker_mutex = &state.playback_position_tracker;
-        start_playback_position_tracking(app_handle, sink_mutex, tracker_mutex)
-            .map_err(|e| format!("Failed to start playback position tracking: {}", e))?;
+/// Starts recording the learner from the default input device. The take is
+/// saved as a WAV file under `recordings/` in AppLocalData.
+#[tauri::command]
+pub fn record_microphone(app_handle: AppHandle, state: State<AudioState>) -> Result<(), String> {
+    info!("record_microphone");
+    let mut recording_guard = state.recording.lock().unwrap();
+    if recording_guard.is_some() {
+        return Err("Already recording".to_string());
     }
 
+    let timestamp_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let path = format!("{}/{}.wav", RECORDING_DIR, timestamp_ms);
+    let full_path = app_handle
+        .path()
+        .resolve(&path, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Failed to resolve path '{}': {}", path, e))?;
+    if let Some(parent) = full_path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create recording directory: {}", e))?;
+    }
+
+    let recording = MicrophoneRecording::start(full_path)?;
+    *recording_guard = Some(ActiveRecording { path, recording });
     Ok(())
 }
+
+#[tauri::command]
+pub fn stop_recording(state: State<AudioState>) -> Result<RecordedTake, String> {
+    info!("stop_recording");
+    let active = state
+        .recording
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or("Not recording".to_string())?;
+    let duration_ms = active.recording.stop()?;
+    info!("Recorded {} ({}ms)", active.path, duration_ms);
+    Ok(RecordedTake {
+        path: active.path,
+        duration_ms,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output_device::NullOutput;
+    use rodio::buffer::SamplesBuffer;
+
+    #[derive(Clone, Default)]
+    struct RecordingEmitter {
+        states: Arc<Mutex<Vec<PlaybackStateEvent>>>,
+        positions: Arc<Mutex<Vec<u64>>>,
+        lost_devices: Arc<Mutex<Vec<OutputDeviceLost>>>,
+    }
+
+    impl PlaybackEmitter for RecordingEmitter {
+        fn emit_state(&self, event: PlaybackStateEvent) -> Result<(), String> {
+            self.states.lock().unwrap().push(event);
+            Ok(())
+        }
+
+        fn emit_position(&self, position_ms: u64) -> Result<(), String> {
+            self.positions.lock().unwrap().push(position_ms);
+            Ok(())
+        }
+
+        fn emit_range_loop_completed(&self, _event: RangeLoopCompleted) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn emit_output_device_lost(&self, event: OutputDeviceLost) -> Result<(), String> {
+            self.lost_devices.lock().unwrap().push(event);
+            Ok(())
+        }
+
+        fn report_stream_error(&self, _generation: u64, _error: cpal::StreamError) {}
+    }
+
+    /// Writes `seconds` of 8kHz mono silence to a temporary WAV file.
+    fn write_test_wav(name: &str, seconds: u32) -> PathBuf {
+        write_samples_wav(name, (0..8000 * seconds).map(|_| 0.0))
+    }
+
+    /// Writes 8kHz mono `samples` to a temporary WAV file.
+    fn write_samples_wav(name: &str, samples: impl Iterator<Item = f32>) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kotonoha-{}-{}.wav", name, std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in samples {
+            writer.write_sample((sample * 32767.0) as i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    /// Seeks while `output` plays, since the sink hands the seek to the audio
+    /// thread and waits for its result.
+    fn seek_on_output(
+        state: &AudioState,
+        emitter: &RecordingEmitter,
+        output: &NullOutput,
+        position_ms: u32,
+    ) -> Result<(), String> {
+        thread::scope(|scope| {
+            let seek = scope.spawn(|| seek_playback(state, emitter.clone(), position_ms));
+            while !seek.is_finished() {
+                output.advance(Duration::from_millis(1));
+                thread::sleep(Duration::from_millis(1));
+            }
+            seek.join().unwrap()
+        })
+    }
+
+    /// Has the tracker of `state` poll the sink once, and waits until it has.
+    fn tick_position_tracker(state: &AudioState) {
+        let (done, finished) = mpsc::channel();
+        state
+            .playback_position_tracker
+            .lock()
+            .unwrap()
+            .as_ref()
+            .expect("the tracker has not been started")
+            .sender
+            .send(TrackerMessage::Tick(done))
+            .unwrap();
+        finished.recv().unwrap();
+    }
+
+    fn tone(n: u32, amplitude: f32) -> f32 {
+        (2.0 * std::f32::consts::PI * 440.0 * n as f32 / 8000.0).sin() * amplitude
+    }
+
+    #[test]
+    fn test_playback_state_machine_emits_each_state_once() {
+        let mut machine = PlaybackStateMachine::default();
+
+        let events: Vec<_> = [
+            PlaybackInput::Play,
+            PlaybackInput::Play,
+            PlaybackInput::Seek(1000),
+            PlaybackInput::Pause,
+            PlaybackInput::Pause,
+            PlaybackInput::Play,
+            PlaybackInput::ReachedEnd,
+            PlaybackInput::Seek(500),
+            PlaybackInput::Fail("device lost".to_string()),
+            PlaybackInput::Stop,
+            PlaybackInput::Stop,
+        ]
+        .into_iter()
+        .filter_map(|input| machine.handle(input))
+        .collect();
+
+        assert_eq!(
+            events,
+            vec![
+                PlaybackStateEvent::Playing,
+                PlaybackStateEvent::Seeked { ms: 1000 },
+                PlaybackStateEvent::Paused,
+                PlaybackStateEvent::Playing,
+                PlaybackStateEvent::Ended,
+                PlaybackStateEvent::Seeked { ms: 500 },
+                PlaybackStateEvent::Error {
+                    message: "device lost".to_string()
+                },
+                PlaybackStateEvent::Stopped,
+            ]
+        );
+        assert_eq!(machine.status, PlaybackStatus::Stopped);
+    }
+
+    #[test]
+    fn test_single_position_tracker_across_play_seek_pause() {
+        let audio_path = write_test_wav("single-tracker", 10);
+        let state = AudioState::default();
+        *state.audio_path.lock().unwrap() = Some(audio_path.clone());
+        let output = NullOutput::new(1, 8000);
+        state.stream.lock().unwrap().output = Some(Box::new(output.clone()));
+        let emitter = RecordingEmitter::default();
+        let running_threads = Arc::clone(&state.tracker_threads);
+        let tracker_count = || running_threads.load(Ordering::SeqCst);
+
+        // play, then a burst of seeks, pause, resume and more seeks.
+        start_playback(&state, emitter.clone()).unwrap();
+        assert_eq!(tracker_count(), 1);
+        for i in 0..20 {
+            seek_on_output(&state, &emitter, &output, i * 100).unwrap();
+        }
+        pause_playback(&state, &emitter);
+        seek_on_output(&state, &emitter, &output, 3000).unwrap();
+        resume_playback(&state, emitter.clone());
+        for i in 0..5 {
+            seek_on_output(&state, &emitter, &output, 4000 + i * 100).unwrap();
+        }
+        // Playing again from the start replaces the sink, not the tracker.
+        start_playback(&state, emitter.clone()).unwrap();
+        assert_eq!(tracker_count(), 1);
+
+        output.advance(Duration::from_millis(500));
+        tick_position_tracker(&state);
+        assert!(!emitter.positions.lock().unwrap().is_empty());
+
+        // Once paused, the tracker stops reporting.
+        pause_playback(&state, &emitter);
+        tick_position_tracker(&state);
+        let reported = emitter.positions.lock().unwrap().len();
+        tick_position_tracker(&state);
+        assert_eq!(emitter.positions.lock().unwrap().len(), reported);
+        assert_eq!(tracker_count(), 1);
+
+        // Dropping the state shuts the thread down.
+        drop(state);
+        assert_eq!(tracker_count(), 0);
+        std::fs::remove_file(&audio_path).unwrap();
+    }
+
+    #[test]
+    fn test_secondary_track_starts_once_when_queued() {
+        let secondary = SecondaryTrack::default();
+        assert!(!secondary.start_if_queued());
+
+        let (sink, _output) = Sink::new();
+        sink.append(SamplesBuffer::new(1, 1000, vec![0.0; 1000]));
+        sink.pause();
+        secondary.replace(sink, true);
+        assert!(!secondary.is_playing());
+        // Only resumable once the tracker has started it.
+        assert!(!secondary.resume());
+
+        assert!(secondary.start_if_queued());
+        assert!(secondary.is_playing());
+        assert!(!secondary.start_if_queued());
+
+        secondary.pause();
+        assert!(!secondary.is_playing());
+        assert!(secondary.resume());
+        secondary.clear();
+        assert!(!secondary.is_playing());
+        assert!(!secondary.resume());
+    }
+
+    #[test]
+    fn test_rebuild_playback_keeps_position_and_playing_state() {
+        let audio_path = write_test_wav("rebuild", 3);
+
+        let state = AudioState::default();
+        *state.audio_path.lock().unwrap() = Some(audio_path.clone());
+        // Sinks without an output device stand in for the old and new streams.
+        let (old_sink, _old_output) = Sink::new();
+        old_sink.append(SamplesBuffer::new(1, 1000, vec![0.0; 10_000]));
+        *state.sink.lock().unwrap() = Some(old_sink);
+        state.playback_control.set_position_ms(1500);
+        state
+            .playback_state
+            .lock()
+            .unwrap()
+            .handle(PlaybackInput::Play);
+        let emitter = RecordingEmitter::default();
+
+        let (sink, _output) = Sink::new();
+        rebuild_playback(&state, emitter.clone(), sink, true).unwrap();
+        assert_eq!(state.playback_control.position_ms(), 1500);
+        assert!(!state.sink.lock().unwrap().as_ref().unwrap().is_paused());
+
+        // A lost device resumes paused on the fallback device.
+        let (sink, _output) = Sink::new();
+        rebuild_playback(&state, emitter.clone(), sink, false).unwrap();
+        assert_eq!(state.playback_control.position_ms(), 1500);
+        assert!(state.sink.lock().unwrap().as_ref().unwrap().is_paused());
+        assert_eq!(
+            state.playback_state.lock().unwrap().status,
+            PlaybackStatus::Paused
+        );
+
+        drop(state);
+        std::fs::remove_file(&audio_path).unwrap();
+    }
+
+    #[test]
+    fn test_playback_commands_on_null_output() {
+        let audio_path = write_test_wav("null-output", 2);
+        let state = AudioState::default();
+        *state.audio_path.lock().unwrap() = Some(audio_path.clone());
+        let output = NullOutput::new(1, 8000);
+        state.stream.lock().unwrap().output = Some(Box::new(output.clone()));
+        let emitter = RecordingEmitter::default();
+        let position = || state.playback_control.position_ms() as i64;
+        let assert_near = |actual: i64, expected: i64| {
+            assert!(
+                (actual - expected).abs() <= 60,
+                "{} != {}",
+                actual,
+                expected
+            );
+        };
+
+        start_playback(&state, emitter.clone()).unwrap();
+        output.advance(Duration::from_millis(500));
+        assert_near(position(), 500);
+
+        // The sink applies a pause within a few milliseconds of output.
+        pause_playback(&state, &emitter);
+        output.advance(Duration::from_millis(10));
+        let paused_at = position();
+        output.advance(Duration::from_millis(500));
+        assert_eq!(position(), paused_at);
+
+        resume_playback(&state, emitter.clone());
+        output.advance(Duration::from_millis(300));
+        assert_near(position(), paused_at + 300);
+
+        // The seek has been applied when it returns.
+        seek_on_output(&state, &emitter, &output, 200).unwrap();
+        assert_near(position(), 200);
+        output.advance(Duration::from_millis(100));
+        assert_near(position(), 300);
+
+        seek_on_output(&state, &emitter, &output, 1800).unwrap();
+        output.advance(Duration::from_millis(500));
+        tick_position_tracker(&state);
+        assert_eq!(
+            state.playback_state.lock().unwrap().status,
+            PlaybackStatus::Ended
+        );
+        assert!(state.sink.lock().unwrap().as_ref().unwrap().is_paused());
+
+        assert_eq!(
+            *emitter.states.lock().unwrap(),
+            vec![
+                PlaybackStateEvent::Playing,
+                PlaybackStateEvent::Paused,
+                PlaybackStateEvent::Playing,
+                PlaybackStateEvent::Seeked { ms: 200 },
+                PlaybackStateEvent::Seeked { ms: 1800 },
+                PlaybackStateEvent::Ended,
+            ]
+        );
+
+        drop(state);
+        std::fs::remove_file(&audio_path).unwrap();
+    }
+
+    #[test]
+    fn test_lost_device_falls_back_to_the_default_device_paused() {
+        let audio_path = write_test_wav("device-lost", 3);
+        let state = AudioState::default();
+        *state.audio_path.lock().unwrap() = Some(audio_path.clone());
+        let output = NullOutput::new(1, 8000);
+        {
+            let mut stream = state.stream.lock().unwrap();
+            stream.output = Some(Box::new(output.clone()));
+            stream.device_id = Some("USB Headphones".to_string());
+            stream.generation = 1;
+            stream.open_output = |_, _| Ok(Box::new(NullOutput::new(1, 8000)));
+        }
+        let emitter = RecordingEmitter::default();
+
+        start_playback(&state, emitter.clone()).unwrap();
+        output.advance(Duration::from_millis(500));
+        let position_ms = state.playback_control.position_ms();
+
+        // An error of a stream that has been replaced is ignored.
+        handle_stream_error(&state, &emitter, 0, cpal::StreamError::DeviceNotAvailable);
+        assert!(emitter.lost_devices.lock().unwrap().is_empty());
+        assert_eq!(state.stream.lock().unwrap().generation, 1);
+        assert!(!state.sink.lock().unwrap().as_ref().unwrap().is_paused());
+
+        handle_stream_error(&state, &emitter, 1, cpal::StreamError::DeviceNotAvailable);
+        assert_eq!(
+            *emitter.lost_devices.lock().unwrap(),
+            vec![OutputDeviceLost {
+                device_id: Some("USB Headphones".to_string()),
+                message: cpal::StreamError::DeviceNotAvailable.to_string(),
+            }]
+        );
+        {
+            let stream = state.stream.lock().unwrap();
+            assert_eq!(stream.device_id, None);
+            assert_eq!(stream.generation, 2);
+        }
+        assert!(state.sink.lock().unwrap().as_ref().unwrap().is_paused());
+        assert_eq!(state.playback_control.position_ms(), position_ms);
+        assert_eq!(
+            *emitter.states.lock().unwrap(),
+            vec![PlaybackStateEvent::Playing, PlaybackStateEvent::Paused]
+        );
+
+        drop(state);
+        std::fs::remove_file(&audio_path).unwrap();
+    }
+
+    #[test]
+    fn test_playback_waits_at_the_end_of_growing_media() {
+        let audio_path = write_test_wav("growing", 1);
+        let state = AudioState::default();
+        *state.audio_path.lock().unwrap() = Some(audio_path.clone());
+        let output = NullOutput::new(1, 8000);
+        state.stream.lock().unwrap().output = Some(Box::new(output.clone()));
+        state.playback_control.set_media_growing(true);
+        let emitter = RecordingEmitter::default();
+        let status = || state.playback_state.lock().unwrap().status;
+
+        start_playback(&state, emitter.clone()).unwrap();
+        output.advance(Duration::from_millis(1500));
+        tick_position_tracker(&state);
+        assert_eq!(
+            state.playback_control.hold_reason(),
+            Some(HoldReason::MediaEnd)
+        );
+        assert_eq!(status(), PlaybackStatus::Playing);
+        assert!(!state.sink.lock().unwrap().as_ref().unwrap().is_paused());
+
+        // More audio is written: playback goes on from where it waited.
+        let waited_at = state.playback_control.position_ms();
+        let mut writer = hound::WavWriter::append(&audio_path).unwrap();
+        for _ in 0..8000 {
+            writer.write_sample(0_i16).unwrap();
+        }
+        writer.finalize().unwrap();
+        continue_growing_media(&state, &emitter, &audio_path, false);
+        assert_eq!(state.playback_control.hold_reason(), None);
+        assert_eq!(state.playback_control.position_ms(), waited_at);
+        assert_eq!(status(), PlaybackStatus::Playing);
+        output.advance(Duration::from_millis(500));
+        let position_ms = state.playback_control.position_ms();
+        assert!(
+            (position_ms as i64 - (waited_at + 500) as i64).abs() <= 60,
+            "{} != {}",
+            position_ms,
+            waited_at + 500
+        );
+
+        // Once the file is complete, its end is the end of the playback.
+        continue_growing_media(&state, &emitter, &audio_path, true);
+        output.advance(Duration::from_millis(1000));
+        tick_position_tracker(&state);
+        assert_eq!(status(), PlaybackStatus::Ended);
+        assert!(state.sink.lock().unwrap().as_ref().unwrap().is_paused());
+
+        drop(state);
+        std::fs::remove_file(&audio_path).unwrap();
+    }
+
+    #[test]
+    fn test_normalized_transient_does_not_clip() {
+        // Quiet speech-like tone with a single half-scale click in it.
+        let path = write_samples_wav(
+            "transient",
+            (0..8000 * 3).map(|n| if n == 12_000 { 0.5 } else { tone(n, 0.05) }),
+        );
+
+        let peak_file = generate_peak_file(&path).unwrap();
+        let gain = loudness_gain(&peak_file);
+        assert!(gain > 1.0 && gain <= 2.0, "gain: {}", gain);
+
+        let control = Arc::new(PlaybackControl::default());
+        control.set_gain(gain);
+        let source = PlaybackSource::new(open_audio_decoder(&path).unwrap(), control);
+        let output: Vec<f32> = source.take(8000 * 3).collect();
+
+        let peak = output.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+        assert!(peak <= 1.0, "peak: {}", peak);
+        assert!(peak > 0.9, "peak: {}", peak);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_detect_silences_reports_the_decoded_length() {
+        // Two seconds of silence, then speech up to the very end.
+        let path = write_samples_wav(
+            "silence-then-speech",
+            (0..8000 * 3).map(|n| if n < 16_000 { 0.0 } else { tone(n, 0.5) }),
+        );
+
+        let (silences, total_ms) = detect_silences(&path, 500).unwrap();
+
+        assert_eq!(total_ms, 3000);
+        assert_eq!(silences.len(), 1);
+        assert!(silences[0].end_ms < 2100, "{:?}", silences);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_silences_between_includes_leading_and_trailing_silence() {
+        let speech = vec![
+            SpeechSegment {
+                start_ms: 1000,
+                end_ms: 2000,
+            },
+            SpeechSegment {
+                start_ms: 2200,
+                end_ms: 3000,
+            },
+            SpeechSegment {
+                start_ms: 4000,
+                end_ms: 5000,
+            },
+        ];
+
+        assert_eq!(
+            silences_between(&speech, 5000, 500),
+            vec![
+                SilenceSegment {
+                    start_ms: 0,
+                    end_ms: 1000,
+                },
+                SilenceSegment {
+                    start_ms: 3000,
+                    end_ms: 4000,
+                },
+            ]
+        );
+        assert_eq!(
+            silences_between(&[], 800, 500),
+            vec![SilenceSegment {
+                start_ms: 0,
+                end_ms: 800,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_detect_speech_from_levels_merges_short_pauses() {
+        // 20ms frames: silence, speech, short pause, speech, long pause, speech
+        let mut levels = vec![-80.0_f32; 100];
+        levels[10..30].fill(-20.0); // 200ms - 600ms
+        levels[35..50].fill(-20.0); // 700ms - 1000ms (100ms pause before)
+        levels[80..90].fill(-20.0); // 1600ms - 1800ms
+
+        let segments = detect_speech_from_levels(&levels, 20, 300, 50);
+
+        assert_eq!(
+            segments,
+            vec![
+                SpeechSegment {
+                    start_ms: 150,
+                    end_ms: 1050,
+                },
+                SpeechSegment {
+                    start_ms: 1550,
+                    end_ms: 1850,
+                },
+            ]
+        );
+    }
+}
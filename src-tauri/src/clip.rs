@@ -0,0 +1,386 @@
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::info;
+use mp3lame_encoder::{Builder as Mp3EncoderBuilder, FlushNoGap, InterleavedPcm, MonoPcm};
+use rodio::Source;
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+
+use crate::audio::{downmix_to_mono, open_audio_decoder};
+use crate::tts::create_vorbis_encoder;
+
+/// Length of the fade-in and fade-out applied to every clip.
+const FADE_MS: u32 = 10;
+const CLIP_DIR: &str = "clips";
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioClipFormat {
+    Ogg,
+    Wav,
+    Mp3,
+}
+
+impl AudioClipFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            AudioClipFormat::Ogg => "ogg",
+            AudioClipFormat::Wav => "wav",
+            AudioClipFormat::Mp3 => "mp3",
+        }
+    }
+}
+
+/// Interleaved samples of a part of an audio file.
+pub(crate) struct AudioClip {
+    pub(crate) samples: Vec<f32>,
+    pub(crate) channels: u16,
+    pub(crate) sample_rate: u32,
+}
+
+/// Decodes `start_ms..end_ms` of `file_path`, widened by `padding_ms` on both
+/// sides. Files with more than two channels are mixed down to mono.
+pub(crate) fn extract_clip(
+    file_path: &Path,
+    start_ms: u32,
+    end_ms: u32,
+    padding_ms: u32,
+) -> Result<AudioClip, String> {
+    if end_ms <= start_ms {
+        return Err(format!("Invalid clip range: {}-{}", start_ms, end_ms));
+    }
+    let mut decoder = open_audio_decoder(file_path)?;
+    let channels = decoder.channels().max(1);
+    let sample_rate = decoder.sample_rate();
+
+    let clip_start_ms = start_ms.saturating_sub(padding_ms);
+    let clip_end_ms = end_ms.saturating_add(padding_ms);
+    decoder
+        .try_seek(Duration::from_millis(clip_start_ms as u64))
+        .map_err(|e| format!("Failed to seek audio: {}", e))?;
+
+    let frames = (clip_end_ms - clip_start_ms) as u64 * sample_rate as u64 / 1000;
+    let samples = decoder.take(frames as usize * channels as usize);
+    let (samples, channels): (Vec<f32>, u16) = if channels > 2 {
+        (downmix_to_mono(samples, channels).collect(), 1)
+    } else {
+        (samples.collect(), channels)
+    };
+    if samples.is_empty() {
+        return Err("The clip range is beyond the end of the audio".to_string());
+    }
+
+    Ok(AudioClip {
+        samples,
+        channels,
+        sample_rate,
+    })
+}
+
+/// Fades the clip in and out linearly so that it does not start or end with a
+/// click.
+fn apply_fades(clip: &mut AudioClip) {
+    let channels = clip.channels as usize;
+    let frame_count = clip.samples.len() / channels;
+    let fade_frames = ((clip.sample_rate * FADE_MS / 1000) as usize).min(frame_count / 2);
+    for i in 0..fade_frames {
+        let gain = i as f32 / fade_frames as f32;
+        for c in 0..channels {
+            clip.samples[i * channels + c] *= gain;
+            clip.samples[(frame_count - 1 - i) * channels + c] *= gain;
+        }
+    }
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+fn encode_wav(clip: &AudioClip, output_path: &Path) -> Result<(), String> {
+    let spec = WavSpec {
+        channels: clip.channels,
+        sample_rate: clip.sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let mut writer = WavWriter::create(output_path, spec)
+        .map_err(|e| format!("Could not create WAV file: {}", e))?;
+    for &sample in &clip.samples {
+        writer
+            .write_sample(to_i16(sample))
+            .map_err(|e| format!("Could not write WAV sample: {}", e))?;
+    }
+    writer
+        .finalize()
+        .map_err(|e| format!("Could not finish WAV file: {}", e))
+}
+
+fn encode_ogg(clip: &AudioClip) -> Result<Vec<u8>, String> {
+    let channels = clip.channels as usize;
+    let planar: Vec<Vec<f32>> = (0..channels)
+        .map(|c| {
+            clip.samples
+                .iter()
+                .skip(c)
+                .step_by(channels)
+                .copied()
+                .collect()
+        })
+        .collect();
+
+    let mut output = vec![];
+    let mut encoder = create_vorbis_encoder(&mut output, clip.sample_rate, clip.channels as u8)?;
+    encoder
+        .encode_audio_block(&planar)
+        .map_err(|e| format!("Could not encode audio block: {:?}", e))?;
+    encoder
+        .finish()
+        .map_err(|e| format!("Could not finish encoding: {:?}", e))?;
+    Ok(output)
+}
+
+fn encode_mp3(clip: &AudioClip) -> Result<Vec<u8>, String> {
+    let mut builder = Mp3EncoderBuilder::new().ok_or("Could not create MP3 encoder")?;
+    builder
+        .set_num_channels(clip.channels as u8)
+        .map_err(|e| format!("Could not set MP3 channels: {:?}", e))?;
+    builder
+        .set_sample_rate(clip.sample_rate)
+        .map_err(|e| format!("Could not set MP3 sample rate: {:?}", e))?;
+    builder
+        .set_brate(mp3lame_encoder::Bitrate::Kbps128)
+        .map_err(|e| format!("Could not set MP3 bitrate: {:?}", e))?;
+    let mut encoder = builder
+        .build()
+        .map_err(|e| format!("Could not build MP3 encoder: {:?}", e))?;
+
+    let pcm: Vec<i16> = clip.samples.iter().map(|&s| to_i16(s)).collect();
+    let mut output = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(pcm.len()));
+    let encoded = if clip.channels == 1 {
+        encoder.encode_to_vec(MonoPcm(&pcm), &mut output)
+    } else {
+        encoder.encode_to_vec(InterleavedPcm(&pcm), &mut output)
+    };
+    encoded.map_err(|e| format!("Could not encode MP3: {:?}", e))?;
+    encoder
+        .flush_to_vec::<FlushNoGap>(&mut output)
+        .map_err(|e| format!("Could not finish MP3 encoding: {:?}", e))?;
+    Ok(output)
+}
+
+/// Path of a clip relative to the directory of the episode audio, e.g.
+/// `media/<uuid>/clips/1200-3400-p250.ogg` for `media/<uuid>/full.mp3`.
+///
+/// Every setting that changes the clip is part of the name, so exporting the
+/// same line with other settings does not overwrite a clip a card may
+/// already use. The fades and bitrates are fixed for each format.
+fn clip_relative_path(
+    episode_audio: &str,
+    start_ms: u32,
+    end_ms: u32,
+    padding_ms: u32,
+    format: AudioClipFormat,
+) -> PathBuf {
+    Path::new(episode_audio)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(CLIP_DIR)
+        .join(format!(
+            "{}-{}-p{}.{}",
+            start_ms,
+            end_ms,
+            padding_ms,
+            format.extension()
+        ))
+}
+
+#[tauri::command]
+pub async fn export_audio_clip(
+    app_handle: AppHandle,
+    episode_audio: String,
+    start_ms: u32,
+    end_ms: u32,
+    padding_ms: Option<u32>,
+    format: AudioClipFormat,
+) -> Result<String, String> {
+    info!(
+        "export_audio_clip: {} {}-{} ({:?})",
+        episode_audio, start_ms, end_ms, format
+    );
+    let audio_path = app_handle
+        .path()
+        .resolve(&episode_audio, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Failed to resolve path '{}': {}", episode_audio, e))?;
+    let padding_ms = padding_ms.unwrap_or(0);
+    let clip_path = clip_relative_path(&episode_audio, start_ms, end_ms, padding_ms, format);
+    let output_path = app_handle
+        .path()
+        .resolve(&clip_path, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Failed to resolve clip path: {}", e))?;
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Failed to create clip directory: {}", e))?;
+    }
+
+    let mut clip = extract_clip(&audio_path, start_ms, end_ms, padding_ms)?;
+    apply_fades(&mut clip);
+    match format {
+        AudioClipFormat::Wav => encode_wav(&clip, &output_path)?,
+        AudioClipFormat::Ogg => fs::write(&output_path, encode_ogg(&clip)?)
+            .map_err(|e| format!("Could not write clip file: {}", e))?,
+        AudioClipFormat::Mp3 => fs::write(&output_path, encode_mp3(&clip)?)
+            .map_err(|e| format!("Could not write clip file: {}", e))?,
+    }
+
+    Ok(clip_path.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// Writes a WAV file whose samples are `sample(frame, channel)`.
+    fn write_test_wav(
+        name: &str,
+        channels: u16,
+        seconds: u32,
+        sample: impl Fn(u32, u16) -> i16,
+    ) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kotonoha-clip-{}-{}.wav", name, std::process::id()));
+        let spec = WavSpec {
+            channels,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let mut writer = WavWriter::create(&path, spec).unwrap();
+        for frame in 0..SAMPLE_RATE * seconds {
+            for channel in 0..channels {
+                writer.write_sample(sample(frame, channel)).unwrap();
+            }
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    fn to_frame_index(sample: f32) -> i32 {
+        (sample * 32768.0).round() as i32
+    }
+
+    #[test]
+    fn test_extract_clip_seeks_and_pads() {
+        // Every sample holds its frame index (modulo 8000).
+        let path = write_test_wav("padded", 2, 2, |frame, _| (frame % 8000) as i16);
+
+        let clip = extract_clip(&path, 500, 1000, 100).unwrap();
+        assert_eq!((clip.channels, clip.sample_rate), (2, SAMPLE_RATE));
+        // 400-1100ms of stereo audio.
+        assert_eq!(clip.samples.len(), 5600 * 2);
+        assert_eq!(to_frame_index(clip.samples[0]), 3200);
+        assert_eq!(to_frame_index(clip.samples[1]), 3200);
+        assert_eq!(to_frame_index(clip.samples[2]), 3201);
+
+        // Padding is clipped at the start of the audio.
+        let clip = extract_clip(&path, 50, 100, 100).unwrap();
+        assert_eq!(clip.samples.len(), 1600 * 2);
+        assert_eq!(to_frame_index(clip.samples[0]), 0);
+
+        // And at the end.
+        let clip = extract_clip(&path, 1900, 2000, 500).unwrap();
+        assert_eq!(clip.samples.len(), 4800 * 2);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_clip_mixes_surround_down_to_mono() {
+        let path = write_test_wav("surround", 4, 1, |_, channel| (channel as i16 + 1) * 1000);
+
+        let clip = extract_clip(&path, 0, 500, 0).unwrap();
+        assert_eq!(clip.channels, 1);
+        assert_eq!(clip.samples.len(), 4000);
+        assert_eq!(to_frame_index(clip.samples[100]), 2500);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_extract_clip_rejects_invalid_ranges() {
+        let path = write_test_wav("invalid", 1, 1, |_, _| 0);
+
+        assert!(extract_clip(&path, 500, 500, 0).is_err());
+        assert!(extract_clip(&path, 600, 500, 0).is_err());
+        assert_eq!(
+            extract_clip(&path, 2000, 3000, 0).err(),
+            Some("The clip range is beyond the end of the audio".to_string())
+        );
+        assert!(extract_clip(Path::new("/nonexistent/audio.wav"), 0, 100, 0).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_apply_fades_ramps_both_ends() {
+        let mut clip = AudioClip {
+            samples: vec![1.0; 1000 * 2],
+            channels: 2,
+            sample_rate: SAMPLE_RATE,
+        };
+        apply_fades(&mut clip);
+
+        // 10ms at 8kHz.
+        let fade_frames = 80;
+        assert_eq!(clip.samples[0..2], [0.0, 0.0]);
+        assert_eq!(clip.samples[40 * 2], 0.5);
+        assert_eq!(clip.samples[fade_frames * 2], 1.0);
+        assert_eq!(clip.samples[1000 * 2 - 1], 0.0);
+        assert_eq!(clip.samples[(999 - 40) * 2 + 1], 0.5);
+        assert_eq!(clip.samples[(999 - fade_frames) * 2], 1.0);
+
+        // A clip shorter than both fades fades over half of it each way.
+        let mut clip = AudioClip {
+            samples: vec![1.0; 10],
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+        };
+        apply_fades(&mut clip);
+        assert_eq!(
+            clip.samples,
+            [0.0, 0.2, 0.4, 0.6, 0.8, 0.8, 0.6, 0.4, 0.2, 0.0]
+        );
+    }
+
+    #[test]
+    fn test_clip_relative_path_is_next_to_the_episode_audio() {
+        assert_eq!(
+            clip_relative_path("media/abc/full.mp3", 1200, 3400, 250, AudioClipFormat::Ogg),
+            PathBuf::from("media/abc/clips/1200-3400-p250.ogg")
+        );
+        assert_eq!(
+            clip_relative_path("full.mp3", 0, 500, 0, AudioClipFormat::Mp3),
+            PathBuf::from("clips/0-500-p0.mp3")
+        );
+    }
+
+    #[test]
+    fn test_clip_relative_path_differs_for_every_setting() {
+        let path = |padding_ms, format| {
+            clip_relative_path("media/abc/full.mp3", 1200, 3400, padding_ms, format)
+        };
+        assert_ne!(
+            path(0, AudioClipFormat::Ogg),
+            path(250, AudioClipFormat::Ogg)
+        );
+        assert_ne!(
+            path(250, AudioClipFormat::Ogg),
+            path(250, AudioClipFormat::Mp3)
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use log::info;
+use rodio::Source;
+use serde::Serialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+
+use crate::audio::{detect_silences, downmix_to_mono, open_audio_decoder, SilenceSegment};
+use crate::tts::create_vorbis_encoder;
+
+const DEFAULT_MIN_SILENCE_MS: u32 = 1000;
+/// Frames handed to the encoder at a time.
+const ENCODE_BLOCK_FRAMES: usize = 4096;
+
+/// A part of the source audio that is kept in the compacted audio.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeMapSegment {
+    source_start_ms: u32,
+    source_end_ms: u32,
+    output_start_ms: u32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CompactedAudio {
+    /// Relative to AppLocalData, like the episode audio.
+    media_path: String,
+    duration_ms: u32,
+    time_map: Vec<TimeMapSegment>,
+}
+
+/// Keeps everything but the middle of silences longer than `max_gap_ms`, so
+/// that every silence is at most `max_gap_ms` long afterwards.
+fn build_time_map(
+    silences: &[SilenceSegment],
+    duration_ms: u32,
+    max_gap_ms: u32,
+) -> Vec<TimeMapSegment> {
+    let mut time_map = Vec::new();
+    let mut source_start_ms = 0;
+    let mut output_start_ms = 0;
+    let mut keep_until = |source_end_ms: u32, next_start_ms: u32| {
+        if source_end_ms > source_start_ms {
+            time_map.push(TimeMapSegment {
+                source_start_ms,
+                source_end_ms,
+                output_start_ms,
+            });
+            output_start_ms += source_end_ms - source_start_ms;
+        }
+        source_start_ms = next_start_ms;
+    };
+    for silence in silences {
+        if silence.end_ms - silence.start_ms <= max_gap_ms {
+            continue;
+        }
+        let cut_start_ms = silence.start_ms + max_gap_ms / 2;
+        let cut_end_ms = silence.end_ms - (max_gap_ms - max_gap_ms / 2);
+        keep_until(cut_start_ms, cut_end_ms);
+    }
+    keep_until(duration_ms, duration_ms);
+    time_map
+}
+
+/// Position in the compacted audio of `source_ms`. Positions inside a removed
+/// part map to the point where it was cut.
+fn remap_ms(time_map: &[TimeMapSegment], source_ms: u32) -> u32 {
+    let index = time_map.partition_point(|segment| segment.source_end_ms <= source_ms);
+    match time_map.get(index) {
+        Some(segment) if segment.source_start_ms <= source_ms => {
+            segment.output_start_ms + (source_ms - segment.source_start_ms)
+        }
+        Some(segment) => segment.output_start_ms,
+        None => time_map.last().map_or(0, |segment| {
+            segment.output_start_ms + (segment.source_end_ms - segment.source_start_ms)
+        }),
+    }
+}
+
+fn ms_to_frames(ms: u32, sample_rate: u32) -> u64 {
+    ms as u64 * sample_rate as u64 / 1000
+}
+
+/// Decodes `source_path` and encodes the parts kept by `time_map` to Ogg
+/// Vorbis, one block at a time.
+fn write_compacted_audio(
+    source_path: &Path,
+    output_path: &Path,
+    time_map: &[TimeMapSegment],
+) -> Result<(), String> {
+    let decoder = open_audio_decoder(source_path)?;
+    let source_channels = decoder.channels().max(1);
+    let sample_rate = decoder.sample_rate();
+    let (samples, channels): (Box<dyn Iterator<Item = f32>>, usize) = if source_channels > 2 {
+        (Box::new(downmix_to_mono(decoder, source_channels)), 1)
+    } else {
+        (Box::new(decoder), source_channels as usize)
+    };
+    // The file is decoded from start to end rather than seeked, so that the
+    // cuts are exact in every format.
+    let mut kept_frames = time_map.iter().map(|segment| {
+        ms_to_frames(segment.source_start_ms, sample_rate)
+            ..ms_to_frames(segment.source_end_ms, sample_rate)
+    });
+    let mut kept = kept_frames.next();
+
+    let mut output = vec![];
+    let mut encoder = create_vorbis_encoder(&mut output, sample_rate, channels as u8)?;
+    let mut block: Vec<Vec<f32>> = vec![Vec::with_capacity(ENCODE_BLOCK_FRAMES); channels];
+    for (index, sample) in samples.enumerate() {
+        let frame = (index / channels) as u64;
+        while kept.as_ref().is_some_and(|range| range.end <= frame) {
+            kept = kept_frames.next();
+        }
+        match &kept {
+            Some(range) if range.contains(&frame) => {}
+            Some(_) => continue,
+            None => break,
+        }
+        block[index % channels].push(sample);
+        if block[channels - 1].len() == ENCODE_BLOCK_FRAMES {
+            encoder
+                .encode_audio_block(&block)
+                .map_err(|e| format!("Could not encode audio block: {:?}", e))?;
+            block.iter_mut().for_each(Vec::clear);
+        }
+    }
+    if !block[0].is_empty() {
+        encoder
+            .encode_audio_block(&block)
+            .map_err(|e| format!("Could not encode audio block: {:?}", e))?;
+    }
+    encoder
+        .finish()
+        .map_err(|e| format!("Could not finish encoding: {:?}", e))?;
+
+    fs::write(output_path, output).map_err(|e| format!("Could not write audio file: {}", e))
+}
+
+/// `media/<uuid>/full.compact.ogg` for `media/<uuid>/full.mp3`.
+fn compacted_relative_path(episode_audio: &str) -> PathBuf {
+    let path = Path::new(episode_audio);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}.compact.ogg", stem))
+}
+
+#[tauri::command]
+pub async fn export_compacted_audio(
+    app_handle: AppHandle,
+    episode_audio: String,
+    max_gap_ms: u32,
+    min_silence_ms: Option<u32>,
+) -> Result<CompactedAudio, String> {
+    info!(
+        "export_compacted_audio: {} (max gap {}ms)",
+        episode_audio, max_gap_ms
+    );
+    let audio_path = app_handle
+        .path()
+        .resolve(&episode_audio, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Failed to resolve path '{}': {}", episode_audio, e))?;
+    let media_path = compacted_relative_path(&episode_audio);
+    let output_path = app_handle
+        .path()
+        .resolve(&media_path, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Failed to resolve output path: {}", e))?;
+
+    let min_silence_ms = min_silence_ms
+        .unwrap_or(DEFAULT_MIN_SILENCE_MS)
+        .max(max_gap_ms);
+    let (silences, decoded_ms) = detect_silences(&audio_path, min_silence_ms)?;
+    // Containers without a duration in their header are measured by decoding.
+    let duration_ms = open_audio_decoder(&audio_path)?
+        .total_duration()
+        .map_or(decoded_ms, |duration| duration.as_millis() as u32);
+    let time_map = build_time_map(&silences, duration_ms, max_gap_ms);
+    info!(
+        "Shortening {} silences: {}ms -> {}ms",
+        silences.len(),
+        duration_ms,
+        remap_ms(&time_map, duration_ms)
+    );
+
+    write_compacted_audio(&audio_path, &output_path, &time_map)?;
+
+    Ok(CompactedAudio {
+        media_path: media_path.to_string_lossy().to_string(),
+        duration_ms: remap_ms(&time_map, duration_ms),
+        time_map,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn silence(start_ms: u32, end_ms: u32) -> SilenceSegment {
+        SilenceSegment { start_ms, end_ms }
+    }
+
+    #[test]
+    fn test_time_map_shortens_long_silences() {
+        let silences = vec![silence(0, 300), silence(1000, 3000), silence(4000, 4400)];
+        let time_map = build_time_map(&silences, 5000, 400);
+
+        assert_eq!(
+            time_map,
+            vec![
+                TimeMapSegment {
+                    source_start_ms: 0,
+                    source_end_ms: 1200,
+                    output_start_ms: 0,
+                },
+                TimeMapSegment {
+                    source_start_ms: 2800,
+                    source_end_ms: 5000,
+                    output_start_ms: 1200,
+                },
+            ]
+        );
+        assert_eq!(remap_ms(&time_map, 500), 500);
+        assert_eq!(remap_ms(&time_map, 2000), 1200);
+        assert_eq!(remap_ms(&time_map, 3000), 1400);
+        assert_eq!(remap_ms(&time_map, 5000), 3400);
+        assert_eq!(remap_ms(&time_map, 6000), 3400);
+    }
+}
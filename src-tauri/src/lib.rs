@@ -1,25 +1,48 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
+mod alignment;
+mod anki;
+mod anki_connect;
+mod asr;
 mod audio;
+mod clip;
+mod compact;
 mod download;
 mod language_detection;
 mod llm;
+mod loudness;
+mod microphone;
 mod migrations;
+mod opus;
+mod output_device;
+mod playback;
+mod pronunciation;
+mod spectral;
 mod stronghold;
 mod tts;
+mod waveform;
 mod youtube;
 
 use dotenvy::from_filename;
 use std::{env, fs};
 use tauri::Manager;
 
+use anki::export_anki_package;
+use anki_connect::push_cards_to_anki;
+use asr::{align_transcript, cancel_transcription, start_transcription};
 use audio::{
-    analyze_audio, copy_audio_file, open_audio, pause_audio, play_audio, resume_audio, seek_audio,
-    stop_audio, AudioState,
+    analyze_audio, copy_audio_file, detect_speech_segments, list_output_devices, open_audio,
+    pause_audio, play_audio, play_line_with_tts, play_range, record_microphone, resume_audio,
+    seek_audio, set_output_device, set_playback_rate, set_track_muted, set_volume, stop_audio,
+    stop_recording, AudioState,
 };
+use clip::export_audio_clip;
+use compact::export_compacted_audio;
 use download::{cancel_download, download_file_with_progress};
 use language_detection::detect_language_from_text;
 use llm::analyze_sentence_with_llm;
 use migrations::get_migrations;
+use pronunciation::compare_pronunciation;
+use spectral::analyze_audio_spectral;
 use stronghold::{create_salt_file_if_not_exists, get_stronghold_password};
 use tts::{cancel_tts, start_tts};
 use youtube::fetch_youtube_subtitle;
@@ -100,16 +123,35 @@ pub fn run() {
             get_env_prefix_command,
             open_audio,
             analyze_audio,
+            detect_speech_segments,
+            analyze_audio_spectral,
             play_audio,
             pause_audio,
             resume_audio,
             stop_audio,
             seek_audio,
+            play_range,
+            play_line_with_tts,
+            set_playback_rate,
+            set_volume,
+            set_track_muted,
+            list_output_devices,
+            set_output_device,
+            record_microphone,
+            stop_recording,
+            compare_pronunciation,
             read_text_file,
             copy_audio_file,
+            export_audio_clip,
+            export_compacted_audio,
+            export_anki_package,
+            push_cards_to_anki,
             fetch_youtube_subtitle,
             start_tts,
             cancel_tts,
+            start_transcription,
+            cancel_transcription,
+            align_transcript,
             detect_language_from_text,
             download_file_with_progress,
             cancel_download,
@@ -0,0 +1,208 @@
+// cSpell:words biquad
+use std::{collections::VecDeque, f64::consts::PI};
+
+/// Loudness every episode and the TTS output are normalized to. -16 LUFS is
+/// the usual target for spoken-word podcasts.
+pub(crate) const TARGET_LOUDNESS_LUFS: f32 = -16.0;
+/// Quiet recordings are not boosted by more than this, so that their noise
+/// floor stays acceptable.
+const MAX_NORMALIZATION_GAIN_DB: f32 = 12.0;
+
+const SUB_BLOCK_MS: u32 = 100;
+/// A gating block is 400ms long and starts every 100ms.
+const SUB_BLOCKS_PER_BLOCK: usize = 4;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// Second-order IIR filter in transposed direct form II.
+#[derive(Clone)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The two stages of the K-weighting filter of ITU-R BS.1770, designed for
+/// any sample rate the same way libebur128 does.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let fs = sample_rate as f64;
+
+    // high shelf modelling the acoustic effect of the head
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / fs).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    // RLB high-pass
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, high_pass]
+}
+
+/// Measures the integrated loudness (EBU R128) of interleaved samples fed one
+/// at a time, keeping only one value per 400ms block in memory.
+pub(crate) struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    sub_block_frames: usize,
+    /// Channel of the next sample.
+    channel: usize,
+    sum_squares: f64,
+    frames: usize,
+    /// Mean squares of the latest sub-blocks.
+    recent: VecDeque<f64>,
+    block_energies: Vec<f64>,
+}
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+impl LoudnessMeter {
+    pub(crate) fn new(sample_rate: u32, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        Self {
+            channels,
+            filters: vec![k_weighting(sample_rate); channels],
+            sub_block_frames: ((sample_rate * SUB_BLOCK_MS / 1000) as usize).max(1),
+            channel: 0,
+            sum_squares: 0.0,
+            frames: 0,
+            recent: VecDeque::with_capacity(SUB_BLOCKS_PER_BLOCK),
+            block_energies: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, sample: f32) {
+        let [shelf, high_pass] = &mut self.filters[self.channel];
+        let weighted = high_pass.process(shelf.process(sample as f64));
+        // All channels are weighted equally; surround weights are not needed
+        // for the mono and stereo material the app deals with.
+        self.sum_squares += weighted * weighted;
+
+        self.channel += 1;
+        if self.channel < self.channels {
+            return;
+        }
+        self.channel = 0;
+        self.frames += 1;
+        if self.frames < self.sub_block_frames {
+            return;
+        }
+
+        if self.recent.len() == SUB_BLOCKS_PER_BLOCK {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(self.sum_squares / self.frames as f64);
+        self.sum_squares = 0.0;
+        self.frames = 0;
+        if self.recent.len() == SUB_BLOCKS_PER_BLOCK {
+            let energy = self.recent.iter().sum::<f64>() / SUB_BLOCKS_PER_BLOCK as f64;
+            self.block_energies.push(energy);
+        }
+    }
+
+    /// Gated loudness of everything pushed so far in LUFS, or `None` if the
+    /// audio is silent or shorter than one block.
+    pub(crate) fn integrated_loudness(&self) -> Option<f32> {
+        let mean = |energies: &[f64]| energies.iter().sum::<f64>() / energies.len() as f64;
+
+        let above_absolute: Vec<f64> = self
+            .block_energies
+            .iter()
+            .copied()
+            .filter(|&e| energy_to_lufs(e) > ABSOLUTE_GATE_LUFS)
+            .collect();
+        if above_absolute.is_empty() {
+            return None;
+        }
+        let relative_gate = energy_to_lufs(mean(&above_absolute)) + RELATIVE_GATE_LU;
+        let gated: Vec<f64> = above_absolute
+            .into_iter()
+            .filter(|&e| energy_to_lufs(e) > relative_gate)
+            .collect();
+        Some(energy_to_lufs(mean(&gated)) as f32)
+    }
+}
+
+/// Linear gain that brings audio of `loudness_lufs` to the target loudness.
+pub(crate) fn normalization_gain(loudness_lufs: f32) -> f32 {
+    let gain_db = (TARGET_LOUDNESS_LUFS - loudness_lufs).min(MAX_NORMALIZATION_GAIN_DB);
+    10f32.powf(gain_db / 20.0)
+}
+
+/// Scales mono `samples` to the target loudness. Audio too short or too quiet
+/// to be measured is left unchanged.
+pub(crate) fn normalize_loudness(samples: &mut [f32], sample_rate: u32) {
+    let mut meter = LoudnessMeter::new(sample_rate, 1);
+    samples.iter().for_each(|&sample| meter.push(sample));
+    if let Some(loudness_lufs) = meter.integrated_loudness() {
+        let gain = normalization_gain(loudness_lufs);
+        for sample in samples.iter_mut() {
+            *sample = (*sample * gain).clamp(-1.0, 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(sample_rate: u32, channels: u16, amplitude: f32, seconds: u32) -> Option<f32> {
+        let mut meter = LoudnessMeter::new(sample_rate, channels);
+        for n in 0..sample_rate * seconds {
+            let t = n as f32 / sample_rate as f32;
+            let sample = amplitude * (2.0 * std::f32::consts::PI * 1000.0 * t).sin();
+            for _ in 0..channels {
+                meter.push(sample);
+            }
+        }
+        meter.integrated_loudness()
+    }
+
+    #[test]
+    fn test_integrated_loudness_of_reference_tones() {
+        // A full-scale 1kHz sine reads -3.01 LUFS per channel (BS.1770).
+        let mono = measure(48000, 1, 1.0, 3).unwrap();
+        assert!((mono + 3.01).abs() < 0.1, "{}", mono);
+        let stereo = measure(44100, 2, 0.1, 3).unwrap();
+        assert!((stereo + 20.0).abs() < 0.1, "{}", stereo);
+        assert_eq!(measure(48000, 1, 0.0, 3), None);
+    }
+
+    #[test]
+    fn test_normalization_gain_is_capped() {
+        assert!((normalization_gain(TARGET_LOUDNESS_LUFS) - 1.0).abs() < 1e-6);
+        assert!((normalization_gain(-10.0) - 0.5012).abs() < 1e-3);
+        assert!((normalization_gain(-60.0) - 3.981).abs() < 1e-2);
+    }
+}
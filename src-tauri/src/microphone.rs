@@ -0,0 +1,192 @@
+use hound::{SampleFormat, WavSpec, WavWriter};
+use log::{error, info};
+use rodio::cpal::{
+    self,
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SizedSample,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    thread::{self, JoinHandle},
+};
+
+use crate::audio::downmix_to_mono;
+
+enum RecorderMessage {
+    Samples(Vec<f32>),
+    Error(String),
+    Stop,
+}
+
+/// A take being recorded from the default input device.
+///
+/// The input stream lives on a thread of its own, which writes the captured
+/// audio to a mono WAV file as it arrives.
+pub(crate) struct MicrophoneRecording {
+    sender: Sender<RecorderMessage>,
+    thread: JoinHandle<Result<u64, String>>,
+}
+
+impl MicrophoneRecording {
+    /// Starts recording to `output_path`, returning once the input stream is
+    /// running.
+    pub(crate) fn start(output_path: PathBuf) -> Result<Self, String> {
+        let (sender, receiver) = mpsc::channel();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        let stream_sender = sender.clone();
+        let thread = thread::spawn(move || {
+            let (stream, channels, sample_rate) = match open_input_stream(stream_sender) {
+                Ok(opened) => opened,
+                Err(e) => {
+                    let _ = ready_sender.send(Err(e.clone()));
+                    return Err(e);
+                }
+            };
+            let _ = ready_sender.send(Ok(()));
+            let result = write_take(&receiver, channels, sample_rate, &output_path);
+            drop(stream);
+            if result.is_err() {
+                let _ = fs::remove_file(&output_path);
+            }
+            result
+        });
+        ready_receiver
+            .recv()
+            .map_err(|_| "The recording thread exited unexpectedly".to_string())??;
+        Ok(Self { sender, thread })
+    }
+
+    /// Stops recording and returns the duration of the take in milliseconds.
+    pub(crate) fn stop(self) -> Result<u64, String> {
+        // The thread may already have stopped because of an input error.
+        let _ = self.sender.send(RecorderMessage::Stop);
+        self.thread
+            .join()
+            .map_err(|_| "The recording thread panicked".to_string())?
+    }
+}
+
+fn open_input_stream(sender: Sender<RecorderMessage>) -> Result<(cpal::Stream, u16, u32), String> {
+    let device = cpal::default_host()
+        .default_input_device()
+        .ok_or("No input device is available".to_string())?;
+    info!(
+        "Recording from {}",
+        device
+            .name()
+            .unwrap_or_else(|_| "unknown device".to_string())
+    );
+    let supported_config = device
+        .default_input_config()
+        .map_err(|e| format!("Failed to get the input device config: {}", e))?;
+    let config = supported_config.config();
+    let stream = match supported_config.sample_format() {
+        cpal::SampleFormat::F32 => build_input_stream::<f32>(&device, &config, sender),
+        cpal::SampleFormat::I16 => build_input_stream::<i16>(&device, &config, sender),
+        cpal::SampleFormat::U16 => build_input_stream::<u16>(&device, &config, sender),
+        cpal::SampleFormat::I32 => build_input_stream::<i32>(&device, &config, sender),
+        format => return Err(format!("Unsupported input sample format: {}", format)),
+    }
+    .map_err(|e| format!("Failed to open the input stream: {}", e))?;
+    stream
+        .play()
+        .map_err(|e| format!("Failed to start the input stream: {}", e))?;
+    Ok((stream, config.channels, config.sample_rate.0))
+}
+
+fn build_input_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    sender: Sender<RecorderMessage>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let error_sender = sender.clone();
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let samples = data.iter().map(|&s| s.to_sample::<f32>()).collect();
+            let _ = sender.send(RecorderMessage::Samples(samples));
+        },
+        move |e| {
+            error!("Audio input error: {}", e);
+            let _ = error_sender.send(RecorderMessage::Error(e.to_string()));
+        },
+        None,
+    )
+}
+
+/// Writes the samples received until `Stop` to a 16-bit mono WAV file and
+/// returns its duration in milliseconds.
+fn write_take(
+    receiver: &Receiver<RecorderMessage>,
+    channels: u16,
+    sample_rate: u32,
+    output_path: &Path,
+) -> Result<u64, String> {
+    let spec = WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let mut writer = WavWriter::create(output_path, spec)
+        .map_err(|e| format!("Could not create recording file: {}", e))?;
+    let mut frames = 0_u64;
+    loop {
+        match receiver.recv() {
+            Ok(RecorderMessage::Samples(samples)) => {
+                for sample in downmix_to_mono(samples.into_iter(), channels) {
+                    writer
+                        .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+                        .map_err(|e| format!("Could not write recording: {}", e))?;
+                    frames += 1;
+                }
+            }
+            Ok(RecorderMessage::Error(message)) => {
+                return Err(format!("Recording failed: {}", message));
+            }
+            Ok(RecorderMessage::Stop) | Err(_) => break,
+        }
+    }
+    writer
+        .finalize()
+        .map_err(|e| format!("Could not finish recording: {}", e))?;
+    Ok(frames * 1000 / sample_rate as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_take_downmixes_until_stopped() {
+        let path = std::env::temp_dir().join(format!("kotonoha-take-{}.wav", std::process::id()));
+        let (sender, receiver) = mpsc::channel();
+        sender
+            .send(RecorderMessage::Samples([0.5, 0.0].repeat(4000)))
+            .unwrap();
+        sender
+            .send(RecorderMessage::Samples([-0.25, -0.25].repeat(4000)))
+            .unwrap();
+        sender.send(RecorderMessage::Stop).unwrap();
+        // Nothing after `Stop` is written.
+        sender
+            .send(RecorderMessage::Samples(vec![1.0; 1000]))
+            .unwrap();
+
+        let duration_ms = write_take(&receiver, 2, 16000, &path).unwrap();
+        assert_eq!(duration_ms, 500);
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, 1);
+        let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(samples.len(), 8000);
+        assert_eq!(samples[0], (0.25 * i16::MAX as f32) as i16);
+        assert_eq!(samples[7999], (-0.25 * i16::MAX as f32) as i16);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+use audiopus::{coder::Encoder, Application, Bitrate, Channels, SampleRate, Signal};
+use ogg::{PacketWriteEndInfo, PacketWriter};
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Ogg Opus always counts the granule position in 48kHz samples.
+const GRANULE_RATE: u32 = 48000;
+const FRAME_MS: u32 = 20;
+const BITRATE: i32 = 32000;
+/// The largest packet libopus produces for a single frame.
+const MAX_PACKET_LEN: usize = 4000;
+const VENDOR: &str = "kotonoha";
+
+/// The sample rates Opus can encode.
+pub(crate) const OPUS_SAMPLE_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+
+/// Encodes mono audio into an Ogg Opus stream (RFC 7845), one 20ms packet at
+/// a time.
+///
+/// The last packet is held back until the next one is ready, so that `flush`
+/// can end a page with it and make everything encoded so far readable.
+pub(crate) struct OggOpusEncoder<W: Write> {
+    encoder: Encoder,
+    writer: PacketWriter<W>,
+    serial: u32,
+    sample_rate: u32,
+    frame_len: usize,
+    pre_skip: u64,
+    /// Samples waiting for a whole frame.
+    buffer: Vec<f32>,
+    /// The last packet and the samples encoded up to its end.
+    pending: Option<(Vec<u8>, u64)>,
+    encoded_samples: u64,
+    /// Samples of the packets in complete pages.
+    written_frames: Arc<AtomicU64>,
+}
+
+impl<W: Write> OggOpusEncoder<W> {
+    pub(crate) fn new(
+        output: W,
+        sample_rate: u32,
+        written_frames: Arc<AtomicU64>,
+    ) -> Result<Self, String> {
+        // audiopus has its own TryFrom, which the std prelude would shadow.
+        let opus_rate: SampleRate = audiopus::TryFrom::try_from(sample_rate as i32)
+            .map_err(|_| format!("Opus cannot encode at {}Hz", sample_rate))?;
+        let mut encoder = Encoder::new(opus_rate, Channels::Mono, Application::Voip)
+            .map_err(|e| format!("Could not create encoder: {:?}", e))?;
+        encoder
+            .set_bitrate(Bitrate::BitsPerSecond(BITRATE))
+            .and_then(|()| encoder.set_signal(Signal::Voice))
+            .map_err(|e| format!("Could not configure encoder: {:?}", e))?;
+        let lookahead = encoder
+            .lookahead()
+            .map_err(|e| format!("Could not configure encoder: {:?}", e))?;
+
+        let mut opus = Self {
+            encoder,
+            writer: PacketWriter::new(output),
+            serial: rand::random(),
+            sample_rate,
+            frame_len: (sample_rate * FRAME_MS / 1000) as usize,
+            pre_skip: lookahead as u64 * (GRANULE_RATE / sample_rate) as u64,
+            buffer: Vec::new(),
+            pending: None,
+            encoded_samples: 0,
+            written_frames,
+        };
+        opus.write_headers()
+            .map_err(|e| format!("Could not write Opus headers: {:?}", e))?;
+        Ok(opus)
+    }
+
+    fn write_headers(&mut self) -> std::io::Result<()> {
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // version
+        head.push(1); // channels
+        head.extend_from_slice(&(self.pre_skip as u16).to_le_bytes());
+        head.extend_from_slice(&self.sample_rate.to_le_bytes());
+        head.extend_from_slice(&0_i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family: mono or stereo
+        self.writer.write_packet(
+            head.into_boxed_slice(),
+            self.serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )?;
+
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&(VENDOR.len() as u32).to_le_bytes());
+        tags.extend_from_slice(VENDOR.as_bytes());
+        tags.extend_from_slice(&0_u32.to_le_bytes()); // no user comments
+        self.writer.write_packet(
+            tags.into_boxed_slice(),
+            self.serial,
+            PacketWriteEndInfo::EndPage,
+            0,
+        )
+    }
+
+    /// Granule position after `samples` samples of audio.
+    fn granule_position(&self, samples: u64) -> u64 {
+        self.pre_skip + samples * (GRANULE_RATE / self.sample_rate) as u64
+    }
+
+    fn write_pending(&mut self, end_info: PacketWriteEndInfo) -> Result<(), String> {
+        if let Some((packet, samples)) = self.pending.take() {
+            let granule_position = self.granule_position(samples);
+            self.writer
+                .write_packet(
+                    packet.into_boxed_slice(),
+                    self.serial,
+                    end_info,
+                    granule_position,
+                )
+                .map_err(|e| format!("Could not write output file: {:?}", e))?;
+        }
+        Ok(())
+    }
+
+    fn encode_frame(&mut self, frame: &[f32], samples: u64) -> Result<(), String> {
+        let mut packet = vec![0u8; MAX_PACKET_LEN];
+        let len = self
+            .encoder
+            .encode_float(frame, &mut packet)
+            .map_err(|e| format!("Could not encode audio block: {:?}", e))?;
+        packet.truncate(len);
+        self.write_pending(PacketWriteEndInfo::NormalPacket)?;
+        self.encoded_samples += samples;
+        self.pending = Some((packet, self.encoded_samples));
+        Ok(())
+    }
+
+    pub(crate) fn encode(&mut self, samples: &[f32]) -> Result<(), String> {
+        self.buffer.extend_from_slice(samples);
+        while self.buffer.len() >= self.frame_len {
+            let frame: Vec<f32> = self.buffer.drain(..self.frame_len).collect();
+            self.encode_frame(&frame, self.frame_len as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the current page, so that every whole frame encoded so far is
+    /// in the output.
+    pub(crate) fn flush(&mut self) -> Result<(), String> {
+        let samples = self.pending.as_ref().map(|(_, samples)| *samples);
+        self.write_pending(PacketWriteEndInfo::EndPage)?;
+        self.writer
+            .inner_mut()
+            .flush()
+            .map_err(|e| format!("Could not write output file: {:?}", e))?;
+        if let Some(samples) = samples {
+            self.written_frames.store(samples, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Encodes the rest of the audio padded to a whole frame and ends the
+    /// stream. The granule position of the last page trims the padding.
+    pub(crate) fn finish(mut self) -> Result<W, String> {
+        if !self.buffer.is_empty() || self.pending.is_none() {
+            let samples = self.buffer.len() as u64;
+            let mut frame = std::mem::take(&mut self.buffer);
+            frame.resize(self.frame_len, 0.0);
+            self.encode_frame(&frame, samples)?;
+        }
+        let samples = self.encoded_samples;
+        self.write_pending(PacketWriteEndInfo::EndStream)?;
+        self.written_frames.store(samples, Ordering::Release);
+        let mut output = self.writer.into_inner();
+        output
+            .flush()
+            .map_err(|e| format!("Could not write output file: {:?}", e))?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audiopus::coder::Decoder;
+    use ogg::PacketReader;
+    use std::io::Cursor;
+
+    fn tone(sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|n| {
+                (2.0 * std::f32::consts::PI * 440.0 * n as f32 / sample_rate as f32).sin() * 0.5
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_ogg_opus_round_trip() {
+        let sample_rate = 24000;
+        // Not a whole number of frames, and fed in uneven blocks.
+        let input = tone(sample_rate, 24000 + 100);
+        let written_frames = Arc::new(AtomicU64::new(0));
+        let mut encoder =
+            OggOpusEncoder::new(Vec::new(), sample_rate, Arc::clone(&written_frames)).unwrap();
+        for block in input.chunks(1000) {
+            encoder.encode(block).unwrap();
+        }
+        encoder.flush().unwrap();
+        // The frames of the held-back packet are readable after a flush.
+        assert_eq!(written_frames.load(Ordering::Acquire), 24000);
+        let output = encoder.finish().unwrap();
+        assert_eq!(written_frames.load(Ordering::Acquire), 24100);
+
+        let mut reader = PacketReader::new(Cursor::new(output));
+        let head = reader.read_packet().unwrap().unwrap();
+        assert_eq!(&head.data[..8], b"OpusHead");
+        assert_eq!(head.data[9], 1);
+        let pre_skip = u16::from_le_bytes([head.data[10], head.data[11]]) as usize;
+        assert_eq!(
+            u32::from_le_bytes(head.data[12..16].try_into().unwrap()),
+            sample_rate
+        );
+        let tags = reader.read_packet().unwrap().unwrap();
+        assert_eq!(&tags.data[..8], b"OpusTags");
+
+        let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Mono).unwrap();
+        let mut decoded = Vec::new();
+        let mut last_granule_position = 0;
+        while let Some(packet) = reader.read_packet().unwrap() {
+            let mut frame = vec![0.0_f32; 5760];
+            let len = decoder
+                .decode_float(Some(&packet.data), &mut frame, false)
+                .unwrap();
+            decoded.extend_from_slice(&frame[..len]);
+            last_granule_position = packet.absgp_page();
+            if packet.last_in_stream() {
+                break;
+            }
+        }
+
+        // The end of the stream trims the padding of the last frame.
+        assert_eq!(last_granule_position as usize, pre_skip + 24100 * 2);
+        let decoded = &decoded[pre_skip..last_granule_position as usize];
+        assert_eq!(decoded.len(), 24100 * 2);
+        // Compare the level of the middle of the tone with the input.
+        let rms = |samples: &[f32]| {
+            (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+        };
+        let expected = rms(&input[6000..18000]);
+        let actual = rms(&decoded[12000..36000]);
+        assert!(
+            (actual - expected).abs() < 0.05,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_rejects_rates_opus_cannot_encode() {
+        let result = OggOpusEncoder::new(Vec::new(), 22050, Arc::new(AtomicU64::new(0)));
+        assert!(result.is_err());
+    }
+}
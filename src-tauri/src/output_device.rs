@@ -0,0 +1,119 @@
+use rodio::cpal::{
+    self,
+    traits::{DeviceTrait, HostTrait},
+};
+use rodio::{mixer::Mixer, OutputStream, OutputStreamBuilder};
+use serde::Serialize;
+#[cfg(test)]
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Where the tracks are played: every track is a sink on the mixer of the
+/// output.
+pub(crate) trait AudioOutput: Send {
+    fn mixer(&self) -> &Mixer;
+}
+
+impl AudioOutput for OutputStream {
+    fn mixer(&self) -> &Mixer {
+        OutputStream::mixer(self)
+    }
+}
+
+/// An output without a device, for tests. Nothing is played until `advance`
+/// pulls the mixed samples, which moves its virtual clock.
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct NullOutput {
+    mixer: Mixer,
+    source: Arc<Mutex<rodio::mixer::MixerSource>>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+#[cfg(test)]
+impl NullOutput {
+    pub(crate) fn new(channels: u16, sample_rate: u32) -> Self {
+        let (mixer, source) = rodio::mixer::mixer(channels, sample_rate);
+        Self {
+            mixer,
+            source: Arc::new(Mutex::new(source)),
+            channels,
+            sample_rate,
+        }
+    }
+
+    /// Plays `duration` of audio.
+    pub(crate) fn advance(&self, duration: Duration) {
+        let frames = duration.as_millis() as u64 * self.sample_rate as u64 / 1000;
+        let mut source = self.source.lock().unwrap();
+        for _ in 0..frames * self.channels as u64 {
+            source.next();
+        }
+    }
+}
+
+#[cfg(test)]
+impl AudioOutput for NullOutput {
+    fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputDevice {
+    /// cpal has no stable device identifiers, so the name is used.
+    id: String,
+    name: String,
+    is_default: bool,
+}
+
+pub(crate) fn output_devices() -> Result<Vec<OutputDevice>, String> {
+    let host = cpal::default_host();
+    let default_name = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+    let devices = host
+        .output_devices()
+        .map_err(|e| format!("Failed to list output devices: {}", e))?;
+    Ok(devices
+        .filter_map(|device| device.name().ok())
+        .map(|name| OutputDevice {
+            id: name.clone(),
+            is_default: default_name.as_ref() == Some(&name),
+            name,
+        })
+        .collect())
+}
+
+fn find_output_device(device_id: &str) -> Result<cpal::Device, String> {
+    cpal::default_host()
+        .output_devices()
+        .map_err(|e| format!("Failed to list output devices: {}", e))?
+        .find(|device| device.name().is_ok_and(|name| name == device_id))
+        .ok_or_else(|| format!("Output device not found: {}", device_id))
+}
+
+/// Opens an output stream on `device_id`, or on the system default device if
+/// `None`. `on_error` is called on the audio thread when the stream fails,
+/// e.g. because the device has been unplugged.
+pub(crate) fn open_output_stream<F>(
+    device_id: Option<&str>,
+    on_error: F,
+) -> Result<OutputStream, String>
+where
+    F: FnMut(cpal::StreamError) + Clone + Send + 'static,
+{
+    let builder = match device_id {
+        Some(device_id) => OutputStreamBuilder::from_device(find_output_device(device_id)?),
+        None => OutputStreamBuilder::from_default_device(),
+    }
+    .map_err(|e| format!("Failed to open audio output stream: {}", e))?;
+    builder
+        .with_error_callback(on_error)
+        .open_stream()
+        .map_err(|e| format!("Failed to open audio output stream: {}", e))
+}
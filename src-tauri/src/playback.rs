@@ -0,0 +1,675 @@
+// cSpell:words WSOLA
+use log::warn;
+use rodio::{source::SeekError, Source};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    f32::consts::PI,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+pub(crate) const MIN_PLAYBACK_RATE: f32 = 0.5;
+pub(crate) const MAX_PLAYBACK_RATE: f32 = 1.5;
+
+/// Length of the WSOLA analysis/synthesis window.
+const WINDOW_MS: u32 = 40;
+/// How far the analysis window may be shifted to find the best overlap.
+const SEARCH_MS: u32 = 10;
+/// Only every n-th frame is compared when searching for the best overlap.
+const CORRELATION_STEP: usize = 4;
+
+/// A part of the media that is played `repeat_count` times with `gap_ms` of
+/// silence between the repetitions.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PlaybackRange {
+    pub(crate) start_ms: u32,
+    pub(crate) end_ms: u32,
+    pub(crate) repeat_count: u32,
+    pub(crate) gap_ms: u32,
+}
+
+/// Why the source stopped producing audio.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum HoldReason {
+    RangeEnd = 1,
+    MediaEnd = 2,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct RangeLoopCompleted {
+    start_ms: u32,
+    end_ms: u32,
+    /// 1-based number of the repetition that has just finished.
+    repetition: u32,
+    repeat_count: u32,
+}
+
+/// State shared between the audio thread and the commands controlling it.
+pub(crate) struct PlaybackControl {
+    rate: AtomicU32,
+    /// Loudness normalization gain applied to the output.
+    gain: AtomicU32,
+    position_ms: AtomicU64,
+    range: Mutex<Option<PlaybackRange>>,
+    /// Incremented whenever `range` is replaced, so the audio thread only has
+    /// to take the lock when there is something new.
+    range_generation: AtomicU64,
+    range_events: Mutex<Vec<RangeLoopCompleted>>,
+    /// Set by the source when the end of the media or the last repetition of
+    /// a range has been played. The owner of the sink pauses it and clears the
+    /// flag when playback is resumed.
+    hold: AtomicU8,
+    /// The media file is still being written, so its end is only the end of
+    /// what is available so far.
+    media_growing: AtomicBool,
+}
+
+impl Default for PlaybackControl {
+    fn default() -> Self {
+        Self {
+            rate: AtomicU32::new(1.0_f32.to_bits()),
+            gain: AtomicU32::new(1.0_f32.to_bits()),
+            position_ms: AtomicU64::new(0),
+            range: Mutex::new(None),
+            range_generation: AtomicU64::new(0),
+            range_events: Mutex::new(Vec::new()),
+            hold: AtomicU8::new(0),
+            media_growing: AtomicBool::new(false),
+        }
+    }
+}
+
+impl PlaybackControl {
+    pub(crate) fn rate(&self) -> f32 {
+        f32::from_bits(self.rate.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_rate(&self, rate: f32) {
+        let rate = rate.clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE);
+        self.rate.store(rate.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn gain(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_gain(&self, gain: f32) {
+        self.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_media_growing(&self) -> bool {
+        self.media_growing.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_media_growing(&self, growing: bool) {
+        self.media_growing.store(growing, Ordering::Release);
+    }
+
+    /// Position in the media (not wall-clock time) of the audio that is
+    /// currently being handed to the output.
+    pub(crate) fn position_ms(&self) -> u64 {
+        self.position_ms.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_position_ms(&self, position_ms: u64) {
+        self.position_ms.store(position_ms, Ordering::Relaxed);
+    }
+
+    /// Starts looping `range` from its beginning, or stops looping if `None`.
+    pub(crate) fn set_range(&self, range: Option<PlaybackRange>) {
+        *self.range.lock().unwrap() = range;
+        self.range_generation.fetch_add(1, Ordering::Release);
+    }
+
+    fn range_generation(&self) -> u64 {
+        self.range_generation.load(Ordering::Acquire)
+    }
+
+    fn push_range_event(&self, event: RangeLoopCompleted) {
+        self.range_events.lock().unwrap().push(event);
+    }
+
+    pub(crate) fn take_range_events(&self) -> Vec<RangeLoopCompleted> {
+        std::mem::take(&mut *self.range_events.lock().unwrap())
+    }
+
+    pub(crate) fn hold_reason(&self) -> Option<HoldReason> {
+        match self.hold.load(Ordering::Acquire) {
+            1 => Some(HoldReason::RangeEnd),
+            2 => Some(HoldReason::MediaEnd),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_holding(&self) -> bool {
+        self.hold_reason().is_some()
+    }
+
+    fn start_hold(&self, reason: HoldReason) {
+        self.hold.store(reason as u8, Ordering::Release);
+    }
+
+    pub(crate) fn release_hold(&self) {
+        self.hold.store(0, Ordering::Release);
+    }
+}
+
+/// Loop state of the range that is currently being played.
+struct ActiveRange {
+    range: PlaybackRange,
+    start_frame: u64,
+    end_frame: u64,
+    gap_frames: u64,
+    /// Silent frames still to be inserted before the next repetition.
+    gap_left: u64,
+    completed: u32,
+}
+
+/// Source used for episode playback.
+///
+/// It time-stretches the decoded audio with WSOLA (waveform similarity
+/// overlap-add) so that the playback rate can change without changing the
+/// pitch, and it keeps track of the media position of the audio it outputs.
+///
+/// The source never finishes on its own: at the end of the media it outputs
+/// silence until it is paused, so the sink stays usable for seeking back.
+pub(crate) struct PlaybackSource<S: Source> {
+    inner: S,
+    control: Arc<PlaybackControl>,
+    channels: usize,
+    sample_rate: u32,
+    /// Normalization gain, picked up from the control once per hop.
+    gain: f32,
+
+    /// Media frame index of the next frame read from `inner`.
+    next_media_frame: u64,
+    inner_ended: bool,
+
+    range: Option<ActiveRange>,
+    seen_range_generation: u64,
+    /// No more input is read because the last repetition has been played.
+    at_range_end: bool,
+    /// Silence is output until the owner of the sink has paused it.
+    holding: bool,
+
+    window_len: usize,
+    hop_len: usize,
+    search_len: usize,
+    window: Vec<f32>,
+
+    /// Interleaved input that has not been consumed yet.
+    input: Vec<f32>,
+    /// Media frame index of every frame in `input`.
+    input_media_frames: Vec<u64>,
+    /// Nominal position of the next analysis window in `input` (in frames).
+    analysis_pos: f64,
+    /// Position in `input` that naturally continues the previous window.
+    natural_pos: usize,
+    is_first_window: bool,
+
+    /// Second half of the previous window, added to the next one.
+    overlap: Vec<f32>,
+    output: VecDeque<f32>,
+}
+
+impl<S: Source> PlaybackSource<S> {
+    pub(crate) fn new(inner: S, control: Arc<PlaybackControl>) -> Self {
+        let channels = inner.channels().max(1) as usize;
+        let sample_rate = inner.sample_rate();
+        let window_len = ((sample_rate * WINDOW_MS / 1000) as usize / 2).max(1) * 2;
+        let hop_len = window_len / 2;
+        let search_len = (sample_rate * SEARCH_MS / 1000) as usize;
+        // Periodic Hann window: overlapping halves sum to exactly one.
+        let window = (0..window_len)
+            .map(|n| 0.5 - 0.5 * (2.0 * PI * n as f32 / window_len as f32).cos())
+            .collect();
+        control.set_position_ms(0);
+        control.release_hold();
+        // Ranges requested before this source was created do not apply to it.
+        let seen_range_generation = control.range_generation();
+
+        Self {
+            inner,
+            gain: control.gain(),
+            control,
+            channels,
+            sample_rate,
+            next_media_frame: 0,
+            inner_ended: false,
+            range: None,
+            seen_range_generation,
+            at_range_end: false,
+            holding: false,
+            window_len,
+            hop_len,
+            search_len,
+            window,
+            input: Vec::new(),
+            input_media_frames: Vec::new(),
+            analysis_pos: 0.0,
+            natural_pos: 0,
+            is_first_window: true,
+            overlap: vec![0.0; hop_len * channels],
+            output: VecDeque::new(),
+        }
+    }
+
+    fn frames_to_ms(&self, frames: u64) -> u64 {
+        frames * 1000 / self.sample_rate.max(1) as u64
+    }
+
+    fn ms_to_frames(&self, ms: u32) -> u64 {
+        ms as u64 * self.sample_rate as u64 / 1000
+    }
+
+    fn buffered_frames(&self) -> usize {
+        self.input_media_frames.len()
+    }
+
+    fn read_inner_frame(&mut self) -> bool {
+        for _ in 0..self.channels {
+            match self.inner.next() {
+                Some(sample) => self.input.push(sample),
+                None => {
+                    // Drop an incomplete trailing frame.
+                    let complete = self.input_media_frames.len() * self.channels;
+                    self.input.truncate(complete);
+                    return false;
+                }
+            }
+        }
+        self.input_media_frames.push(self.next_media_frame);
+        self.next_media_frame += 1;
+        true
+    }
+
+    /// Appends the next input frame. Loops are resolved here, before the
+    /// time-stretch, so that they are sample-accurate at any rate.
+    fn read_frame(&mut self) -> bool {
+        loop {
+            if self.inner_ended || self.at_range_end {
+                return false;
+            }
+            if let Some(active) = self.range.as_mut() {
+                if active.gap_left > 0 {
+                    active.gap_left -= 1;
+                    let media_frame = active.end_frame;
+                    let rewind = active.gap_left == 0;
+                    self.input.resize(self.input.len() + self.channels, 0.0);
+                    self.input_media_frames.push(media_frame);
+                    if rewind {
+                        self.rewind_to_range_start();
+                    }
+                    return true;
+                }
+                if self.next_media_frame >= active.end_frame {
+                    self.complete_repetition();
+                    continue;
+                }
+            }
+            if self.read_inner_frame() {
+                return true;
+            }
+            if self.range.is_some() {
+                // The range extends past the end of the media.
+                self.complete_repetition();
+            } else {
+                self.inner_ended = true;
+            }
+        }
+    }
+
+    fn complete_repetition(&mut self) {
+        let Some(active) = self.range.as_mut() else {
+            return;
+        };
+        active.completed += 1;
+        self.control.push_range_event(RangeLoopCompleted {
+            start_ms: active.range.start_ms,
+            end_ms: active.range.end_ms,
+            repetition: active.completed,
+            repeat_count: active.range.repeat_count,
+        });
+        if active.completed >= active.range.repeat_count {
+            self.range = None;
+            self.at_range_end = true;
+        } else if active.gap_frames > 0 {
+            active.gap_left = active.gap_frames;
+        } else {
+            self.rewind_to_range_start();
+        }
+    }
+
+    fn rewind_to_range_start(&mut self) {
+        let Some(active) = self.range.as_ref() else {
+            return;
+        };
+        let start_frame = active.start_frame;
+        match self
+            .inner
+            .try_seek(Duration::from_millis(active.range.start_ms as u64))
+        {
+            Ok(()) => self.next_media_frame = start_frame,
+            Err(e) => {
+                warn!("Failed to rewind to the start of the range: {}", e);
+                self.range = None;
+                self.at_range_end = true;
+            }
+        }
+    }
+
+    /// Picks up a range set through `PlaybackControl::set_range`.
+    fn apply_range_request(&mut self) {
+        let generation = self.control.range_generation();
+        if generation == self.seen_range_generation {
+            return;
+        }
+        self.seen_range_generation = generation;
+
+        let Some(range) = *self.control.range.lock().unwrap() else {
+            self.range = None;
+            return;
+        };
+        let start_frame = self.ms_to_frames(range.start_ms);
+        match self
+            .inner
+            .try_seek(Duration::from_millis(range.start_ms as u64))
+        {
+            Ok(()) => {
+                self.reset(start_frame);
+                self.range = Some(ActiveRange {
+                    range,
+                    start_frame,
+                    end_frame: self.ms_to_frames(range.end_ms),
+                    gap_frames: self.ms_to_frames(range.gap_ms),
+                    gap_left: 0,
+                    completed: 0,
+                });
+            }
+            Err(e) => warn!("Failed to seek to the start of the range: {}", e),
+        }
+    }
+
+    fn fill_input(&mut self, frames: usize) {
+        while self.buffered_frames() < frames && self.read_frame() {}
+    }
+
+    fn mono_frame(&self, frame: usize) -> f32 {
+        let start = frame * self.channels;
+        self.input
+            .get(start..start + self.channels)
+            .map(|samples| samples.iter().sum())
+            .unwrap_or(0.0)
+    }
+
+    /// Finds the window start near `nominal` whose beginning best matches the
+    /// natural continuation of the previous window.
+    fn find_best_offset(&self, nominal: usize) -> usize {
+        let lowest = nominal.saturating_sub(self.search_len);
+        let highest = nominal + self.search_len;
+        let mut best = nominal;
+        let mut best_score = f32::MIN;
+        for candidate in lowest..=highest {
+            if candidate + self.window_len > self.buffered_frames() {
+                break;
+            }
+            let mut correlation = 0.0;
+            let mut energy = 0.0;
+            for n in (0..self.hop_len).step_by(CORRELATION_STEP) {
+                let a = self.mono_frame(candidate + n);
+                let b = self.mono_frame(self.natural_pos + n);
+                correlation += a * b;
+                energy += a * a;
+            }
+            let score = correlation / energy.sqrt().max(1e-6);
+            if score > best_score {
+                best_score = score;
+                best = candidate;
+            }
+        }
+        best
+    }
+
+    /// Produces the next `hop_len` output frames. Returns false once all
+    /// input has been played.
+    fn process_hop(&mut self) -> bool {
+        let rate = self.control.rate();
+        self.gain = self.control.gain();
+        let unity = (rate - 1.0).abs() < 1e-3;
+        let nominal = self.analysis_pos.round() as usize;
+
+        self.fill_input(nominal.max(self.natural_pos) + self.search_len + self.window_len);
+        let start = if self.is_first_window {
+            nominal
+        } else if unity {
+            self.natural_pos
+        } else {
+            self.find_best_offset(nominal)
+        };
+
+        if start >= self.buffered_frames() {
+            if self.overlap.iter().all(|&s| s == 0.0) {
+                return false;
+            }
+            // Flush the tail of the last window.
+            self.output.extend(self.overlap.drain(..));
+            self.overlap.resize(self.hop_len * self.channels, 0.0);
+            return true;
+        }
+
+        if let Some(&media_frame) = self.input_media_frames.get(start) {
+            self.control.set_position_ms(self.frames_to_ms(media_frame));
+        }
+
+        for n in 0..self.window_len {
+            for c in 0..self.channels {
+                let sample = self
+                    .input
+                    .get((start + n) * self.channels + c)
+                    .copied()
+                    .unwrap_or(0.0)
+                    * self.window[n];
+                if n < self.hop_len {
+                    self.output
+                        .push_back(self.overlap[n * self.channels + c] + sample);
+                } else {
+                    self.overlap[(n - self.hop_len) * self.channels + c] = sample;
+                }
+            }
+        }
+
+        self.is_first_window = false;
+        self.natural_pos = start + self.hop_len;
+        self.analysis_pos = if unity {
+            self.natural_pos as f64
+        } else {
+            self.analysis_pos + self.hop_len as f64 * rate as f64
+        };
+
+        // Forget input that can no longer be part of a window.
+        let consumed = self
+            .natural_pos
+            .min((self.analysis_pos as usize).saturating_sub(self.search_len));
+        if consumed > self.window_len {
+            self.input.drain(..consumed * self.channels);
+            self.input_media_frames.drain(..consumed);
+            self.natural_pos -= consumed;
+            self.analysis_pos -= consumed as f64;
+        }
+        true
+    }
+
+    fn reset(&mut self, media_frame: u64) {
+        self.next_media_frame = media_frame;
+        self.inner_ended = false;
+        self.at_range_end = false;
+        if self.holding {
+            self.holding = false;
+            self.control.release_hold();
+        }
+        self.input.clear();
+        self.input_media_frames.clear();
+        self.analysis_pos = 0.0;
+        self.natural_pos = 0;
+        self.is_first_window = true;
+        self.overlap.fill(0.0);
+        self.output.clear();
+        self.control.set_position_ms(self.frames_to_ms(media_frame));
+    }
+}
+
+impl<S: Source> Iterator for PlaybackSource<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        while self.output.is_empty() {
+            self.apply_range_request();
+            if self.holding {
+                if self.control.is_holding() {
+                    return Some(0.0);
+                }
+                // Paused and resumed: continue where the input stopped.
+                self.reset(self.next_media_frame);
+            }
+            if !self.process_hop() {
+                self.holding = true;
+                self.control.start_hold(if self.at_range_end {
+                    HoldReason::RangeEnd
+                } else {
+                    HoldReason::MediaEnd
+                });
+            }
+        }
+        self.output.pop_front().map(|sample| sample * self.gain)
+    }
+}
+
+impl<S: Source> Source for PlaybackSource<S> {
+    fn current_span_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        // The length in wall-clock time depends on the playback rate.
+        None
+    }
+
+    fn try_seek(&mut self, pos: Duration) -> Result<(), SeekError> {
+        self.inner.try_seek(pos)?;
+        let media_frame = (pos.as_secs_f64() * self.sample_rate as f64).round() as u64;
+        self.reset(media_frame);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn sine(sample_rate: u32, seconds: f32) -> SamplesBuffer {
+        let samples: Vec<f32> = (0..(sample_rate as f32 * seconds) as usize)
+            .map(|n| (2.0 * PI * 440.0 * n as f32 / sample_rate as f32).sin() * 0.5)
+            .collect();
+        SamplesBuffer::new(1, sample_rate, samples)
+    }
+
+    #[test]
+    fn test_unity_rate_reproduces_input() {
+        let input = sine(8000, 1.0);
+        let expected: Vec<f32> = input.clone().collect();
+        let source = PlaybackSource::new(input, Arc::new(PlaybackControl::default()));
+
+        let output: Vec<f32> = source.take(expected.len()).collect();
+
+        // The first half window fades in; everything after it is unchanged.
+        let hop_len = 160;
+        for (a, b) in output[hop_len..].iter().zip(&expected[hop_len..]) {
+            assert!((a - b).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_rate_changes_length_but_reports_media_position() {
+        let control = Arc::new(PlaybackControl::default());
+        control.set_rate(0.5);
+        let source = PlaybackSource::new(sine(8000, 1.0), Arc::clone(&control));
+
+        let output_len = source.take_while(|_| !control.is_holding()).count();
+
+        // Twice as long at half speed (within one window).
+        assert!((output_len as i64 - 16000).abs() < 640);
+        assert!(control.position_ms() >= 950);
+    }
+
+    #[test]
+    fn test_range_loops_with_gap_and_holds_at_end() {
+        // Every sample holds its own frame index.
+        let samples: Vec<f32> = (0..1000).map(|n| n as f32).collect();
+        let control = Arc::new(PlaybackControl::default());
+        let mut source =
+            PlaybackSource::new(SamplesBuffer::new(1, 1000, samples), Arc::clone(&control));
+        control.set_range(Some(PlaybackRange {
+            start_ms: 100,
+            end_ms: 300,
+            repeat_count: 2,
+            gap_ms: 50,
+        }));
+
+        let output: Vec<f32> = source.by_ref().take(500).collect();
+
+        let mut expected: Vec<f32> = (100..300).map(|n| n as f32).collect();
+        expected.extend(std::iter::repeat_n(0.0, 50));
+        expected.extend((100..300).map(|n| n as f32));
+        expected.extend(std::iter::repeat_n(0.0, 50));
+        // The first half window fades in.
+        let hop_len = 20;
+        for (a, b) in output[hop_len..].iter().zip(&expected[hop_len..]) {
+            assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+        }
+        assert_eq!(control.hold_reason(), Some(HoldReason::RangeEnd));
+        let events = control.take_range_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].repetition, 2);
+
+        // After the owner pauses and releases the hold, playback continues
+        // after the range.
+        control.release_hold();
+        let resumed: Vec<f32> = source.take(100).collect();
+        assert!((resumed[50] - 350.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_seek_backward_after_end_of_media() {
+        let samples: Vec<f32> = (0..1000).map(|n| n as f32).collect();
+        let control = Arc::new(PlaybackControl::default());
+        let mut source =
+            PlaybackSource::new(SamplesBuffer::new(1, 1000, samples), Arc::clone(&control));
+
+        let played = source
+            .by_ref()
+            .take_while(|_| !control.is_holding())
+            .count();
+        assert_eq!(played, 1000);
+
+        source.try_seek(Duration::from_millis(200)).unwrap();
+
+        assert!(!control.is_holding());
+        assert_eq!(control.position_ms(), 200);
+        let output: Vec<f32> = source.take(100).collect();
+        assert!((output[50] - 250.0).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,511 @@
+// cSpell:words mfcc mfccs
+use log::info;
+use rodio::Source;
+use serde::{Deserialize, Serialize};
+use std::{f32::consts::PI, path::Path};
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+
+use crate::audio::{downmix_to_mono, open_audio_decoder};
+use crate::clip::extract_clip;
+use crate::spectral::{stft_magnitudes_db, yin_pitch_contour, PitchPoint, HOP_MS};
+
+const MEL_FILTER_COUNT: usize = 26;
+/// Cepstral coefficients compared, after dropping c0 (the overall level).
+const MFCC_COUNT: usize = 12;
+const MIN_MEL_FREQUENCY_HZ: f32 = 60.0;
+const MAX_MEL_FREQUENCY_HZ: f32 = 8000.0;
+/// Frames this much quieter than the loudest frame are taken as silence when
+/// trimming the start and end of a take.
+const SPEECH_LEVEL_RANGE_DB: f32 = 35.0;
+const MIN_SPEECH_LEVEL_DB: f32 = -55.0;
+/// The alignment is quadratic in the length of the audio.
+const MAX_COMPARED_MS: u32 = 30_000;
+/// Fewer frames voiced in both takes do not give a meaningful correlation.
+const MIN_VOICED_PAIRS: usize = 10;
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LineRange {
+    pub(crate) start_ms: u32,
+    pub(crate) end_ms: u32,
+}
+
+/// A frame of the native line and the frame of the recording aligned to it,
+/// relative to the start of the line and of the recording.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AlignmentPoint {
+    native_ms: u32,
+    recording_ms: u32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PronunciationComparison {
+    /// Mean distance between the MFCCs of the aligned frames; 0 for
+    /// identical audio.
+    mfcc_distance: f32,
+    /// Correlation (-1 to 1) of the aligned pitch contours in semitones from
+    /// each speaker's median pitch, or `None` if too few frames are voiced.
+    pitch_similarity: Option<f32>,
+    /// Lengths of the speech, without the silence before and after it.
+    native_duration_ms: u32,
+    recording_duration_ms: u32,
+    /// `recording_duration_ms / native_duration_ms`.
+    duration_ratio: f32,
+    /// Mean distance of the alignment from a constant tempo: how unevenly the
+    /// recording is faster or slower than the native line.
+    timing_deviation_ms: f32,
+    alignment: Vec<AlignmentPoint>,
+}
+
+struct Take {
+    samples: Vec<f32>,
+    sample_rate: u32,
+}
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+/// Triangular filters evenly spaced on the mel scale, as weights per bin.
+fn mel_filterbank(bins: usize, bin_hz: f32, sample_rate: u32) -> Vec<Vec<f32>> {
+    let max_hz = MAX_MEL_FREQUENCY_HZ.min(sample_rate as f32 / 2.0);
+    let min_mel = hz_to_mel(MIN_MEL_FREQUENCY_HZ);
+    let mel_step = (hz_to_mel(max_hz) - min_mel) / (MEL_FILTER_COUNT + 1) as f32;
+    let edge_hz = |i: usize| mel_to_hz(min_mel + i as f32 * mel_step);
+    (0..MEL_FILTER_COUNT)
+        .map(|m| {
+            let (low, center, high) = (edge_hz(m), edge_hz(m + 1), edge_hz(m + 2));
+            (0..bins)
+                .map(|k| {
+                    let hz = k as f32 * bin_hz;
+                    if hz <= low || hz >= high {
+                        0.0
+                    } else if hz <= center {
+                        (hz - low) / (center - low)
+                    } else {
+                        (high - hz) / (high - center)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// MFCCs of every frame, `HOP_MS` apart. The mel filters are placed in Hz, so
+/// takes of different sample rates can be compared.
+fn mfccs(samples: &[f32], sample_rate: u32) -> Vec<Vec<f32>> {
+    let (spectrogram, bin_hz) = stft_magnitudes_db(samples, sample_rate);
+    let Some(bins) = spectrogram.first().map(Vec::len) else {
+        return Vec::new();
+    };
+    let filters = mel_filterbank(bins, bin_hz, sample_rate);
+    spectrogram
+        .iter()
+        .map(|row| {
+            let log_energies: Vec<f32> = filters
+                .iter()
+                .map(|filter| {
+                    let energy: f32 = filter
+                        .iter()
+                        .zip(row)
+                        .map(|(weight, db)| weight * 10f32.powf(db / 10.0))
+                        .sum();
+                    energy.max(1e-10).ln()
+                })
+                .collect();
+            // DCT-II, without c0.
+            (1..=MFCC_COUNT)
+                .map(|n| {
+                    log_energies
+                        .iter()
+                        .enumerate()
+                        .map(|(m, e)| {
+                            e * (PI * n as f32 * (m as f32 + 0.5) / MEL_FILTER_COUNT as f32).cos()
+                        })
+                        .sum()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Subtracts the mean of every coefficient, which removes the coloring of
+/// the microphone and the room.
+fn normalize_cepstral_mean(frames: &mut [Vec<f32>]) {
+    if frames.is_empty() {
+        return;
+    }
+    for n in 0..MFCC_COUNT {
+        let mean = frames.iter().map(|frame| frame[n]).sum::<f32>() / frames.len() as f32;
+        frames.iter_mut().for_each(|frame| frame[n] -= mean);
+    }
+}
+
+/// The first and last frame (exclusive) of the speech in `samples`.
+fn speech_frames(samples: &[f32], sample_rate: u32) -> Option<(usize, usize)> {
+    let hop = (sample_rate * HOP_MS / 1000) as usize;
+    let levels_db: Vec<f32> = samples
+        .chunks(hop.max(1))
+        .map(|frame| {
+            let mean_square = frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32;
+            10.0 * mean_square.max(1e-10).log10()
+        })
+        .collect();
+    let loudest = levels_db.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let threshold = (loudest - SPEECH_LEVEL_RANGE_DB).max(MIN_SPEECH_LEVEL_DB);
+    let first = levels_db.iter().position(|&db| db > threshold)?;
+    let last = levels_db.iter().rposition(|&db| db > threshold)?;
+    Some((first, last + 1))
+}
+
+fn euclidean_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// Dynamic time warping of two frame sequences. Returns the mean distance
+/// along the best path and the path from the first frames to the last ones.
+fn dtw(a: &[Vec<f32>], b: &[Vec<f32>]) -> (f32, Vec<(usize, usize)>) {
+    let (n, m) = (a.len(), b.len());
+    let width = m + 1;
+    let mut cost = vec![f32::INFINITY; (n + 1) * width];
+    cost[0] = 0.0;
+    for i in 1..=n {
+        for j in 1..=m {
+            let best = cost[(i - 1) * width + j - 1]
+                .min(cost[(i - 1) * width + j])
+                .min(cost[i * width + j - 1]);
+            cost[i * width + j] = euclidean_distance(&a[i - 1], &b[j - 1]) + best;
+        }
+    }
+
+    let mut path = vec![(n - 1, m - 1)];
+    let (mut i, mut j) = (n, m);
+    while i > 1 || j > 1 {
+        let diagonal = cost[(i - 1) * width + j - 1];
+        let up = cost[(i - 1) * width + j];
+        let left = cost[i * width + j - 1];
+        if diagonal <= up && diagonal <= left {
+            i -= 1;
+            j -= 1;
+        } else if up <= left {
+            i -= 1;
+        } else {
+            j -= 1;
+        }
+        path.push((i - 1, j - 1));
+    }
+    path.reverse();
+    (cost[n * width + m] / path.len() as f32, path)
+}
+
+/// Pitch in semitones from the median pitch of the take, so that speakers of
+/// different pitch ranges can be compared.
+fn relative_semitones(contour: &[PitchPoint]) -> Vec<Option<f32>> {
+    let mut voiced: Vec<f32> = contour.iter().filter_map(|p| p.f0_hz).collect();
+    if voiced.is_empty() {
+        return vec![None; contour.len()];
+    }
+    voiced.sort_by(f32::total_cmp);
+    let median = voiced[voiced.len() / 2];
+    contour
+        .iter()
+        .map(|p| p.f0_hz.map(|f0| 12.0 * (f0 / median).log2()))
+        .collect()
+}
+
+fn pearson_correlation(pairs: &[(f32, f32)]) -> Option<f32> {
+    let count = pairs.len() as f32;
+    let mean_x = pairs.iter().map(|(x, _)| x).sum::<f32>() / count;
+    let mean_y = pairs.iter().map(|(_, y)| y).sum::<f32>() / count;
+    let (mut covariance, mut variance_x, mut variance_y) = (0.0, 0.0, 0.0);
+    for (x, y) in pairs {
+        covariance += (x - mean_x) * (y - mean_y);
+        variance_x += (x - mean_x) * (x - mean_x);
+        variance_y += (y - mean_y) * (y - mean_y);
+    }
+    let denominator = (variance_x * variance_y).sqrt();
+    (denominator > 0.0).then(|| covariance / denominator)
+}
+
+fn compare_takes(native: &Take, recording: &Take) -> Result<PronunciationComparison, String> {
+    let (native_first, native_last) = speech_frames(&native.samples, native.sample_rate)
+        .ok_or("No speech found in the native line".to_string())?;
+    let (recording_first, recording_last) =
+        speech_frames(&recording.samples, recording.sample_rate)
+            .ok_or("No speech found in the recording".to_string())?;
+
+    let mut native_mfccs = mfccs(&native.samples, native.sample_rate);
+    let mut recording_mfccs = mfccs(&recording.samples, recording.sample_rate);
+    let native_mfccs = &mut native_mfccs[native_first..native_last];
+    let recording_mfccs = &mut recording_mfccs[recording_first..recording_last];
+    normalize_cepstral_mean(native_mfccs);
+    normalize_cepstral_mean(recording_mfccs);
+    let (mfcc_distance, path) = dtw(native_mfccs, recording_mfccs);
+
+    let native_pitch = relative_semitones(
+        &yin_pitch_contour(&native.samples, native.sample_rate)[native_first..native_last],
+    );
+    let recording_pitch = relative_semitones(
+        &yin_pitch_contour(&recording.samples, recording.sample_rate)
+            [recording_first..recording_last],
+    );
+    let voiced_pairs: Vec<(f32, f32)> = path
+        .iter()
+        .filter_map(|&(i, j)| native_pitch[i].zip(recording_pitch[j]))
+        .collect();
+    let pitch_similarity = if voiced_pairs.len() >= MIN_VOICED_PAIRS {
+        pearson_correlation(&voiced_pairs)
+    } else {
+        None
+    };
+
+    let native_frames = native_last - native_first;
+    let recording_frames = recording_last - recording_first;
+    let duration_ratio = recording_frames as f32 / native_frames as f32;
+    let mut alignment = Vec::with_capacity(native_frames);
+    let mut deviation_sum = 0.0;
+    for &(i, j) in &path {
+        // The first frame of the recording aligned to each native frame.
+        if alignment.len() > i {
+            continue;
+        }
+        deviation_sum += (j as f32 - i as f32 * duration_ratio).abs();
+        alignment.push(AlignmentPoint {
+            native_ms: ((native_first + i) as u32) * HOP_MS,
+            recording_ms: ((recording_first + j) as u32) * HOP_MS,
+        });
+    }
+
+    Ok(PronunciationComparison {
+        mfcc_distance,
+        pitch_similarity,
+        native_duration_ms: native_frames as u32 * HOP_MS,
+        recording_duration_ms: recording_frames as u32 * HOP_MS,
+        duration_ratio,
+        timing_deviation_ms: deviation_sum / native_frames as f32 * HOP_MS as f32,
+        alignment,
+    })
+}
+
+fn check_length(take: &Take, name: &str) -> Result<(), String> {
+    let duration_ms = take.samples.len() as u64 * 1000 / take.sample_rate.max(1) as u64;
+    if duration_ms > MAX_COMPARED_MS as u64 {
+        return Err(format!(
+            "The {} is too long to compare: {}ms (max {}ms)",
+            name, duration_ms, MAX_COMPARED_MS
+        ));
+    }
+    Ok(())
+}
+
+/// Compares `line` of the episode audio with a recording of the learner.
+pub(crate) fn compare_pronunciation_files(
+    episode_audio: &Path,
+    line: LineRange,
+    recording: &Path,
+) -> Result<PronunciationComparison, String> {
+    if line.end_ms.saturating_sub(line.start_ms) > MAX_COMPARED_MS {
+        return Err(format!(
+            "The line is too long to compare: {}ms (max {}ms)",
+            line.end_ms - line.start_ms,
+            MAX_COMPARED_MS
+        ));
+    }
+    let clip = extract_clip(episode_audio, line.start_ms, line.end_ms, 0)?;
+    let native = Take {
+        samples: downmix_to_mono(clip.samples.into_iter(), clip.channels).collect(),
+        sample_rate: clip.sample_rate,
+    };
+
+    let decoder = open_audio_decoder(recording)?;
+    let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+    let recording = Take {
+        samples: downmix_to_mono(decoder, channels).collect(),
+        sample_rate,
+    };
+    check_length(&recording, "recording")?;
+
+    compare_takes(&native, &recording)
+}
+
+#[tauri::command]
+pub async fn compare_pronunciation(
+    app_handle: AppHandle,
+    episode_audio: String,
+    line_range: LineRange,
+    recording: String,
+) -> Result<PronunciationComparison, String> {
+    info!(
+        "compare_pronunciation: {} {}-{} with {}",
+        episode_audio, line_range.start_ms, line_range.end_ms, recording
+    );
+    let resolve = |path: &str| {
+        app_handle
+            .path()
+            .resolve(path, BaseDirectory::AppLocalData)
+            .map_err(|e| format!("Failed to resolve path '{}': {}", path, e))
+    };
+    let episode_path = resolve(&episode_audio)?;
+    let recording_path = resolve(&recording)?;
+    let comparison = compare_pronunciation_files(&episode_path, line_range, &recording_path)?;
+    info!(
+        "MFCC distance: {}, pitch similarity: {:?}, duration ratio: {}",
+        comparison.mfcc_distance, comparison.pitch_similarity, comparison.duration_ratio
+    );
+    Ok(comparison)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A voiced sound whose pitch and formants (F1, F2) glide from `start` to
+    /// `end` over its duration, like a syllable moving between two vowels.
+    fn utterance(
+        sample_rate: u32,
+        duration_ms: u32,
+        f0_hz: (f32, f32),
+        start: (f32, f32),
+        end: (f32, f32),
+    ) -> Vec<f32> {
+        let len = (sample_rate * duration_ms / 1000) as usize;
+        let fade = (sample_rate / 50) as usize;
+        let peak = |hz: f32, formant: f32| (-((hz - formant) / 150.0).powi(2)).exp();
+        let mut phase = 0.0_f32;
+        (0..len)
+            .map(|n| {
+                let progress = n as f32 / len as f32;
+                let f0 = f0_hz.0 + (f0_hz.1 - f0_hz.0) * progress;
+                let f1 = start.0 + (end.0 - start.0) * progress;
+                let f2 = start.1 + (end.1 - start.1) * progress;
+                phase += 2.0 * PI * f0 / sample_rate as f32;
+                let envelope = (n.min(len - n) as f32 / fade as f32).min(1.0);
+                let sample: f32 = (1..=(4000.0 / f0) as usize)
+                    .map(|h| {
+                        let hz = h as f32 * f0;
+                        let amplitude = peak(hz, f1) + 0.7 * peak(hz, f2) + 0.02;
+                        amplitude * (h as f32 * phase).sin()
+                    })
+                    .sum();
+                0.1 * envelope * sample
+            })
+            .collect()
+    }
+
+    fn write_wav_fixture(name: &str, sample_rate: u32, samples: &[f32]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("kotonoha-{}-{}.wav", name, std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in samples {
+            writer
+                .write_sample((sample * i16::MAX as f32) as i16)
+                .unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    fn with_silence(sample_rate: u32, before_ms: u32, speech: Vec<f32>, after_ms: u32) -> Vec<f32> {
+        let mut samples = vec![0.0; (sample_rate * before_ms / 1000) as usize];
+        samples.extend(speech);
+        samples.extend(vec![0.0; (sample_rate * after_ms / 1000) as usize]);
+        samples
+    }
+
+    const VOWEL_A: (f32, f32) = (800.0, 1200.0);
+    const VOWEL_I: (f32, f32) = (300.0, 2300.0);
+    const VOWEL_O: (f32, f32) = (500.0, 900.0);
+
+    #[test]
+    fn test_dtw_aligns_a_stretched_sequence() {
+        let a: Vec<Vec<f32>> = [0.0, 1.0, 2.0, 3.0].iter().map(|&x| vec![x]).collect();
+        let b: Vec<Vec<f32>> = [0.0, 1.0, 1.0, 2.0, 3.0, 3.0]
+            .iter()
+            .map(|&x| vec![x])
+            .collect();
+        let (distance, path) = dtw(&a, &b);
+        assert_eq!(distance, 0.0);
+        assert_eq!(path, vec![(0, 0), (1, 1), (1, 2), (2, 3), (3, 4), (3, 5)]);
+    }
+
+    #[test]
+    fn test_compare_pronunciation_of_wav_fixtures() {
+        // The native line, with some of the surrounding episode in the range.
+        let native = with_silence(
+            16000,
+            500,
+            utterance(16000, 1500, (150.0, 250.0), VOWEL_A, VOWEL_I),
+            500,
+        );
+        let episode_path = write_wav_fixture("native", 16000, &native);
+        // The same line said more slowly, recorded at another sample rate
+        // with silence before and after it.
+        let good_take = with_silence(
+            22050,
+            300,
+            utterance(22050, 1875, (120.0, 200.0), VOWEL_A, VOWEL_I),
+            200,
+        );
+        let good_path = write_wav_fixture("good-take", 22050, &good_take);
+        let bad_take = with_silence(
+            22050,
+            300,
+            utterance(22050, 1500, (250.0, 150.0), VOWEL_I, VOWEL_O),
+            200,
+        );
+        let bad_path = write_wav_fixture("bad-take", 22050, &bad_take);
+        let line = LineRange {
+            start_ms: 400,
+            end_ms: 2100,
+        };
+
+        let good = compare_pronunciation_files(&episode_path, line, &good_path).unwrap();
+        assert!(
+            (good.native_duration_ms as i32 - 1500).abs() <= 30,
+            "{:?}",
+            good
+        );
+        assert!(
+            (good.recording_duration_ms as i32 - 1875).abs() <= 30,
+            "{:?}",
+            good
+        );
+        assert!((good.duration_ratio - 1.25).abs() < 0.05, "{:?}", good);
+        assert!(good.timing_deviation_ms < 50.0, "{:?}", good);
+        assert!(good.pitch_similarity.unwrap() > 0.9, "{:?}", good);
+        // The speech starts 100ms into the line and 300ms into the recording.
+        let first = &good.alignment[0];
+        assert!((first.native_ms as i32 - 100).abs() <= 20, "{:?}", first);
+        assert!((first.recording_ms as i32 - 300).abs() <= 20, "{:?}", first);
+
+        let bad = compare_pronunciation_files(&episode_path, line, &bad_path).unwrap();
+        assert!(bad.pitch_similarity.unwrap() < 0.0, "{:?}", bad);
+        assert!(
+            bad.mfcc_distance > good.mfcc_distance * 3.0,
+            "{} vs {}",
+            bad.mfcc_distance,
+            good.mfcc_distance
+        );
+
+        for path in [episode_path, good_path, bad_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,283 @@
+// cSpell:words rustfft cmndf
+use log::info;
+use rustfft::{num_complex::Complex, FftPlanner};
+use serde::Serialize;
+use std::f32::consts::PI;
+use tauri::{path::BaseDirectory, AppHandle, Manager};
+
+use crate::audio::downmix_to_mono;
+use crate::clip::extract_clip;
+
+/// Every column of the spectrogram and every point of the pitch contour is
+/// this far from the previous one.
+pub(crate) const HOP_MS: u32 = 10;
+/// Length of the Hann window of the STFT.
+const STFT_WINDOW_MS: u32 = 32;
+/// The spectrogram is cut off above this, where speech has little energy.
+const MAX_FREQUENCY_HZ: f32 = 8000.0;
+/// Magnitudes are clamped to this so that silence does not produce `-inf`.
+const MIN_MAGNITUDE_DB: f32 = -100.0;
+/// Longer ranges would produce matrices too large to send to the UI.
+const MAX_RANGE_MS: u32 = 60_000;
+
+/// Range of fundamental frequencies searched for, covering low male to high
+/// female and child voices.
+const MIN_F0_HZ: f32 = 60.0;
+const MAX_F0_HZ: f32 = 500.0;
+/// Threshold of the cumulative mean normalized difference below which a lag
+/// is taken as the period (0.1-0.15 in the YIN paper).
+const YIN_THRESHOLD: f32 = 0.15;
+/// Frames quieter than this are unvoiced, whatever their periodicity.
+const MIN_VOICED_LEVEL_DB: f32 = -50.0;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PitchPoint {
+    /// Start of the analysis frame, relative to the start of the range.
+    pub(crate) time_ms: u32,
+    /// `None` for unvoiced or silent frames.
+    pub(crate) f0_hz: Option<f32>,
+    /// 1 minus the normalized difference at the chosen lag; close to 1 for
+    /// clearly periodic frames.
+    pub(crate) confidence: f32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SpectralAnalysis {
+    start_ms: u32,
+    end_ms: u32,
+    hop_ms: u32,
+    /// Width of a frequency bin; bin `k` is centered on `k * binHz`.
+    bin_hz: f32,
+    /// Magnitudes in dBFS, one row per frame (`hopMs` apart) and one column
+    /// per frequency bin from 0Hz up to 8kHz.
+    spectrogram: Vec<Vec<f32>>,
+    pitch: Vec<PitchPoint>,
+}
+
+fn hann_window(len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / len as f32).cos())
+        .collect()
+}
+
+fn frame_count(sample_count: usize, hop: usize) -> usize {
+    sample_count.div_ceil(hop)
+}
+
+/// Magnitude spectrogram of mono `samples` in dBFS. A full-scale sine reads
+/// about 0dB in the bin of its frequency.
+pub(crate) fn stft_magnitudes_db(samples: &[f32], sample_rate: u32) -> (Vec<Vec<f32>>, f32) {
+    let window_len = (sample_rate * STFT_WINDOW_MS / 1000) as usize;
+    let fft_len = window_len.next_power_of_two();
+    let hop = (sample_rate * HOP_MS / 1000) as usize;
+    let bin_hz = sample_rate as f32 / fft_len as f32;
+    let bins = ((MAX_FREQUENCY_HZ / bin_hz) as usize + 1).min(fft_len / 2 + 1);
+
+    let window = hann_window(window_len);
+    // Scales the peak of a full-scale sine to 1.
+    let scale = 2.0 / window.iter().sum::<f32>();
+    let fft = FftPlanner::new().plan_fft_forward(fft_len);
+    let mut buffer = vec![Complex::new(0.0, 0.0); fft_len];
+
+    let spectrogram = (0..frame_count(samples.len(), hop))
+        .map(|frame| {
+            let start = frame * hop;
+            buffer.fill(Complex::new(0.0, 0.0));
+            for (i, w) in window.iter().enumerate() {
+                let sample = samples.get(start + i).copied().unwrap_or(0.0);
+                buffer[i] = Complex::new(sample * w, 0.0);
+            }
+            fft.process(&mut buffer);
+            buffer[..bins]
+                .iter()
+                .map(|c| (20.0 * (c.norm() * scale).log10()).max(MIN_MAGNITUDE_DB))
+                .collect()
+        })
+        .collect();
+    (spectrogram, bin_hz)
+}
+
+/// Refines the lag of a minimum of `values` by fitting a parabola through it
+/// and its neighbors.
+fn parabolic_minimum(values: &[f32], tau: usize) -> f32 {
+    if tau == 0 || tau + 1 >= values.len() {
+        return tau as f32;
+    }
+    let (a, b, c) = (values[tau - 1], values[tau], values[tau + 1]);
+    let denominator = a - 2.0 * b + c;
+    if denominator.abs() < f32::EPSILON {
+        tau as f32
+    } else {
+        tau as f32 + 0.5 * (a - c) / denominator
+    }
+}
+
+/// F0 contour of mono `samples` with YIN (de Cheveigné and Kawahara, 2002).
+///
+/// The difference function of each frame is computed from an FFT
+/// cross-correlation, so that the cost does not grow with the square of the
+/// longest period.
+pub(crate) fn yin_pitch_contour(samples: &[f32], sample_rate: u32) -> Vec<PitchPoint> {
+    let hop = (sample_rate * HOP_MS / 1000) as usize;
+    let tau_min = (sample_rate as f32 / MAX_F0_HZ).floor() as usize;
+    let tau_max = (sample_rate as f32 / MIN_F0_HZ).ceil() as usize;
+    // The integration window holds the longest period once.
+    let window_len = tau_max;
+    let span = window_len + tau_max;
+    let fft_len = span.next_power_of_two();
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(fft_len);
+    let ifft = planner.plan_fft_inverse(fft_len);
+    let mut window = vec![Complex::new(0.0, 0.0); fft_len];
+    let mut frame = vec![Complex::new(0.0, 0.0); fft_len];
+    let mut energies = vec![0.0_f32; span + 1];
+    let mut cmndf = vec![1.0_f32; tau_max + 1];
+    let min_energy = window_len as f32 * 10f32.powf(MIN_VOICED_LEVEL_DB / 10.0);
+
+    (0..frame_count(samples.len(), hop))
+        .map(|index| {
+            let start = index * hop;
+            let time_ms = (index * HOP_MS as usize) as u32;
+            let unvoiced = PitchPoint {
+                time_ms,
+                f0_hz: None,
+                confidence: 0.0,
+            };
+
+            window.fill(Complex::new(0.0, 0.0));
+            frame.fill(Complex::new(0.0, 0.0));
+            for i in 0..span {
+                let sample = samples.get(start + i).copied().unwrap_or(0.0);
+                frame[i] = Complex::new(sample, 0.0);
+                if i < window_len {
+                    window[i] = Complex::new(sample, 0.0);
+                }
+                // Prefix sums of squares, for the energy of any sub-window.
+                energies[i + 1] = energies[i] + sample * sample;
+            }
+            let energy = |from: usize| energies[from + window_len] - energies[from];
+            if energy(0) < min_energy {
+                return unvoiced;
+            }
+
+            fft.process(&mut window);
+            fft.process(&mut frame);
+            for (w, f) in window.iter_mut().zip(frame.iter()) {
+                *w = w.conj() * f;
+            }
+            ifft.process(&mut window);
+            // rustfft does not normalize the inverse transform.
+            let correlation = |tau: usize| window[tau].re / fft_len as f32;
+
+            // d(tau) = sum (x_j - x_{j+tau})^2, normalized by its running mean.
+            let mut running_sum = 0.0;
+            for (tau, value) in cmndf.iter_mut().enumerate().skip(1) {
+                let difference = (energy(0) + energy(tau) - 2.0 * correlation(tau)).max(0.0);
+                running_sum += difference;
+                *value = if running_sum > 0.0 {
+                    difference * tau as f32 / running_sum
+                } else {
+                    1.0
+                };
+            }
+
+            let Some(mut tau) = (tau_min.max(2)..tau_max).find(|&tau| cmndf[tau] < YIN_THRESHOLD)
+            else {
+                return unvoiced;
+            };
+            // Go down to the bottom of the dip below the threshold.
+            while tau + 1 < tau_max && cmndf[tau + 1] < cmndf[tau] {
+                tau += 1;
+            }
+            PitchPoint {
+                time_ms,
+                f0_hz: Some(sample_rate as f32 / parabolic_minimum(&cmndf, tau)),
+                confidence: (1.0 - cmndf[tau]).clamp(0.0, 1.0),
+            }
+        })
+        .collect()
+}
+
+#[tauri::command]
+pub async fn analyze_audio_spectral(
+    app_handle: AppHandle,
+    path: String,
+    start_ms: u32,
+    end_ms: u32,
+) -> Result<SpectralAnalysis, String> {
+    info!("analyze_audio_spectral: {} {}-{}", path, start_ms, end_ms);
+    if end_ms.saturating_sub(start_ms) > MAX_RANGE_MS {
+        return Err(format!(
+            "The range is too long for spectral analysis: {}ms (max {}ms)",
+            end_ms - start_ms,
+            MAX_RANGE_MS
+        ));
+    }
+    let full_path = app_handle
+        .path()
+        .resolve(&path, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Failed to resolve path '{}': {}", path, e))?;
+
+    let clip = extract_clip(&full_path, start_ms, end_ms, 0)?;
+    let samples: Vec<f32> = downmix_to_mono(clip.samples.into_iter(), clip.channels).collect();
+    let (spectrogram, bin_hz) = stft_magnitudes_db(&samples, clip.sample_rate);
+    let pitch = yin_pitch_contour(&samples, clip.sample_rate);
+
+    Ok(SpectralAnalysis {
+        start_ms,
+        // The range may go beyond the end of the media.
+        end_ms: start_ms + (samples.len() as u64 * 1000 / clip.sample_rate as u64) as u32,
+        hop_ms: HOP_MS,
+        bin_hz,
+        spectrogram,
+        pitch,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, amplitude: f32, sample_rate: u32, ms: u32) -> Vec<f32> {
+        (0..sample_rate * ms / 1000)
+            .map(|n| amplitude * (2.0 * PI * frequency * n as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_stft_peaks_at_the_frequency_of_a_sine() {
+        let samples = sine(1000.0, 1.0, 16000, 500);
+        let (spectrogram, bin_hz) = stft_magnitudes_db(&samples, 16000);
+        assert_eq!(spectrogram.len(), 50);
+        // 512-point FFT at 16kHz, cut off at 8kHz.
+        assert_eq!(bin_hz, 31.25);
+        assert_eq!(spectrogram[0].len(), 257);
+
+        let row = &spectrogram[10];
+        let peak_bin = (0..row.len())
+            .max_by(|&a, &b| row[a].total_cmp(&row[b]))
+            .unwrap();
+        assert_eq!(peak_bin, 32);
+        assert!(row[peak_bin].abs() < 1.0, "{}", row[peak_bin]);
+        assert!(row[200] < -60.0, "{}", row[200]);
+    }
+
+    #[test]
+    fn test_yin_pitch_contour_of_tones_and_silence() {
+        let mut samples = sine(220.0, 0.5, 16000, 300);
+        samples.extend(vec![0.0; 16000 * 200 / 1000]);
+        samples.extend(sine(150.0, 0.5, 16000, 300));
+
+        let contour = yin_pitch_contour(&samples, 16000);
+        assert_eq!(contour.len(), 80);
+        assert_eq!(contour[3].time_ms, 30);
+
+        let f0 = |point: &PitchPoint| point.f0_hz.unwrap();
+        assert!((f0(&contour[10]) - 220.0).abs() < 1.0, "{:?}", contour[10]);
+        assert!(contour[10].confidence > 0.9);
+        assert_eq!(contour[40].f0_hz, None);
+        assert!((f0(&contour[65]) - 150.0).abs() < 1.0, "{:?}", contour[65]);
+    }
+}
@@ -1,28 +1,458 @@
+use hound::{SampleFormat, WavSpec, WavWriter};
 use log::{error, info};
 use piper_rs::synth::{AudioOutputConfig, PiperSpeechSynthesizer};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, BufWriter, Write},
     num::{NonZero, NonZeroU32},
-    path::PathBuf,
-    sync::{LazyLock, Mutex},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, LazyLock, Mutex, MutexGuard,
+    },
+    time::Duration,
 };
 use tauri::{path::BaseDirectory, AppHandle, Emitter, Manager};
 use tokio_util::sync::CancellationToken;
 use vorbis_rs::{VorbisBitrateManagementStrategy, VorbisEncoder, VorbisEncoderBuilder};
 
-const TTS_ID: &str = "tts";
+use crate::asr::resample;
+use crate::audio::{begin_growing_media, media_grown};
+use crate::loudness::normalize_loudness;
+use crate::opus::{OggOpusEncoder, OPUS_SAMPLE_RATES};
 
-static TTS_CANCEL_TOKENS: LazyLock<Mutex<HashMap<String, CancellationToken>>> =
+/// ID of the jobs started without one.
+const DEFAULT_TTS_ID: &str = "tts";
+/// Directory in AppLocalData of the outputs of jobs started without a path.
+const TTS_OUTPUT_DIR: &str = "tts";
+/// Synthesis already runs in parallel within a job, so only a few jobs run
+/// at a time and the rest wait for their turn.
+const MAX_CONCURRENT_TTS_JOBS: usize = 2;
+/// How often a waiting job checks whether it has been cancelled.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_SAMPLE_RATE: u32 = 22050;
+/// Opus encodes at 48kHz internally, so there is nothing to gain from less.
+const DEFAULT_OPUS_SAMPLE_RATE: u32 = 48000;
+const SAMPLE_RATE_RANGE: (u32, u32) = (8000, 48000);
+
+/// Silence before and after every line unless set otherwise.
+const DEFAULT_PAUSE_MS: u32 = 500;
+/// Bounds of the prosody settings, within which the voices stay intelligible.
+const RATE_RANGE: (f32, f32) = (0.25, 4.0);
+const PITCH_RANGE: (f32, f32) = (0.5, 1.5);
+const VOLUME_DB_RANGE: (f32, f32) = (-30.0, 12.0);
+const MAX_PAUSE_MS: u32 = 10_000;
+/// piper-rs takes the rate and the pitch as percentages of these ranges of
+/// factors.
+const PIPER_RATE_RANGE: (f32, f32) = (0.0, 5.0);
+const PIPER_PITCH_RANGE: (f32, f32) = (0.5, 1.5);
+
+static TTS_JOBS: LazyLock<Mutex<HashMap<String, TtsJob>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
+static TTS_QUEUE: LazyLock<TtsQueue> = LazyLock::new(|| TtsQueue::new(MAX_CONCURRENT_TTS_JOBS));
+
+/// A job that is running or waiting for its turn.
+struct TtsJob {
+    cancel_token: CancellationToken,
+    audio_path: PathBuf,
+    script_path: PathBuf,
+}
+
+impl TtsJob {
+    fn writes_to(&self, path: &Path) -> bool {
+        self.audio_path == path || self.script_path == path
+    }
+}
+
+struct TtsQueueState {
+    running: usize,
+    waiting: VecDeque<String>,
+}
+
+/// Lets at most `capacity` jobs run at a time, in the order they were
+/// queued.
+struct TtsQueue {
+    capacity: usize,
+    state: Mutex<TtsQueueState>,
+    turn: Condvar,
+}
+
+/// The turn of a running job, which ends when this is dropped.
+struct TtsTurn<'a> {
+    queue: &'a TtsQueue,
+}
+
+impl TtsQueue {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            state: Mutex::new(TtsQueueState {
+                running: 0,
+                waiting: VecDeque::new(),
+            }),
+            turn: Condvar::new(),
+        }
+    }
+
+    /// Waits until it is the turn of `tts_id`. Fails if the job is cancelled
+    /// while waiting.
+    fn enter(&self, tts_id: &str, cancel_token: &CancellationToken) -> Result<TtsTurn<'_>, String> {
+        let mut state = self.lock();
+        state.waiting.push_back(tts_id.to_string());
+        loop {
+            if cancel_token.is_cancelled() {
+                state.waiting.retain(|id| id != tts_id);
+                // The job after this one may be able to run now.
+                self.turn.notify_all();
+                return Err("TTS cancelled".to_string());
+            }
+            if state.running < self.capacity
+                && state.waiting.front().map(String::as_str) == Some(tts_id)
+            {
+                state.waiting.pop_front();
+                state.running += 1;
+                self.turn.notify_all();
+                return Ok(TtsTurn { queue: self });
+            }
+            state = self
+                .turn
+                .wait_timeout(state, QUEUE_POLL_INTERVAL)
+                .unwrap()
+                .0;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TtsQueueState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for TtsTurn<'_> {
+    fn drop(&mut self) {
+        self.queue.lock().running -= 1;
+        self.queue.turn.notify_all();
+    }
+}
+
 #[derive(Serialize, Clone)]
 #[serde(rename_all = "camelCase")]
 struct TtsProgressPayload {
+    tts_id: String,
     progress: u8, // 0-100
     start_ms: u32,
     end_ms: u32,
     text: String,
+    /// The output file, which can be played while it is being written.
+    /// `None` for formats the app cannot play.
+    audio_path: Option<String>,
+    /// How much of the output has been written to the file.
+    available_ms: Option<u32>,
+}
+
+/// How the lines are spoken. Unset values fall back to the natural delivery
+/// of the voice and a pause of 500ms.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TtsProsody {
+    /// Speaking rate as a factor of the natural rate, e.g. 0.7 for beginners.
+    pub rate: Option<f32>,
+    /// Pitch as a factor of the natural pitch.
+    pub pitch: Option<f32>,
+    /// Gain in dB on top of the loudness the lines are normalized to.
+    pub volume_db: Option<f32>,
+    /// Silence before and after the line.
+    pub pause_ms: Option<u32>,
+}
+
+impl TtsProsody {
+    /// These settings with the values set in `line` taking precedence.
+    fn overridden_by(&self, line: &TtsProsody) -> TtsProsody {
+        TtsProsody {
+            rate: line.rate.or(self.rate),
+            pitch: line.pitch.or(self.pitch),
+            volume_db: line.volume_db.or(self.volume_db),
+            pause_ms: line.pause_ms.or(self.pause_ms),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let check = |name: &str, value: Option<f32>, (min, max): (f32, f32)| match value {
+            Some(value) if !(min..=max).contains(&value) => Err(format!(
+                "TTS {} must be between {} and {}: {}",
+                name, min, max, value
+            )),
+            _ => Ok(()),
+        };
+        check("rate", self.rate, RATE_RANGE)?;
+        check("pitch", self.pitch, PITCH_RANGE)?;
+        check("volume", self.volume_db, VOLUME_DB_RANGE)?;
+        match self.pause_ms {
+            Some(pause_ms) if pause_ms > MAX_PAUSE_MS => Err(format!(
+                "TTS pause must be at most {}ms: {}ms",
+                MAX_PAUSE_MS, pause_ms
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn pause_ms(&self) -> u32 {
+        self.pause_ms.unwrap_or(DEFAULT_PAUSE_MS)
+    }
+
+    fn gain(&self) -> f32 {
+        10f32.powf(self.volume_db.unwrap_or(0.0) / 20.0)
+    }
+
+    fn output_config(&self) -> AudioOutputConfig {
+        let percent = |factor: f32, (min, max): (f32, f32)| {
+            ((factor - min) / (max - min) * 100.0)
+                .round()
+                .clamp(0.0, 100.0) as u8
+        };
+        AudioOutputConfig {
+            rate: self.rate.map(|rate| percent(rate, PIPER_RATE_RANGE)),
+            pitch: self.pitch.map(|pitch| percent(pitch, PIPER_PITCH_RANGE)),
+            // Each line is normalized to the loudness of the episodes and
+            // `volume_db` is applied on top of that instead.
+            volume: None,
+            appended_silence_ms: None,
+        }
+    }
+}
+
+/// A speaker of a Piper model.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct TtsVoice {
+    /// Piper config, relative to AppLocalData.
+    pub config_path: String,
+    pub speaker_id: u32,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TtsSettings {
+    #[serde(default)]
+    pub prosody: TtsProsody,
+    /// Overrides for single lines, keyed by the index of the line among the
+    /// non-empty lines of the transcript.
+    #[serde(default)]
+    pub line_prosody: HashMap<usize, TtsProsody>,
+    /// Voices of the speakers of a dialogue, by name. A line starting with
+    /// `Name:` for one of these names is spoken by that voice.
+    #[serde(default)]
+    pub voices: HashMap<String, TtsVoice>,
+    /// Speakers of single lines, keyed like `line_prosody`, taking precedence
+    /// over the prefixes of the lines.
+    #[serde(default)]
+    pub line_speakers: HashMap<usize, String>,
+}
+
+/// A line of the transcript and who speaks it.
+#[derive(Debug, PartialEq)]
+struct ScriptLine<'a> {
+    speaker: Option<&'a str>,
+    /// The line without its speaker prefix, as it is synthesized.
+    text: &'a str,
+}
+
+impl ScriptLine<'_> {
+    /// The line as written to the SSWT script, with its speaker.
+    fn script_text(&self) -> String {
+        match self.speaker {
+            Some(speaker) => format!("{}: {}", speaker, self.text),
+            None => self.text.to_string(),
+        }
+    }
+}
+
+/// Splits `Name: text` (with an ASCII or a full-width colon) into the name
+/// and the text.
+fn split_speaker_prefix(line: &str) -> Option<(&str, &str)> {
+    let (name, text) = line.split_once([':', '\u{FF1A}'])?;
+    let name = name.trim();
+    (!name.is_empty()).then(|| (name, text.trim()))
+}
+
+impl TtsSettings {
+    fn line_prosody(&self, index: usize) -> TtsProsody {
+        match self.line_prosody.get(&index) {
+            Some(line) => self.prosody.overridden_by(line),
+            None => self.prosody,
+        }
+    }
+
+    /// The lines of `transcript` with their speakers. Lines without one are
+    /// spoken by the default voice.
+    fn script_lines<'a>(&'a self, transcript: &'a str) -> Vec<ScriptLine<'a>> {
+        transcript_lines(transcript)
+            .into_iter()
+            .enumerate()
+            .map(|(index, line)| {
+                let (prefix, text) = match split_speaker_prefix(line) {
+                    Some((name, text)) if self.voices.contains_key(name) => (Some(name), text),
+                    _ => (None, line),
+                };
+                ScriptLine {
+                    speaker: self
+                        .line_speakers
+                        .get(&index)
+                        .map(String::as_str)
+                        .or(prefix),
+                    text,
+                }
+            })
+            .collect()
+    }
+
+    fn voice<'a>(&'a self, line: &ScriptLine, default_voice: &'a TtsVoice) -> &'a TtsVoice {
+        line.speaker
+            .and_then(|speaker| self.voices.get(speaker))
+            .unwrap_or(default_voice)
+    }
+
+    fn validate(&self, line_count: usize) -> Result<(), String> {
+        self.prosody.validate()?;
+        for (&index, speaker) in &self.line_speakers {
+            if index >= line_count {
+                return Err(format!(
+                    "TTS speaker for line {}, but the transcript has {} lines",
+                    index, line_count
+                ));
+            }
+            if !self.voices.contains_key(speaker) {
+                return Err(format!(
+                    "No voice for the speaker of line {}: {}",
+                    index, speaker
+                ));
+            }
+        }
+        for (&index, line) in &self.line_prosody {
+            if index >= line_count {
+                return Err(format!(
+                    "TTS settings for line {}, but the transcript has {} lines",
+                    index, line_count
+                ));
+            }
+            line.validate()
+                .map_err(|e| format!("{} (line {})", e, index))?;
+        }
+        Ok(())
+    }
+}
+
+/// The non-empty lines of `transcript`, which are synthesized one by one.
+fn transcript_lines(transcript: &str) -> Vec<&str> {
+    transcript
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .collect()
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TtsAudioFormat {
+    /// Ogg Vorbis.
+    #[default]
+    Ogg,
+    /// 16-bit PCM.
+    Wav,
+    /// Ogg Opus.
+    Opus,
+}
+
+impl TtsAudioFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            TtsAudioFormat::Ogg => "ogg",
+            TtsAudioFormat::Wav => "wav",
+            TtsAudioFormat::Opus => "opus",
+        }
+    }
+
+    /// Whether the app can open the output. The audio decoder has no Opus
+    /// support, so Opus output is for export only.
+    fn is_playable(&self) -> bool {
+        !matches!(self, TtsAudioFormat::Opus)
+    }
+
+    fn default_sample_rate(&self) -> u32 {
+        match self {
+            TtsAudioFormat::Opus => DEFAULT_OPUS_SAMPLE_RATE,
+            TtsAudioFormat::Ogg | TtsAudioFormat::Wav => DEFAULT_SAMPLE_RATE,
+        }
+    }
+
+    fn check_sample_rate(&self, sample_rate: u32) -> Result<(), String> {
+        if !(SAMPLE_RATE_RANGE.0..=SAMPLE_RATE_RANGE.1).contains(&sample_rate) {
+            return Err(format!(
+                "TTS sample rate must be between {}Hz and {}Hz: {}Hz",
+                SAMPLE_RATE_RANGE.0, SAMPLE_RATE_RANGE.1, sample_rate
+            ));
+        }
+        if *self == TtsAudioFormat::Opus && !OPUS_SAMPLE_RATES.contains(&sample_rate) {
+            return Err(format!(
+                "Opus sample rate must be one of {:?}Hz: {}Hz",
+                OPUS_SAMPLE_RATES, sample_rate
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Where and how a job writes its output.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TtsOutputOptions {
+    /// Relative to AppLocalData. Defaults to `tts/<ttsId>.<format>`.
+    pub audio_path: Option<String>,
+    /// Relative to AppLocalData. Defaults to the audio path with the `.sswt`
+    /// extension.
+    pub script_path: Option<String>,
+    #[serde(default)]
+    pub format: TtsAudioFormat,
+    /// Defaults to 22050Hz, or 48kHz for Opus. The voices are resampled to
+    /// this rate.
+    pub sample_rate: Option<u32>,
+}
+
+/// Checks that a caller-chosen output path stays inside AppLocalData.
+fn check_output_path(path: &str) -> Result<&Path, String> {
+    let relative = Path::new(path);
+    let inside = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if !inside || relative.file_name().is_none() {
+        return Err(format!(
+            "TTS output path must be a file inside AppLocalData: {}",
+            path
+        ));
+    }
+    Ok(relative)
+}
+
+fn resolve_output_path(app_handle: &AppHandle, path: &str) -> Result<PathBuf, String> {
+    app_handle
+        .path()
+        .resolve(check_output_path(path)?, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Could not resolve output path '{}': {:?}", path, e))
+}
+
+/// Everything a job needs to run, checked before it is queued.
+struct TtsRequest {
+    tts_id: String,
+    transcript: String,
+    /// The voice of the lines without a speaker.
+    default_voice: TtsVoice,
+    settings: TtsSettings,
+    format: TtsAudioFormat,
+    sample_rate: u32,
+    audio_path: PathBuf,
+    script_path: PathBuf,
 }
 
 #[derive(Serialize, Clone)]
@@ -44,8 +474,43 @@ fn format_timestamp(ms: u32) -> String {
     )
 }
 
+/// The parts of a Piper config that are not exposed by piper-rs.
+#[derive(Deserialize)]
+struct PiperConfig {
+    audio: PiperAudioConfig,
+}
+
+#[derive(Deserialize)]
+struct PiperAudioConfig {
+    sample_rate: u32,
+}
+
+/// A loaded voice. Models of different voices may have different sample
+/// rates.
+struct PiperVoice {
+    synthesizer: PiperSpeechSynthesizer,
+    sample_rate: u32,
+}
+
+fn load_piper_voice(app_handle: &AppHandle, voice: &TtsVoice) -> Result<PiperVoice, String> {
+    let config_absolute_path = app_handle
+        .path()
+        .resolve(&voice.config_path, BaseDirectory::AppLocalData)
+        .map_err(|e| format!("Could not resolve config path: {:?}", e))?;
+    let config: PiperConfig = serde_json::from_str(
+        &fs::read_to_string(&config_absolute_path)
+            .map_err(|e| format!("Could not read config: {:?}", e))?,
+    )
+    .map_err(|e| format!("Could not parse config: {:?}", e))?;
+    let synthesizer = create_piper_synthesizer(&config_absolute_path, voice.speaker_id as i64)?;
+    Ok(PiperVoice {
+        synthesizer,
+        sample_rate: config.audio.sample_rate,
+    })
+}
+
 fn create_piper_synthesizer(
-    config_absolute_path: &PathBuf,
+    config_absolute_path: &Path,
     speaker_id: i64,
 ) -> Result<PiperSpeechSynthesizer, String> {
     let model = piper_rs::from_config_path(config_absolute_path)
@@ -58,11 +523,69 @@ fn create_piper_synthesizer(
     Ok(synth)
 }
 
-fn create_vorbis_encoder(
-    output: &mut Vec<u8>,
+/// Passes an Ogg stream on one complete page at a time, so that a file being
+/// written never ends in the middle of a page, and counts the audio frames
+/// of the pages written so far.
+pub(crate) struct OggPageWriter<W: Write> {
+    inner: W,
+    pending: Vec<u8>,
+    written_frames: Arc<AtomicU64>,
+}
+
+impl<W: Write> OggPageWriter<W> {
+    pub(crate) fn new(inner: W, written_frames: Arc<AtomicU64>) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            written_frames,
+        }
+    }
+
+    /// Length of the complete page at the start of `pending`, if any.
+    fn complete_page_len(&self) -> Option<usize> {
+        const HEADER_LEN: usize = 27;
+        let header = self.pending.get(..HEADER_LEN)?;
+        let segment_count = header[26] as usize;
+        let segments = self.pending.get(HEADER_LEN..HEADER_LEN + segment_count)?;
+        let page_len =
+            HEADER_LEN + segment_count + segments.iter().map(|&s| s as usize).sum::<usize>();
+        (self.pending.len() >= page_len).then_some(page_len)
+    }
+}
+
+impl<W: Write> Write for OggPageWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        while let Some(page_len) = self.complete_page_len() {
+            if &self.pending[..4] != b"OggS" {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Not an Ogg page",
+                ));
+            }
+            self.inner.write_all(&self.pending[..page_len])?;
+            self.inner.flush()?;
+            // -1 for pages on which no packet ends.
+            let granule_position = u64::from_le_bytes(self.pending[6..14].try_into().unwrap());
+            if granule_position != u64::MAX {
+                self.written_frames
+                    .store(granule_position, Ordering::Release);
+            }
+            self.pending.drain(..page_len);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) fn create_vorbis_encoder<W: Write>(
+    output: W,
     sample_rate: u32,
     channels: u8,
-) -> Result<VorbisEncoder<&mut Vec<u8>>, String> {
+) -> Result<VorbisEncoder<W>, String> {
     let encoder = VorbisEncoderBuilder::new(
         NonZero::new(sample_rate).ok_or("Sample rate must be non-zero")?,
         NonZero::new(channels).ok_or("Channels must be non-zero")?,
@@ -77,51 +600,140 @@ fn create_vorbis_encoder(
     Ok(encoder)
 }
 
+/// Encodes the synthesized audio into the output file as it is produced.
+enum TtsEncoder {
+    Ogg(VorbisEncoder<OggPageWriter<File>>),
+    Opus(OggOpusEncoder<BufWriter<File>>),
+    Wav {
+        writer: WavWriter<BufWriter<File>>,
+        written_frames: Arc<AtomicU64>,
+    },
+}
+
+impl TtsEncoder {
+    /// Creates the encoder of mono audio, which counts the frames readable
+    /// from the file in `written_frames`.
+    fn create(
+        path: &Path,
+        format: TtsAudioFormat,
+        sample_rate: u32,
+        written_frames: Arc<AtomicU64>,
+    ) -> Result<Self, String> {
+        let file =
+            File::create(path).map_err(|e| format!("Could not create output file: {:?}", e))?;
+        match format {
+            TtsAudioFormat::Ogg => {
+                let writer = OggPageWriter::new(file, written_frames);
+                Ok(TtsEncoder::Ogg(create_vorbis_encoder(
+                    writer,
+                    sample_rate,
+                    1,
+                )?))
+            }
+            TtsAudioFormat::Opus => Ok(TtsEncoder::Opus(OggOpusEncoder::new(
+                BufWriter::new(file),
+                sample_rate,
+                written_frames,
+            )?)),
+            TtsAudioFormat::Wav => {
+                let spec = WavSpec {
+                    channels: 1,
+                    sample_rate,
+                    bits_per_sample: 16,
+                    sample_format: SampleFormat::Int,
+                };
+                let writer = WavWriter::new(BufWriter::new(file), spec)
+                    .map_err(|e| format!("Could not create WAV writer: {:?}", e))?;
+                Ok(TtsEncoder::Wav {
+                    writer,
+                    written_frames,
+                })
+            }
+        }
+    }
+
+    fn encode(&mut self, samples: &[f32]) -> Result<(), String> {
+        match self {
+            TtsEncoder::Ogg(encoder) => encoder
+                .encode_audio_block([samples])
+                .map_err(|e| format!("{:?}", e)),
+            TtsEncoder::Opus(encoder) => encoder.encode(samples),
+            TtsEncoder::Wav { writer, .. } => samples.iter().try_for_each(|&sample| {
+                writer
+                    .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+                    .map_err(|e| format!("{:?}", e))
+            }),
+        }
+    }
+
+    /// Makes the audio encoded so far readable from the file.
+    fn flush(&mut self) -> Result<(), String> {
+        match self {
+            // Complete pages are written as soon as they are produced.
+            TtsEncoder::Ogg(_) => Ok(()),
+            TtsEncoder::Opus(encoder) => encoder.flush(),
+            TtsEncoder::Wav {
+                writer,
+                written_frames,
+            } => {
+                // Also updates the sizes in the header.
+                writer
+                    .flush()
+                    .map_err(|e| format!("Could not write output file: {:?}", e))?;
+                written_frames.store(writer.len() as u64, Ordering::Release);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self {
+            TtsEncoder::Ogg(encoder) => encoder
+                .finish()
+                .map(|_| ())
+                .map_err(|e| format!("Could not finish encoding: {:?}", e)),
+            TtsEncoder::Opus(encoder) => encoder.finish().map(|_| ()),
+            TtsEncoder::Wav { writer, .. } => writer
+                .finalize()
+                .map_err(|e| format!("Could not finish encoding: {:?}", e)),
+        }
+    }
+}
+
 fn process_tts<F>(
-    synthesizer: &PiperSpeechSynthesizer,
-    encoder: &mut VorbisEncoder<&mut Vec<u8>>,
+    lines: &[(ScriptLine, &PiperVoice)],
+    encoder: &mut TtsEncoder,
     sample_rate: u32,
-    margin_silence_ms: u32,
-    transcript: &str,
+    settings: &TtsSettings,
     cancel_token: &CancellationToken,
     sswt_lines: &mut Vec<String>,
     mut callback: F,
 ) -> Result<(), String>
 where
-    F: FnMut(u8, u32, u32, String) -> (),
+    F: FnMut(u8, u32, u32, String),
 {
     let mut current_ms = 0_f64;
+    let silence_of = |pause_ms: u32| vec![0.0_f32; (sample_rate * pause_ms / 1000) as usize];
+    let duration_ms = |samples: &[f32]| samples.len() as f64 * 1000.0 / sample_rate as f64;
 
-    let silence = vec![0.0_f32; (sample_rate * margin_silence_ms / 1000) as usize];
-    let margin_silence_ms = silence.len() as f64 * 1000.0 / sample_rate as f64;
+    let silence = silence_of(settings.prosody.pause_ms());
     encoder
-        .encode_audio_block([silence.as_slice()])
-        .map_err(|e| format!("Could not encode audio block: {:?}", e))?;
-    current_ms += margin_silence_ms;
-
-    let output_config = AudioOutputConfig {
-        rate: None,
-        pitch: None,
-        volume: Some(80),
-        appended_silence_ms: None,
-    };
+        .encode(&silence)
+        .map_err(|e| format!("Could not encode audio block: {}", e))?;
+    current_ms += duration_ms(&silence);
 
-    // Pre-calc non-empty lines to compute per-line progress
-    let lines: Vec<&str> = transcript
-        .lines()
-        .map(|l| l.trim())
-        .filter(|l| !l.is_empty())
-        .collect();
     let total_lines = lines.len().max(1); // avoid div-by-zero
 
-    for (idx, &line) in lines.iter().enumerate() {
+    for (idx, (line, voice)) in lines.iter().enumerate() {
         if cancel_token.is_cancelled() {
             return Err("TTS cancelled".to_string());
         }
 
+        let prosody = settings.line_prosody(idx);
         let mut samples: Vec<f32> = Vec::new();
-        let speech_stream = synthesizer
-            .synthesize_parallel(line.to_string(), Some(output_config.clone()))
+        let speech_stream = voice
+            .synthesizer
+            .synthesize_parallel(line.text.to_string(), Some(prosody.output_config()))
             .map_err(|e| format!("Could not synthesize speech: {:?}", e))?;
         for result in speech_stream {
             if cancel_token.is_cancelled() {
@@ -136,25 +748,31 @@ where
                 }
             };
         }
+        let mut samples = resample(samples.into_iter(), voice.sample_rate, sample_rate)?;
+        normalize_loudness(&mut samples, sample_rate);
+        let gain = prosody.gain();
+        for sample in samples.iter_mut() {
+            *sample = (*sample * gain).clamp(-1.0, 1.0);
+        }
 
+        let silence = silence_of(prosody.pause_ms());
         encoder
-            .encode_audio_block([silence.as_slice()])
-            .map_err(|e| format!("Could not encode leading silence audio block: {:?}", e))?;
-        current_ms += margin_silence_ms;
+            .encode(&silence)
+            .map_err(|e| format!("Could not encode leading silence audio block: {}", e))?;
+        current_ms += duration_ms(&silence);
         let start_ms = current_ms;
+        encoder.encode(&samples).map_err(|e| {
+            format!(
+                "Could not encode synthesized audio block (line: {}): {}",
+                line.text, e
+            )
+        })?;
+        current_ms += duration_ms(&samples);
         encoder
-            .encode_audio_block([samples.as_slice()])
-            .map_err(|e| {
-                format!(
-                    "Could not encode synthesized audio block (line: {}): {:?}",
-                    line, e
-                )
-            })?;
-        current_ms += samples.len() as f64 * 1000.0 / sample_rate as f64;
-        encoder
-            .encode_audio_block([silence.as_slice()])
-            .map_err(|e| format!("Could not encode trailing silence audio block: {:?}", e))?;
-        current_ms += margin_silence_ms;
+            .encode(&silence)
+            .map_err(|e| format!("Could not encode trailing silence audio block: {}", e))?;
+        current_ms += duration_ms(&silence);
+        encoder.flush()?;
 
         let progress = (((idx + 1) * 100) / total_lines) as u8;
 
@@ -162,77 +780,138 @@ where
             progress,
             start_ms.round() as u32,
             current_ms.round() as u32,
-            line.to_string(),
+            line.script_text(),
         );
 
         let sswt_line = format!(
             "[{} -> {}] {}",
             format_timestamp(start_ms.round() as u32),
             format_timestamp(current_ms.round() as u32),
-            line.trim()
+            line.script_text()
         );
         sswt_lines.push(sswt_line);
     }
     Ok(())
 }
 
+/// Synthesizes the transcript of `request` into its audio file and returns
+/// the lines of its SSWT script.
 fn process_all_tts(
     app_handle: &AppHandle,
-    config_path: &String,
-    sample_rate: u32,
-    channels: u8,
-    margin_silence_ms: u32,
-    transcript: &str,
-    output_path: &PathBuf,
+    request: &TtsRequest,
     cancel_token: &CancellationToken,
-    speaker_id: u32,
-    sswt_lines: &mut Vec<String>,
-) -> Result<(), String> {
-    assert!(channels == 1, "Only mono audio is supported");
+) -> Result<Vec<String>, String> {
+    let TtsRequest {
+        tts_id,
+        settings,
+        sample_rate,
+        audio_path,
+        ..
+    } = request;
 
-    let config_absolute_path = app_handle
-        .path()
-        .resolve(config_path, BaseDirectory::AppLocalData)
-        .map_err(|e| format!("Could not resolve config path: {:?}", e))?;
-    let synthesizer = create_piper_synthesizer(&config_absolute_path, speaker_id as i64)?;
+    // One synthesizer per voice, shared by all of its lines.
+    let script_lines = settings.script_lines(&request.transcript);
+    let mut voices: HashMap<&TtsVoice, PiperVoice> = HashMap::new();
+    for line in &script_lines {
+        let voice = settings.voice(line, &request.default_voice);
+        if !voices.contains_key(voice) {
+            voices.insert(voice, load_piper_voice(app_handle, voice)?);
+        }
+    }
+    let lines: Vec<(ScriptLine, &PiperVoice)> = script_lines
+        .into_iter()
+        .map(|line| {
+            let voice = &voices[settings.voice(&line, &request.default_voice)];
+            (line, voice)
+        })
+        .collect();
 
-    let mut transcoded_ogg = vec![];
-    let mut encoder = create_vorbis_encoder(&mut transcoded_ogg, sample_rate, channels)?;
+    // The audio is written as it is encoded, so that it can be played before
+    // the whole transcript has been synthesized.
+    info!("TTS output path: {:?}", audio_path);
+    if let Some(parent) = audio_path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| format!("Could not create output directory: {:?}", e))?;
+    }
+    let written_frames = Arc::new(AtomicU64::new(0));
+    let mut encoder = TtsEncoder::create(
+        audio_path,
+        request.format,
+        *sample_rate,
+        Arc::clone(&written_frames),
+    )?;
+    let playable = request.format.is_playable();
+    if playable {
+        begin_growing_media(app_handle, audio_path);
+    }
 
-    process_tts(
-        &synthesizer,
+    let mut sswt_lines = Vec::new();
+    let result = process_tts(
+        &lines,
         &mut encoder,
-        sample_rate,
-        margin_silence_ms,
-        transcript,
+        *sample_rate,
+        settings,
         cancel_token,
-        sswt_lines,
+        &mut sswt_lines,
         |status: u8, start: u32, end: u32, line: String| {
+            let available_ms = playable.then(|| {
+                (written_frames.load(Ordering::Acquire) * 1000 / *sample_rate as u64) as u32
+            });
             app_handle
                 .emit(
                     "tts-progress",
                     TtsProgressPayload {
+                        tts_id: tts_id.clone(),
                         progress: status,
                         start_ms: start,
                         end_ms: end,
                         text: line.clone(),
+                        audio_path: playable.then(|| audio_path.to_string_lossy().to_string()),
+                        available_ms,
                     },
                 )
                 .unwrap_or_else(|e| {
                     error!("Could not emit tts-progress event: {:?}", e);
                 });
+            if playable {
+                media_grown(app_handle, audio_path, false);
+            }
         },
-    )?;
+    )
+    .and_then(|()| encoder.finish());
+    // A failed or cancelled output is not going to grow any more either.
+    if playable {
+        media_grown(app_handle, audio_path, true);
+    }
+    if result.is_err() {
+        // A failed or cancelled job leaves no partial output behind. This is
+        // only done once the job has created the file itself.
+        let _ = fs::remove_file(audio_path);
+    }
+    result.map(|()| sswt_lines)
+}
 
-    encoder
-        .finish()
-        .map_err(|e| format!("Could not finish encoding: {:?}", e))?;
+fn run_tts_job(
+    app_handle: &AppHandle,
+    request: &TtsRequest,
+    cancel_token: &CancellationToken,
+) -> Result<TtsResult, String> {
+    let _turn = TTS_QUEUE.enter(&request.tts_id, cancel_token)?;
+    info!("Starting TTS job: {}", request.tts_id);
 
-    info!("TTS output path: {:?}", output_path);
-    std::fs::write(&output_path, transcoded_ogg)
-        .map_err(|e| format!("Could not write output file: {:?}", e))?;
+    let sswt_lines = process_all_tts(app_handle, request, cancel_token)?;
+    // SSWT ファイルを書き出す
+    let sswt_content = sswt_lines.join("\n");
+    if let Err(e) = fs::write(&request.script_path, sswt_content) {
+        // The audio is of no use without its script.
+        let _ = fs::remove_file(&request.audio_path);
+        return Err(format!("Could not write SSWT file: {:?}", e));
+    }
 
-    Ok(())
+    Ok(TtsResult {
+        audio_path: request.audio_path.to_string_lossy().to_string(),
+        script_path: request.script_path.to_string_lossy().to_string(),
+    })
 }
 
 #[tauri::command]
@@ -241,66 +920,289 @@ pub async fn start_tts(
     transcript: String,
     config_path: String,
     speaker_id: u32,
+    settings: Option<TtsSettings>,
+    tts_id: Option<String>,
+    output: Option<TtsOutputOptions>,
 ) -> Result<TtsResult, String> {
-    // 同じ tts_id への同時TTSを防ぐ
-    {
-        let mut tokens = TTS_CANCEL_TOKENS.lock().unwrap();
-        if tokens.contains_key(TTS_ID) {
-            return Err("TTS already in progress".to_string());
-        }
-        let cancel_token = CancellationToken::new();
-        tokens.insert(TTS_ID.to_string(), cancel_token.clone());
+    let tts_id = tts_id.unwrap_or_else(|| DEFAULT_TTS_ID.to_string());
+    let settings = settings.unwrap_or_default();
+    settings.validate(transcript_lines(&transcript).len())?;
+
+    let output = output.unwrap_or_default();
+    let sample_rate = output
+        .sample_rate
+        .unwrap_or_else(|| output.format.default_sample_rate());
+    output.format.check_sample_rate(sample_rate)?;
+    let audio_path = match &output.audio_path {
+        Some(path) => resolve_output_path(&app_handle, path)?,
+        None => resolve_output_path(
+            &app_handle,
+            &format!(
+                "{}/{}.{}",
+                TTS_OUTPUT_DIR,
+                tts_id,
+                output.format.extension()
+            ),
+        )?,
+    };
+    let script_path = match &output.script_path {
+        Some(path) => resolve_output_path(&app_handle, path)?,
+        None => audio_path.with_extension("sswt"),
+    };
+    if audio_path == script_path {
+        return Err("TTS audio and script paths must differ".to_string());
     }
 
+    // 同じ tts_id や出力先への同時TTSを防ぐ
     let cancel_token = {
-        let tokens = TTS_CANCEL_TOKENS.lock().unwrap();
-        tokens.get(TTS_ID).cloned().unwrap()
+        let mut jobs = TTS_JOBS.lock().unwrap();
+        if jobs.contains_key(&tts_id) {
+            return Err("TTS already in progress for this TTS ID".to_string());
+        }
+        if jobs
+            .values()
+            .any(|job| job.writes_to(&audio_path) || job.writes_to(&script_path))
+        {
+            return Err("Another TTS job is writing to the same output path".to_string());
+        }
+        let cancel_token = CancellationToken::new();
+        jobs.insert(
+            tts_id.clone(),
+            TtsJob {
+                cancel_token: cancel_token.clone(),
+                audio_path: audio_path.clone(),
+                script_path: script_path.clone(),
+            },
+        );
+        cancel_token
     };
 
-    const SAMPLE_RATE: u32 = 22050;
-    const CHANNELS: u8 = 1;
-    const MARGIN_SILENCE_MS: u32 = 500;
-
-    let temp_dir = std::env::temp_dir();
-    let output_path = temp_dir.join("kotonoha_tts.ogg");
-    let script_path = temp_dir.join("kotonoha_tts.sswt");
-
-    let mut sswt_lines = Vec::new();
-    let result = process_all_tts(
-        &app_handle,
-        &config_path,
-        SAMPLE_RATE,
-        CHANNELS,
-        MARGIN_SILENCE_MS,
-        &transcript,
-        &output_path,
-        &cancel_token,
-        speaker_id,
-        &mut sswt_lines,
-    );
-
-    // 完了またはエラー時にトークンを削除
-    TTS_CANCEL_TOKENS.lock().unwrap().remove(TTS_ID);
-
-    result?;
+    let request = TtsRequest {
+        tts_id: tts_id.clone(),
+        transcript,
+        default_voice: TtsVoice {
+            config_path,
+            speaker_id,
+        },
+        settings,
+        format: output.format,
+        sample_rate,
+        audio_path,
+        script_path,
+    };
+    // Synthesis blocks, and may first wait for other jobs to finish.
+    let job_app_handle = app_handle.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        run_tts_job(&job_app_handle, &request, &cancel_token)
+    })
+    .await
+    .map_err(|e| format!("TTS job failed: {:?}", e))
+    .and_then(|result| result);
 
-    // SSWT ファイルを書き出す
-    let sswt_content = sswt_lines.join("\n");
-    std::fs::write(&script_path, sswt_content)
-        .map_err(|e| format!("Could not write SSWT file: {:?}", e))?;
+    // 完了またはエラー時にジョブを削除する。キャンセルされたジョブも出力先を
+    // 使い終えるまでは残しておく。
+    TTS_JOBS.lock().unwrap().remove(&tts_id);
 
-    Ok(TtsResult {
-        audio_path: output_path.to_string_lossy().to_string(),
-        script_path: script_path.to_string_lossy().to_string(),
-    })
+    result
 }
 
 #[tauri::command]
-pub async fn cancel_tts() -> Result<(), String> {
-    if let Some(token) = TTS_CANCEL_TOKENS.lock().unwrap().remove(TTS_ID) {
-        token.cancel();
+pub async fn cancel_tts(tts_id: Option<String>) -> Result<(), String> {
+    let tts_id = tts_id.unwrap_or_else(|| DEFAULT_TTS_ID.to_string());
+    if let Some(job) = TTS_JOBS.lock().unwrap().get(&tts_id) {
+        job.cancel_token.cancel();
         Ok(())
     } else {
         Err("TTS not found for this TTS ID".to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ogg_page(granule_position: u64, body: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend([0, 0]);
+        page.extend(granule_position.to_le_bytes());
+        page.extend([0; 12]);
+        page.push(1);
+        page.push(body.len() as u8);
+        page.extend(body);
+        page
+    }
+
+    #[test]
+    fn test_ogg_page_writer_writes_complete_pages() {
+        let written_frames = Arc::new(AtomicU64::new(0));
+        let mut output = Vec::new();
+        let first = ogg_page(22050, &[1; 100]);
+        let second = ogg_page(u64::MAX, &[2; 50]);
+        let third = ogg_page(44100, &[3; 10]);
+        let stream: Vec<u8> = [first.clone(), second.clone(), third.clone()].concat();
+
+        let mut writer = OggPageWriter::new(&mut output, Arc::clone(&written_frames));
+        writer.write_all(&stream[..first.len() + 10]).unwrap();
+        writer
+            .write_all(&stream[first.len() + 10..stream.len() - 1])
+            .unwrap();
+        drop(writer);
+        // The last page is not complete yet.
+        assert_eq!(output, [first.clone(), second.clone()].concat());
+        assert_eq!(written_frames.load(Ordering::Acquire), 22050);
+
+        let mut writer = OggPageWriter::new(&mut output, Arc::clone(&written_frames));
+        writer.write_all(&third).unwrap();
+        assert_eq!(written_frames.load(Ordering::Acquire), 44100);
+        assert_eq!(output, [first, second, third].concat());
+    }
+
+    #[test]
+    fn test_line_prosody_overrides_the_settings() {
+        let settings: TtsSettings = serde_json::from_value(serde_json::json!({
+            "prosody": { "rate": 0.75, "pauseMs": 800 },
+            "lineProsody": { "1": { "rate": 1.0, "volumeDb": -6.0 } },
+        }))
+        .unwrap();
+        assert_eq!(settings.validate(2), Ok(()));
+
+        let first = settings.line_prosody(0);
+        assert_eq!(first.pause_ms(), 800);
+        assert_eq!(first.gain(), 1.0);
+        let config = first.output_config();
+        assert_eq!(
+            (config.rate, config.pitch, config.volume),
+            (Some(15), None, None)
+        );
+
+        let second = settings.line_prosody(1);
+        assert_eq!(second.pause_ms(), 800);
+        assert!((second.gain() - 0.501).abs() < 0.001);
+        assert_eq!(second.output_config().rate, Some(20));
+
+        assert_eq!(TtsProsody::default().pause_ms(), DEFAULT_PAUSE_MS);
+        assert!(settings.validate(1).is_err());
+        let too_fast = TtsSettings {
+            prosody: TtsProsody {
+                rate: Some(8.0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(too_fast.validate(1).is_err());
+    }
+
+    #[test]
+    fn test_script_lines_take_speakers_from_prefixes_and_mapping() {
+        let voice = |speaker_id| TtsVoice {
+            config_path: "tts/ja.onnx.json".to_string(),
+            speaker_id,
+        };
+        let settings = TtsSettings {
+            voices: HashMap::from([("A".to_string(), voice(1)), ("B".to_string(), voice(2))]),
+            line_speakers: HashMap::from([(3, "A".to_string())]),
+            ..Default::default()
+        };
+        let transcript = "A: こんにちは。\n\nB\u{FF1A}はじめまして。\nNote: 12:30\nよろしく。";
+        let lines = settings.script_lines(transcript);
+        assert_eq!(
+            lines,
+            [
+                ScriptLine {
+                    speaker: Some("A"),
+                    text: "こんにちは。"
+                },
+                ScriptLine {
+                    speaker: Some("B"),
+                    text: "はじめまして。"
+                },
+                // Not one of the speakers.
+                ScriptLine {
+                    speaker: None,
+                    text: "Note: 12:30"
+                },
+                ScriptLine {
+                    speaker: Some("A"),
+                    text: "よろしく。"
+                },
+            ]
+        );
+        assert_eq!(lines[1].script_text(), "B: はじめまして。");
+        assert_eq!(lines[2].script_text(), "Note: 12:30");
+
+        let default_voice = voice(0);
+        assert_eq!(settings.voice(&lines[1], &default_voice), &voice(2));
+        assert_eq!(settings.voice(&lines[2], &default_voice), &default_voice);
+
+        assert_eq!(settings.validate(4), Ok(()));
+        let unknown_speaker = TtsSettings {
+            line_speakers: HashMap::from([(0, "C".to_string())]),
+            ..settings
+        };
+        assert!(unknown_speaker.validate(4).is_err());
+    }
+
+    #[test]
+    fn test_output_paths_stay_inside_app_local_data() {
+        assert!(check_output_path("episodes/dialogue.ogg").is_ok());
+        assert!(check_output_path("./tts/dialogue.wav").is_ok());
+        assert!(check_output_path("../dialogue.ogg").is_err());
+        assert!(check_output_path("tts/../../dialogue.ogg").is_err());
+        assert!(check_output_path("/tmp/dialogue.ogg").is_err());
+        assert!(check_output_path("").is_err());
+    }
+
+    #[test]
+    fn test_opus_output_needs_an_opus_sample_rate() {
+        let opus = TtsAudioFormat::Opus;
+        assert!(opus.check_sample_rate(opus.default_sample_rate()).is_ok());
+        assert!(opus.check_sample_rate(24000).is_ok());
+        assert!(opus.check_sample_rate(22050).is_err());
+        assert!(TtsAudioFormat::Ogg.check_sample_rate(22050).is_ok());
+        assert!(TtsAudioFormat::Wav.check_sample_rate(96000).is_err());
+    }
+
+    #[test]
+    fn test_opus_output_is_export_only() {
+        assert!(TtsAudioFormat::Ogg.is_playable());
+        assert!(TtsAudioFormat::Wav.is_playable());
+        assert!(!TtsAudioFormat::Opus.is_playable());
+    }
+
+    #[test]
+    fn test_queue_runs_jobs_in_order_up_to_its_capacity() {
+        let queue = Arc::new(TtsQueue::new(1));
+        let token = CancellationToken::new();
+        let first = queue.enter("first", &token).unwrap();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let second = {
+            let queue = Arc::clone(&queue);
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                let _turn = queue.enter("second", &CancellationToken::new()).unwrap();
+                sender.send("second").unwrap();
+            })
+        };
+        let cancelled = CancellationToken::new();
+        let third = {
+            let queue = Arc::clone(&queue);
+            let cancelled = cancelled.clone();
+            std::thread::spawn(move || queue.enter("third", &cancelled).map(|_| ()))
+        };
+        while queue.lock().waiting.len() < 2 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // A cancelled job leaves the queue without running.
+        cancelled.cancel();
+        assert_eq!(third.join().unwrap(), Err("TTS cancelled".to_string()));
+        assert!(receiver.try_recv().is_err());
+
+        drop(first);
+        second.join().unwrap();
+        assert_eq!(receiver.try_recv(), Ok("second"));
+        let state = queue.lock();
+        assert_eq!((state.running, state.waiting.len()), (0, 0));
+    }
+}
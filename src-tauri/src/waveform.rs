@@ -0,0 +1,316 @@
+// cSpell:words audiowaveform
+use log::{debug, warn};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+const MAGIC: &[u8; 4] = b"KPKS";
+const VERSION: u32 = 2;
+const PEAK_FILE_EXTENSION: &str = "peaks";
+/// Magic, version, source fingerprint, duration, loudness and level count.
+const HEADER_LEN: u64 = 40;
+
+/// Number of audio frames summarized by one peak of the base level.
+pub(crate) const BASE_FRAMES_PER_PEAK: usize = 512;
+/// Levels are added until they have fewer peaks than this.
+const MIN_LEVEL_PEAKS: usize = 256;
+
+/// Identifies the audio file a peak file was generated from, so a stale cache
+/// is regenerated when the audio is replaced.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SourceFingerprint {
+    len: u64,
+    modified_secs: u64,
+}
+
+impl SourceFingerprint {
+    pub(crate) fn of(audio_path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(audio_path)?;
+        let modified_secs = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ok(Self {
+            len: metadata.len(),
+            modified_secs,
+        })
+    }
+}
+
+/// Persistent multi-resolution waveform peaks, similar to BBC audiowaveform's
+/// `.dat` files.
+///
+/// The base level stores the maximum absolute sample value of every
+/// `BASE_FRAMES_PER_PEAK` frames quantized to a byte. Every further level halves
+/// the resolution of the previous one, so any zoom level can be served by
+/// downsampling the closest level instead of decoding the audio again.
+#[derive(Debug, PartialEq)]
+pub(crate) struct PeakFile {
+    pub(crate) source: SourceFingerprint,
+    pub(crate) duration_ms: u64,
+    /// Integrated loudness (EBU R128), or `None` for silent audio.
+    pub(crate) loudness_lufs: Option<f32>,
+    levels: Vec<Vec<u8>>,
+}
+
+pub(crate) fn quantize_peak(peak: f32) -> u8 {
+    (peak.abs().min(1.0) * 255.0).ceil() as u8
+}
+
+fn build_levels(base: Vec<u8>) -> Vec<Vec<u8>> {
+    let mut levels = vec![base];
+    while let Some(last) = levels.last() {
+        if last.len() < MIN_LEVEL_PEAKS {
+            break;
+        }
+        let next: Vec<u8> = last
+            .chunks(2)
+            .map(|pair| pair.iter().copied().max().unwrap_or(0))
+            .collect();
+        levels.push(next);
+    }
+    levels
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+impl PeakFile {
+    pub(crate) fn new(
+        source: SourceFingerprint,
+        duration_ms: u64,
+        loudness_lufs: Option<f32>,
+        base_peaks: Vec<u8>,
+    ) -> Self {
+        Self {
+            source,
+            duration_ms,
+            loudness_lufs,
+            levels: build_levels(base_peaks),
+        }
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&self.source.len.to_le_bytes())?;
+        writer.write_all(&self.source.modified_secs.to_le_bytes())?;
+        writer.write_all(&self.duration_ms.to_le_bytes())?;
+        // NaN stands for unknown loudness
+        let loudness = self.loudness_lufs.unwrap_or(f32::NAN);
+        writer.write_all(&loudness.to_le_bytes())?;
+        writer.write_all(&(self.levels.len() as u32).to_le_bytes())?;
+        for level in &self.levels {
+            writer.write_all(&(level.len() as u32).to_le_bytes())?;
+            writer.write_all(level)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a peak file of `file_len` bytes. Lengths that do not fit in the
+    /// file are rejected before anything is allocated for them, so that a
+    /// corrupt file cannot cause a huge allocation.
+    fn read_from(reader: &mut impl Read, file_len: u64) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut remaining = file_len
+            .checked_sub(HEADER_LEN)
+            .ok_or_else(|| invalid("Peak file is truncated"))?;
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a peak file"));
+        }
+        if read_u32(reader)? != VERSION {
+            return Err(invalid("Unsupported peak file version"));
+        }
+        let source = SourceFingerprint {
+            len: read_u64(reader)?,
+            modified_secs: read_u64(reader)?,
+        };
+        let duration_ms = read_u64(reader)?;
+        let loudness_lufs = Some(f32::from_bits(read_u32(reader)?)).filter(|l| !l.is_nan());
+        let level_count = read_u32(reader)? as u64;
+        // Every level takes at least its 4-byte length.
+        if level_count * 4 > remaining {
+            return Err(invalid("Peak file is truncated"));
+        }
+        let mut levels = Vec::with_capacity(level_count as usize);
+        for _ in 0..level_count {
+            let len = read_u32(reader)? as u64;
+            remaining = remaining
+                .checked_sub(4 + len)
+                .ok_or_else(|| invalid("Peak file is truncated"))?;
+            let mut level = vec![0u8; len as usize];
+            reader.read_exact(&mut level)?;
+            levels.push(level);
+        }
+        if levels.is_empty() {
+            return Err(invalid("Peak file has no levels"));
+        }
+        Ok(Self {
+            source,
+            duration_ms,
+            loudness_lufs,
+            levels,
+        })
+    }
+
+    /// Largest absolute sample value of the whole audio. Peaks are quantized
+    /// upwards, so this is never below the real peak.
+    pub(crate) fn sample_peak(&self) -> f32 {
+        // Every level keeps the maximum, so the smallest one is enough.
+        let peak = self
+            .levels
+            .last()
+            .and_then(|level| level.iter().copied().max())
+            .unwrap_or(0);
+        peak as f32 / 255.0
+    }
+
+    /// Returns at most `max_peaks` peaks normalized to the loudest one.
+    pub(crate) fn peaks(&self, max_peaks: usize) -> Vec<f32> {
+        if max_peaks == 0 {
+            return vec![];
+        }
+        // Use the coarsest level that still has enough resolution.
+        let level = self
+            .levels
+            .iter()
+            .rev()
+            .find(|level| level.len() >= max_peaks)
+            .unwrap_or(&self.levels[0]);
+        if level.is_empty() {
+            return vec![];
+        }
+
+        let bins = max_peaks.min(level.len());
+        let raw_peaks: Vec<u8> = (0..bins)
+            .map(|i| {
+                let start = i * level.len() / bins;
+                let end = ((i + 1) * level.len() / bins).max(start + 1);
+                level[start..end].iter().copied().max().unwrap_or(0)
+            })
+            .collect();
+
+        let max_peak = raw_peaks.iter().copied().max().unwrap_or(0).max(1) as f32;
+        raw_peaks.iter().map(|&p| p as f32 / max_peak).collect()
+    }
+}
+
+pub(crate) fn peak_file_path(audio_path: &Path) -> PathBuf {
+    let mut file_name = audio_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(PEAK_FILE_EXTENSION);
+    audio_path.with_file_name(file_name)
+}
+
+/// Loads the cached peaks for `audio_path`, ignoring missing or stale files.
+pub(crate) fn load_peak_file(audio_path: &Path) -> Option<PeakFile> {
+    let cache_path = peak_file_path(audio_path);
+    let file = File::open(&cache_path).ok()?;
+    let file_len = file.metadata().ok()?.len();
+    let peak_file = match PeakFile::read_from(&mut BufReader::new(file), file_len) {
+        Ok(peak_file) => peak_file,
+        Err(e) => {
+            warn!("Ignoring unreadable peak file {:?}: {}", cache_path, e);
+            return None;
+        }
+    };
+    let fingerprint = SourceFingerprint::of(audio_path).ok()?;
+    if peak_file.source != fingerprint {
+        debug!("Peak file {:?} is stale", cache_path);
+        return None;
+    }
+    Some(peak_file)
+}
+
+pub(crate) fn save_peak_file(audio_path: &Path, peak_file: &PeakFile) -> io::Result<()> {
+    let cache_path = peak_file_path(audio_path);
+    let mut writer = BufWriter::new(File::create(&cache_path)?);
+    peak_file.write_to(&mut writer)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint() -> SourceFingerprint {
+        SourceFingerprint {
+            len: 1234,
+            modified_secs: 42,
+        }
+    }
+
+    #[test]
+    fn test_peak_file_round_trip() {
+        let peak_file = PeakFile::new(
+            fingerprint(),
+            60_000,
+            Some(-18.5),
+            (0..600).map(|i| i as u8).collect(),
+        );
+        let mut buffer = Vec::new();
+        peak_file.write_to(&mut buffer).unwrap();
+
+        let loaded = PeakFile::read_from(&mut buffer.as_slice(), buffer.len() as u64).unwrap();
+
+        assert_eq!(loaded, peak_file);
+        assert_eq!(loaded.levels.len(), 3);
+        assert_eq!(loaded.levels[2].len(), 150);
+    }
+
+    #[test]
+    fn test_read_rejects_lengths_beyond_the_file() {
+        let peak_file = PeakFile::new(fingerprint(), 1_000, None, vec![7; 10]);
+        let mut buffer = Vec::new();
+        peak_file.write_to(&mut buffer).unwrap();
+        // Corrupt the length of the only level.
+        buffer[40..44].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let error = PeakFile::read_from(&mut buffer.as_slice(), buffer.len() as u64).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // A truncated file is rejected before its levels are read.
+        let mut buffer = Vec::new();
+        peak_file.write_to(&mut buffer).unwrap();
+        let len = buffer.len() as u64 - 1;
+        assert!(PeakFile::read_from(&mut buffer.as_slice(), len).is_err());
+    }
+
+    #[test]
+    fn test_peaks_downsamples_closest_level() {
+        let mut base = vec![0u8; 1024];
+        base[1000] = 200;
+        base[10] = 100;
+        let peak_file = PeakFile::new(fingerprint(), 10_000, None, base);
+
+        let peaks = peak_file.peaks(100);
+
+        assert_eq!(peaks.len(), 100);
+        assert_eq!(peaks[1], 0.5);
+        assert_eq!(peaks[98], 1.0);
+    }
+
+    #[test]
+    fn test_peak_file_path_appends_extension() {
+        assert_eq!(
+            peak_file_path(Path::new("/data/media/episode.mp3")),
+            PathBuf::from("/data/media/episode.mp3.peaks")
+        );
+    }
+}
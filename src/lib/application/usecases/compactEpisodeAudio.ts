@@ -0,0 +1,49 @@
+import type { CompactedAudio } from '$lib/domain/entities/compactedAudio';
+import type { Episode } from '$lib/domain/entities/episode';
+import { remapTimeMs } from '$lib/domain/services/remapTimeMs';
+import { audioRepository } from '$lib/infrastructure/repositories/audioRepository';
+import { episodeRepository } from '$lib/infrastructure/repositories/episodeRepository';
+import { subtitleLineRepository } from '$lib/infrastructure/repositories/subtitleLineRepository';
+
+/**
+ * エピソード音声の長い無音を縮め、エピソードを新しい音声に切り替えるユースケース
+ * 字幕行の時刻はタイムマップで新しい音声上の時刻に変換する。元の音声ファイルは残す。
+ * @param episode 対象のエピソード
+ * @param maxGapMs 縮めた後の無音の最大の長さ（ミリ秒）
+ * @param minSilenceMs 縮める対象とする無音の最小の長さ（ミリ秒）
+ */
+export async function compactEpisodeAudio(
+  episode: Episode,
+  maxGapMs: number,
+  minSilenceMs?: number
+): Promise<CompactedAudio> {
+  console.info(`Compacting audio of episode ${episode.id} (max gap ${maxGapMs}ms)`);
+  let compacted: CompactedAudio;
+  try {
+    compacted = await audioRepository.exportCompactedAudio(
+      episode.mediaPath,
+      maxGapMs,
+      minSilenceMs
+    );
+  } catch (err) {
+    console.error(`Error compacting episode audio: ${err}`);
+    throw new Error('Failed to compact episode audio.');
+  }
+
+  const subtitleLines = await subtitleLineRepository.getSubtitleLinesByEpisodeId(episode.id);
+  await subtitleLineRepository.updateSubtitleLineTimes(
+    subtitleLines.map((line) => ({
+      id: line.id,
+      startTimeMs: remapTimeMs(compacted.timeMap, line.startTimeMs),
+      endTimeMs: line.endTimeMs === null ? null : remapTimeMs(compacted.timeMap, line.endTimeMs),
+    }))
+  );
+  try {
+    await episodeRepository.updateEpisode(episode.id, { mediaPath: compacted.mediaPath });
+  } catch (err) {
+    // 字幕行の時刻を元の音声に合わせて戻す
+    await subtitleLineRepository.updateSubtitleLineTimes(subtitleLines);
+    throw err;
+  }
+  return compacted;
+}
@@ -0,0 +1,27 @@
+import type { AnkiPushReport } from '$lib/domain/entities/ankiPushReport';
+import type { SentenceCard } from '$lib/domain/entities/sentenceCard';
+import { ankiRepository } from '$lib/infrastructure/repositories/ankiRepository';
+import { sentenceCardRepository } from '$lib/infrastructure/repositories/sentenceCardRepository';
+
+/**
+ * センテンスカードを起動中のAnkiに送り、Ankiに入ったカードを送信済みにするユースケース
+ * @param cards 送るカード
+ * @param deckName 追加先のデッキ名
+ * @param audioClips カードIDから音声クリップのパスへの対応
+ */
+export async function pushSentenceCardsToAnki(
+  cards: readonly SentenceCard[],
+  deckName: string,
+  audioClips: Readonly<Record<string, string>> = {}
+): Promise<AnkiPushReport> {
+  console.info(`Pushing ${cards.length} sentence cards to Anki deck ${deckName}`);
+  let report: AnkiPushReport;
+  try {
+    report = await ankiRepository.pushCards(cards, deckName, audioClips);
+  } catch (err) {
+    console.error(`Error pushing sentence cards to Anki: ${err}`);
+    throw new Error('Failed to push sentence cards to Anki.');
+  }
+  await sentenceCardRepository.markCardsAsSent(report.sentCardIds);
+  return report;
+}
@@ -0,0 +1,18 @@
+/**
+ * What happened to a sentence card sent to Anki through AnkiConnect.
+ */
+export type AnkiPushOutcome =
+  | { readonly outcome: 'added'; readonly noteId: number }
+  | { readonly outcome: 'alreadyInAnki'; readonly noteId: number }
+  | { readonly outcome: 'failed'; readonly message: string };
+
+export type AnkiPushResult = { readonly cardId: string } & AnkiPushOutcome;
+
+/**
+ * The result of sending sentence cards to Anki.
+ */
+export type AnkiPushReport = {
+  readonly results: readonly AnkiPushResult[];
+  /** Cards that are in Anki now, whether added or already there. */
+  readonly sentCardIds: readonly string[];
+};
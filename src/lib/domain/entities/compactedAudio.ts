@@ -0,0 +1,18 @@
+/**
+ * A part of the source audio that is kept in the compacted audio.
+ */
+export type TimeMapSegment = {
+  readonly sourceStartMs: number;
+  readonly sourceEndMs: number;
+  readonly outputStartMs: number;
+};
+
+/**
+ * An episode audio whose long silences were shortened.
+ */
+export type CompactedAudio = {
+  /** Relative to AppLocalData, like the episode audio. */
+  readonly mediaPath: string;
+  readonly durationMs: number;
+  readonly timeMap: readonly TimeMapSegment[];
+};
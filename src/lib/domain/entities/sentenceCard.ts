@@ -1,7 +1,7 @@
 /**
  * Sentence Miningによって作成された学習カードのエンティティ。
  */
-export type SentenceCardStatus = 'active' | 'suspended' | 'cache';
+export type SentenceCardStatus = 'active' | 'suspended' | 'cache' | 'sent'; // sent: Ankiに送信済み
 
 export type SentenceCard = {
   readonly id: string;
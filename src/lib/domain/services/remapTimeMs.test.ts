@@ -0,0 +1,29 @@
+import type { TimeMapSegment } from '$lib/domain/entities/compactedAudio';
+import { describe, expect, it } from 'vitest';
+import { remapTimeMs } from './remapTimeMs';
+
+describe('remapTimeMs', () => {
+  const timeMap: TimeMapSegment[] = [
+    { sourceStartMs: 0, sourceEndMs: 1200, outputStartMs: 0 },
+    { sourceStartMs: 2800, sourceEndMs: 5000, outputStartMs: 1200 },
+  ];
+
+  it('should shift times in kept segments by the removed length', () => {
+    expect(remapTimeMs(timeMap, 500)).toBe(500);
+    expect(remapTimeMs(timeMap, 3000)).toBe(1400);
+  });
+
+  it('should move times inside a removed part to the cut', () => {
+    expect(remapTimeMs(timeMap, 1200)).toBe(1200);
+    expect(remapTimeMs(timeMap, 2000)).toBe(1200);
+  });
+
+  it('should clamp times after the end to the new duration', () => {
+    expect(remapTimeMs(timeMap, 5000)).toBe(3400);
+    expect(remapTimeMs(timeMap, 6000)).toBe(3400);
+  });
+
+  it('should return 0 for an empty time map', () => {
+    expect(remapTimeMs([], 1000)).toBe(0);
+  });
+});
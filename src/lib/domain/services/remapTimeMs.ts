@@ -0,0 +1,22 @@
+import type { TimeMapSegment } from '$lib/domain/entities/compactedAudio';
+
+/**
+ * 元の音声の時刻を、無音を縮めた音声上の時刻に変換します。
+ * 削除された区間内の時刻は切れ目の位置に、末尾より後の時刻は末尾に移ります。
+ *
+ * @param timeMap 残した区間の一覧（元の音声の時刻順）
+ * @param sourceMs 元の音声の時刻（ミリ秒）
+ * @returns 変換後の時刻（ミリ秒）
+ */
+export function remapTimeMs(timeMap: readonly TimeMapSegment[], sourceMs: number): number {
+  for (const segment of timeMap) {
+    if (sourceMs < segment.sourceStartMs) {
+      return segment.outputStartMs;
+    }
+    if (sourceMs < segment.sourceEndMs) {
+      return segment.outputStartMs + (sourceMs - segment.sourceStartMs);
+    }
+  }
+  const last = timeMap[timeMap.length - 1];
+  return last ? last.outputStartMs + (last.sourceEndMs - last.sourceStartMs) : 0;
+}
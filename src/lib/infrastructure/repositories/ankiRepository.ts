@@ -0,0 +1,26 @@
+import type { AnkiPushReport } from '$lib/domain/entities/ankiPushReport';
+import type { SentenceCard } from '$lib/domain/entities/sentenceCard';
+import { invoke } from '@tauri-apps/api/core';
+
+/**
+ * Ankiとの連携を行うためのリポジトリ
+ */
+export const ankiRepository = {
+  /**
+   * 起動中のAnkiにAnkiConnectでカードを送ります。
+   * @param cards - 送るカード。
+   * @param deckName - 追加先のデッキ名。
+   * @param audioClips - カードIDから音声クリップのパス（AppLocalDataからの相対パス）への対応。
+   * @return 各カードの結果。
+   */
+  async pushCards(
+    cards: readonly SentenceCard[],
+    deckName: string,
+    audioClips: Readonly<Record<string, string>> = {}
+  ): Promise<AnkiPushReport> {
+    return await invoke<AnkiPushReport>('push_cards_to_anki', {
+      cards: cards.map((card) => ({ content: card, audioClip: audioClips[card.id] ?? null })),
+      deckName,
+    });
+  },
+};
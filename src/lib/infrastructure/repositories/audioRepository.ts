@@ -1,4 +1,5 @@
 import type { AudioInfo } from '$lib/domain/entities/audioInfo';
+import type { CompactedAudio } from '$lib/domain/entities/compactedAudio';
 import { invoke } from '@tauri-apps/api/core';
 import { listen, type UnlistenFn } from '@tauri-apps/api/event';
 
@@ -67,6 +68,25 @@ export const audioRepository = {
     await invoke('seek_audio', { positionMs: positionMsInt });
   },
 
+  /**
+   * 長い無音を縮めたエピソード音声を書き出します。データベースは更新しません。
+   * @param episodeAudio - エピソード音声のパス（AppLocalData からの相対パス）。
+   * @param maxGapMs - 縮めた後の無音の最大の長さ（ミリ秒）。
+   * @param minSilenceMs - 縮める対象とする無音の最小の長さ（ミリ秒）。
+   * @return 書き出した音声のパスとタイムマップ。
+   */
+  async exportCompactedAudio(
+    episodeAudio: string,
+    maxGapMs: number,
+    minSilenceMs?: number
+  ): Promise<CompactedAudio> {
+    return await invoke<CompactedAudio>('export_compacted_audio', {
+      episodeAudio,
+      maxGapMs,
+      minSilenceMs: minSilenceMs ?? null,
+    });
+  },
+
   /**
    * 再生位置の変更を監視します。
    * @param callback - 再生位置が変更されたときに呼び出されるコールバック関数。引数として現在の再生位置（ミリ秒）を受け取ります。
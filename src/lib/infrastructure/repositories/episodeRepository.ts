@@ -99,7 +99,7 @@ export const episodeRepository = {
         COUNT(sc.id) AS sentence_card_count
       FROM episodes e
       LEFT JOIN subtitle_lines sl ON e.id = sl.episode_id
-      LEFT JOIN sentence_cards sc ON sl.id = sc.subtitle_line_id AND sc.status = 'active'
+      LEFT JOIN sentence_cards sc ON sl.id = sc.subtitle_line_id AND sc.status IN ('active', 'sent')
       WHERE e.episode_group_id = ?
       GROUP BY e.id
       ORDER BY COALESCE(json_extract(e.content, '$.displayOrder'), 0) ASC
@@ -181,7 +181,7 @@ export const episodeRepository = {
 
   async updateEpisode(
     episodeId: string,
-    params: { title?: string; displayOrder?: number }
+    params: { title?: string; displayOrder?: number; mediaPath?: string }
   ): Promise<void> {
     const db = new Database(await getDatabasePath());
     const rows = await db.select<{ content: string }[]>(
@@ -196,6 +196,7 @@ export const episodeRepository = {
       ...currentContent,
       ...(params.title !== undefined ? { title: params.title } : {}),
       ...(params.displayOrder !== undefined ? { displayOrder: params.displayOrder } : {}),
+      ...(params.mediaPath !== undefined ? { mediaPath: params.mediaPath } : {}),
     };
     const now = new Date().toISOString();
     await db.execute('UPDATE episodes SET content = ?, updated_at = ? WHERE id = ?', [
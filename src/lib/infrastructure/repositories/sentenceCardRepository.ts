@@ -91,7 +91,7 @@ export const sentenceCardRepository = {
       SELECT sc.*
       FROM sentence_cards sc
       INNER JOIN subtitle_lines sl ON sc.subtitle_line_id = sl.id
-      WHERE sl.episode_id = ? AND sc.status = 'active'
+      WHERE sl.episode_id = ? AND sc.status IN ('active', 'sent')
       ORDER BY sl.sequence_number ASC, sc.updated_at ASC
     `,
       [episodeId]
@@ -162,6 +162,24 @@ export const sentenceCardRepository = {
     );
   },
 
+  /**
+   * Ankiに送ったカードを送信済みにする
+   */
+  async markCardsAsSent(cardIds: readonly string[]): Promise<void> {
+    if (cardIds.length === 0) return;
+    const db = new Database(await getDatabasePath());
+    const placeholders = cardIds.map(() => '?').join(',');
+    const now = new Date().toISOString();
+    await db.execute(
+      `UPDATE sentence_cards
+       SET status = 'sent',
+           updated_at = ?,
+           content = json_set(content, '$.status', 'sent', '$.updatedAt', ?)
+       WHERE id IN (${placeholders})`,
+      [now, now, ...cardIds]
+    );
+  },
+
   /**
    * Sentence Cardのステータスを更新する
    */
@@ -121,6 +121,35 @@ export const subtitleLineRepository = {
     }));
   },
 
+  async updateSubtitleLineTimes(
+    times: readonly { id: string; startTimeMs: number; endTimeMs: number | null }[]
+  ): Promise<void> {
+    const db = new Database(await getDatabasePath());
+    await db.execute('BEGIN TRANSACTION');
+    try {
+      const now = new Date().toISOString();
+      for (const time of times) {
+        const rows = await db.select<{ content: string }[]>(
+          'SELECT content FROM subtitle_lines WHERE id = ?',
+          [time.id]
+        );
+        if (rows.length === 0) continue;
+
+        const content = parseSubtitleLineContent(rows[0].content);
+        const newContent = { ...content, startTimeMs: time.startTimeMs, endTimeMs: time.endTimeMs };
+        await db.execute('UPDATE subtitle_lines SET content = ?, updated_at = ? WHERE id = ?', [
+          JSON.stringify({ ...newContent, updatedAt: now }),
+          now,
+          time.id,
+        ]);
+      }
+      await db.execute('COMMIT');
+    } catch (e) {
+      await db.execute('ROLLBACK');
+      throw e;
+    }
+  },
+
   async deleteByEpisodeId(episodeId: string): Promise<void> {
     const db = new Database(await getDatabasePath());
     await db.execute('DELETE FROM subtitle_lines WHERE episode_id = ?', [episodeId]);